@@ -5,11 +5,10 @@ use criterion::{criterion_group, criterion_main, Criterion};
 use futures::StreamExt;
 use kv::{
     start_client_with_config, start_server_with_config, ClientConfig, CommandRequest, ServerConfig,
-    StorageConfig, YamuxCtrl,
+    StorageConfig, Transport, YamuxCtrl,
 };
 use rand::seq::SliceRandom;
-use tokio::{net::TcpStream, runtime::Builder, time};
-use tokio_rustls::client::TlsStream;
+use tokio::{runtime::Builder, time};
 use tracing::info;
 
 async fn start_server() -> Result<()> {
@@ -25,12 +24,12 @@ async fn start_server() -> Result<()> {
     Ok(())
 }
 
-async fn connect() -> Result<YamuxCtrl<TlsStream<TcpStream>>> {
+async fn connect() -> Result<YamuxCtrl<Box<dyn Transport>>> {
     let addr = "127.0.0.1:9999";
     let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
     config.general.addr = addr.into();
 
-    Ok(start_client_with_config(&config).await?)
+    start_client_with_config(&config).await
 }
 
 async fn start_subscribers(topic: &'static str) -> Result<()> {
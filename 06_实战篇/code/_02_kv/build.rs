@@ -1,9 +1,10 @@
 fn main() {
     let mut config = prost_build::Config::new();
-    config.bytes(&["."]);
+    config.bytes(["."]);
+    config.btree_map(["."]);
     config.type_attribute(".", "#[derive(PartialOrd)]");
     config
         .out_dir("src/pb")
-        .compile_protos(&["abi.proto"], &["."])
+        .compile_protos(&["abi.proto", "wal.proto"], &["."])
         .unwrap();
 }
@@ -19,9 +19,11 @@ async fn main() -> Result<()> {
             AsyncProstStream::<_, CommandRequest, CommandResponse, _>::from(stream).for_async();
         while let Some(Ok(msg)) = stream.next().await {
             info!("Got a new command: {:?}", msg);
-            let mut resp = CommandResponse::default();
-            resp.status = 404;
-            resp.message = "Not Found".to_string();
+            let resp = CommandResponse {
+                status: 404,
+                message: "Not Found".to_string(),
+                ..Default::default()
+            };
             stream.send(resp).await.unwrap();
         }
         info!("Client {:?} disconnected", addr);
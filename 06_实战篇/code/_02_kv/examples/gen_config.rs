@@ -1,6 +1,7 @@
 use anyhow::Result;
 use kv::{
-    ClientConfig, ClientTlsConfig, GeneralConfig, ServerConfig, ServerTlsConfig, StorageConfig,
+    ClientConfig, ClientTlsConfig, GeneralConfig, ReplicationConfig, ServerConfig, ServerTlsConfig,
+    StorageConfig,
 };
 
 use std::fs;
@@ -21,6 +22,14 @@ fn main() -> Result<()> {
             key: SERVER_KEY.into(),
             ca: None,
         },
+        replication: ReplicationConfig::Standalone,
+        acl: None,
+        metrics: None,
+        limits: Default::default(),
+        topics: Default::default(),
+        keyspace: Default::default(),
+        compression: Default::default(),
+        listeners: vec![],
         // log: LogConfig {
         //     path: "/tmp/kv-log".into(),
         //     rotation: RotationConfig::Daily,
@@ -40,6 +49,9 @@ fn main() -> Result<()> {
             ca: Some(CA_CERT.into()),
             domain: "kvserver.acme.inc".into(),
         },
+        listeners: vec![],
+        pool: Default::default(),
+        compression: Default::default(),
     };
 
     fs::write(
@@ -1,87 +1,151 @@
-// use std::time::Duration;
-
-// use anyhow::Result;
-// use futures::StreamExt;
-// use kv::{CommandRequest, KvError, ProstClientStream, TlsClientConnector, YamuxCtrl};
-// use tokio::{net::TcpStream, time};
-// use tokio_util::compat::Compat;
-// use tracing::info;
-
-// #[tokio::main]
-// async fn main() -> Result<()> {
-//     tracing_subscriber::fmt::init();
-
-//     // 以后用配置替换
-//     let ca_cert = include_str!("../fixtures/ca.cert");
-
-//     let addr = "127.0.0.1:9527";
-//     // 连接服务器
-//     let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(ca_cert))?;
-//     let stream = TcpStream::connect(addr).await?;
-//     let stream = connector.connect(stream).await?;
-
-//     // 打开一个 stream
-//     let mut ctrl = YamuxCtrl::new_client(stream, None);
-
-//     let channel = "lobby";
-//     start_publishing(ctrl.open_stream().await?, channel)?;
-
-//     let stream = ctrl.open_stream().await?;
-
-//     let mut client = ProstClientStream::new(stream);
-
-//     // 生成一个 HSET 命令
-//     let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
-
-//     // 发送 HSET 命令
-//     let data = client.execute_unary(&cmd).await?;
-//     info!("Got response {:?}", data);
-
-//     // 生成一个 Subscribe 命令
-//     let cmd = CommandRequest::new_subscribe(channel);
-//     let mut stream = client.execute_streaming(&cmd).await?;
-//     let id = stream.id;
-//     start_unsubscribe(ctrl.open_stream().await?, channel, id)?;
-
-//     while let Some(Ok(data)) = stream.next().await {
-//         println!("Got published data: {:?}", data);
-//     }
-
-//     println!("Done!");
-
-//     Ok(())
-// }
-
-// fn start_publishing(stream: Compat<yamux::Stream>, name: &str) -> Result<(), KvError> {
-//     let cmd = CommandRequest::new_publish(name, vec![1.into(), 2.into(), "hello".into()]);
-//     tokio::spawn(async move {
-//         time::sleep(Duration::from_millis(1000)).await;
-//         let mut client = ProstClientStream::new(stream);
-//         let res = client.execute_unary(&cmd).await.unwrap();
-//         println!("Finished publishing: {:?}", res);
-//     });
-
-//     Ok(())
-// }
-
-// fn start_unsubscribe(stream: Compat<yamux::Stream>, name: &str, id: u32) -> Result<(), KvError> {
-//     let cmd = CommandRequest::new_unsubscribe(name, id as _);
-//     tokio::spawn(async move {
-//         time::sleep(Duration::from_millis(2000)).await;
-//         let mut client = ProstClientStream::new(stream);
-//         let res = client.execute_unary(&cmd).await.unwrap();
-//         println!("Finished unsubscribing: {:?}", res);
-//     });
-
-//     Ok(())
-// }
+use std::{env, path::PathBuf, process};
 
 use anyhow::Result;
+use clap::Parser;
+use futures::StreamExt;
+use kv::{
+    command_request::RequestData, start_client_with_config, ClientConfig, CommandRequest,
+    CommandResponse, YamuxCtrl,
+};
+use rustyline::{error::ReadlineError, DefaultEditor};
+use tokio::{io::AsyncRead, io::AsyncWrite, signal, task};
+
+/// KV Server 的命令行客户端，不指定命令时进入交互模式
+#[derive(Debug, Parser)]
+#[command(name = "kvc", version)]
+struct Args {
+    /// 客户端配置文件
+    #[arg(short, long, default_value = "fixtures/client.conf")]
+    config: String,
+    /// 要执行的命令，比如 `kvc hset t1 k1 v1`，执行完后退出
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // tracing_subscriber::fmt::init();
-    // let config: ClientConfig = toml::from_str(include!("../fixtures/client.conf"))?;
-    // start_client_with_config(&config).await?;
+    let args = Args::parse();
+    let config = ClientConfig::load(&args.config)?;
+    let mut ctrl = start_client_with_config(&config).await?;
+
+    if args.command.is_empty() {
+        return repl(&mut ctrl, &config.general.addr).await;
+    }
+
+    // 命令行参数已经被 shell 拆分过了，重新拼起来时要保留引号
+    let line = shlex::try_join(args.command.iter().map(|v| v.as_str()))?;
+    if !execute(&mut ctrl, &line).await? {
+        process::exit(1);
+    }
     Ok(())
 }
+
+/// 交互模式，历史记录保存在 ~/.kvc_history
+async fn repl<S>(ctrl: &mut YamuxCtrl<S>, addr: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut editor = DefaultEditor::new()?;
+    let history = env::var_os("HOME").map(|v| PathBuf::from(v).join(".kvc_history"));
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
+
+    let prompt = format!("{}> ", addr);
+    loop {
+        // readline 会阻塞当前线程
+        let line = match task::block_in_place(|| editor.readline(&prompt)) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+
+        match line {
+            "help" => println!("{}", CommandRequest::USAGE),
+            "quit" | "exit" => break,
+            _ => {
+                if let Err(e) = execute(ctrl, line).await {
+                    println!("(error) {}", e);
+                }
+            }
+        }
+    }
+
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
+
+/// 执行一行命令并打印结果，返回命令是否成功
+async fn execute<S>(ctrl: &mut YamuxCtrl<S>, line: &str) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let cmd: CommandRequest = match line.parse() {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            println!("(error) {}", e);
+            return Ok(false);
+        }
+    };
+
+    let mut client = ctrl.open_stream().await?;
+    match &cmd.request_data {
+        Some(RequestData::Subscribe(_) | RequestData::Psubscribe(_)) => {
+            let topic = match &cmd.request_data {
+                Some(RequestData::Psubscribe(param)) => param.pattern.clone(),
+                Some(RequestData::Subscribe(param)) => param.topic.clone(),
+                _ => unreachable!(),
+            };
+            let mut stream = client.execute_chunked(&cmd).await?;
+
+            // 第一个 response 里是订阅的 id
+            let id = match stream.next().await.transpose()? {
+                Some(res) if res.status == 200 => {
+                    res.values.first().and_then(|v| v.try_into().ok())
+                }
+                Some(res) => return Ok(print_response(&res)),
+                None => None,
+            };
+            let id: i64 = id.unwrap_or_default();
+            println!("Subscribed to {}, id: {}", topic, id);
+            println!("Press Ctrl-C to stop");
+
+            loop {
+                tokio::select! {
+                    res = stream.next() => match res.transpose()? {
+                        Some(res) => print_response(&res),
+                        None => break,
+                    },
+                    _ = signal::ctrl_c() => break,
+                };
+            }
+            Ok(true)
+        }
+        Some(RequestData::Hgetall(param)) if param.chunk_size > 0 => {
+            let mut stream = client.execute_chunked(&cmd).await?;
+            let mut ok = true;
+            while let Some(res) = stream.next().await.transpose()? {
+                ok &= print_response(&res);
+            }
+            Ok(ok)
+        }
+        _ => {
+            let res = client.execute_unary(&cmd).await?;
+            Ok(print_response(&res))
+        }
+    }
+}
+
+fn print_response(res: &CommandResponse) -> bool {
+    println!("{}", res);
+    (200..300).contains(&res.status)
+}
@@ -1,42 +1,352 @@
-use std::fs;
+use std::{
+    collections::HashMap,
+    fs,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use serde::{Deserialize, Serialize};
 
-use crate::KvError;
+use crate::{network::COMPRESSION_LIMIT, KvError, TlsClientConnector, TlsServerAcceptor};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ServerConfig {
+    #[serde(default)]
     pub general: GeneralConfig,
     pub storage: StorageConfig,
+    /// TLS listener 使用的证书，没有 TLS listener 时可以省略
+    #[serde(default)]
     pub tls: ServerTlsConfig,
+    /// 同时监听的多个地址，为空时只在 general.addr 上监听 TLS
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub replication: ReplicationConfig,
+    /// ACL 文件的路径，不设置时不做权限检查
+    #[serde(default)]
+    pub acl: Option<String>,
+    /// Prometheus 的 HTTP /metrics 接口，不设置时不启动
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    /// 请求速率和 table 大小的限制，默认不限制
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// 持久化的主题，默认所有的主题都不保留消息
+    #[serde(default)]
+    pub topics: TopicsConfig,
+    /// 发布 key 变化通知的 table，默认不发布
+    #[serde(default)]
+    pub keyspace: KeyspaceConfig,
+    /// 发送 frame 时使用的压缩算法，默认和旧版本一样使用 gzip
+    #[serde(default)]
+    pub compression: CompressionConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ClientConfig {
+    #[serde(default)]
     pub general: GeneralConfig,
+    /// TLS 连接使用的配置，不使用 TLS 时可以省略
+    #[serde(default)]
     pub tls: ClientTlsConfig,
+    /// 服务器的地址，依次尝试连接，使用第一个连接成功的；为空时连接 general.addr 上的 TLS
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// KvPool 的重连、健康检查和空闲 stream 的配置
+    #[serde(default)]
+    pub pool: PoolConfig,
+    /// 发送 frame 时使用的压缩算法，默认和旧版本一样使用 gzip
+    #[serde(default)]
+    pub compression: CompressionConfig,
 }
 
-#[derive(Clone, Serialize, Debug, Deserialize, PartialEq)]
+/// KvPool 的配置，所有的时间都以毫秒为单位
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PoolConfig {
+    /// 最多保留多少个空闲的 stream 给之后的请求使用
+    pub max_idle_streams: usize,
+    /// 第一次重连之前等待的时间，之后每次翻倍
+    pub initial_backoff_ms: u64,
+    /// 重连之间最长的等待时间
+    pub max_backoff_ms: u64,
+    /// 请求失败之前最多重连几次，订阅会一直重连
+    pub max_retries: u32,
+    /// 健康检查的间隔，为 0 时不检查
+    pub health_check_interval_ms: u64,
+    /// 每次连接后用来 AUTH 的 token
+    pub token: Option<String>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_streams: 8,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 5000,
+            max_retries: 5,
+            health_check_interval_ms: 10000,
+            token: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Debug, Default, Deserialize, PartialEq)]
 pub struct GeneralConfig {
     pub addr: String,
 }
 
+/// frame 的压缩算法
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    None,
+    #[default]
+    Gzip,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    /// 支持的所有压缩算法
+    pub const ALL: [Codec; 4] = [Codec::None, Codec::Gzip, Codec::Lz4, Codec::Zstd];
+    /// 没有协商过的对端（包括不认识 HELLO 的旧版本）只支持这两种
+    pub const LEGACY: [Codec; 2] = [Codec::None, Codec::Gzip];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Gzip => "gzip",
+            Codec::Lz4 => "lz4",
+            Codec::Zstd => "zstd",
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Codec::ALL
+            .into_iter()
+            .find(|v| v.as_str() == s)
+            .ok_or_else(|| KvError::ConvertError(s.into(), "Codec"))
+    }
+}
+
+/// 发送 frame 时的压缩配置，接收时根据 frame header 中的算法解压
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// 对端支持这个算法时才使用，否则使用 gzip
+    pub codec: Codec,
+    /// payload 超过这么多字节才压缩
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            codec: Codec::Gzip,
+            threshold: COMPRESSION_LIMIT,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// 根据对端支持的压缩算法，得到实际使用的配置
+    pub fn negotiate(&self, peer: &[Codec]) -> Self {
+        let codec = if peer.contains(&self.codec) {
+            self.codec
+        } else {
+            Codec::Gzip
+        };
+        Self { codec, ..*self }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ListenerConfig {
+    /// TCP 上的 TLS，使用 tls 中的证书
+    Tls { addr: String },
+    /// 不加密的 TCP，只适合在可信的网络中（比如测试）使用
+    Tcp { addr: String },
+    /// Unix domain socket
+    Unix { path: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MetricsConfig {
+    /// HTTP 监听的地址，比如 0.0.0.0:9100
+    pub addr: String,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "type", content = "args")]
 pub enum StorageConfig {
     MemTable,
     SledDb(String),
+    WalMemTable(WalConfig),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct WalConfig {
+    /// 存放 WAL 和 snapshot 的目录
+    pub path: String,
+    /// WAL 写入后什么时候 fsync 到磁盘
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+    /// WAL 中累计多少条记录之后做一次 snapshot
+    #[serde(default = "default_snapshot_threshold")]
+    pub snapshot_threshold: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    /// 每次写入都 fsync，最安全也最慢
+    Always,
+    /// 距离上次 fsync 超过一秒时再 fsync，宕机最多丢失约一秒的数据
+    #[default]
+    EverySec,
+    /// 从不主动 fsync，交给操作系统处理
+    Never,
+}
+
+fn default_snapshot_threshold() -> usize {
+    10000
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "role", rename_all = "snake_case")]
+pub enum ReplicationConfig {
+    /// 不参与复制
+    #[default]
+    Standalone,
+    /// 主节点，允许从节点连接上来同步数据
+    Primary,
+    /// 从节点，从主节点同步数据，只能执行只读的命令
+    Replica(ReplicaConfig),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ReplicaConfig {
+    /// 主节点的地址
+    pub addr: String,
+    /// 连接主节点使用的 TLS 配置
+    pub tls: ClientTlsConfig,
+}
+
+/// ACL 文件的内容：token 到 principal 的映射，以及每个 principal 的权限
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct AclConfig {
+    /// token -> principal，客户端可以用 AUTH 命令通过 token 认证
+    #[serde(default)]
+    pub tokens: HashMap<String, String>,
+    /// principal -> 权限，没有认证的连接使用 anonymous 的权限
+    #[serde(default)]
+    pub principals: HashMap<String, Grants>,
+}
+
+/// 一个 principal 的权限，key 是 table 或者 topic 的名字，"*" 匹配所有的名字
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Grants {
+    #[serde(default)]
+    pub tables: HashMap<String, Vec<Permission>>,
+    #[serde(default)]
+    pub topics: HashMap<String, Vec<Permission>>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Read,
+    Write,
+    Publish,
+    Subscribe,
+}
+
+/// 请求速率和 table 大小的限制
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct LimitsConfig {
+    /// 每个连接的请求速率
+    #[serde(default)]
+    pub connection: Option<RateLimit>,
+    /// 每个 principal 的请求速率，同一个 principal 的所有连接共享
+    #[serde(default)]
+    pub principal: Option<RateLimit>,
+    /// table 中最多有多少个 key，"*" 对没有单独设置的 table 生效
+    #[serde(default)]
+    pub tables: HashMap<String, usize>,
+}
+
+/// 持久化的主题：发布的消息带上序号，并在内存中保留最近的消息，供后来的订阅者重放
+/// 保留的消息不会写入 storage，服务器重启后丢失
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct TopicsConfig {
+    /// 每个主题最多保留多少条消息，"*" 对没有单独设置的主题生效
+    #[serde(default)]
+    pub retain: HashMap<String, usize>,
+    /// 订阅者的 channel 满了之后怎么处理
+    #[serde(default)]
+    pub backpressure: Backpressure,
+}
+
+/// 订阅者处理得太慢，channel 满了之后的策略
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backpressure {
+    /// 不丢弃消息，订阅者处理不过来的消息按顺序缓存在它的 channel 中，不影响其它订阅者。
+    /// 缓存的消息有上限（16K 条），超过之后和 Disconnect 一样断开订阅
+    #[default]
+    Block,
+    /// 丢弃 channel 中最老的消息
+    DropOldest,
+    /// 丢弃新的消息
+    DropNewest,
+    /// 断开订阅，订阅者处理完 channel 中的消息后收到一个错误
+    Disconnect,
+}
+
+impl TopicsConfig {
+    /// 主题保留的消息个数，None 表示不是持久化的主题
+    pub fn retain(&self, topic: &str) -> Option<usize> {
+        let retain = &self.retain;
+        retain.get(topic).or_else(|| retain.get("*")).copied()
+    }
+}
+
+/// key 变化的通知：table 中的 key 被修改后，发布到主题 `__keyspace@<table>__:<key>`
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct KeyspaceConfig {
+    /// 发布通知的 table，"*" 表示所有的 table
+    #[serde(default)]
+    pub tables: Vec<String>,
+}
+
+impl KeyspaceConfig {
+    pub fn enabled(&self, table: &str) -> bool {
+        self.tables.iter().any(|v| v == table || v == "*")
+    }
+}
+
+/// token bucket：每秒补充 rate 个 token，最多积攒 burst 个，每个请求消耗一个
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
 pub struct ServerTlsConfig {
     pub cert: String,
     pub key: String,
     pub ca: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
 pub struct ClientTlsConfig {
     pub domain: String,
     pub identity: Option<(String, String)>,
@@ -51,6 +361,189 @@ impl ServerConfig {
     }
 }
 
+impl ServerConfig {
+    /// 检查配置能否正常使用：地址能否解析，TLS 证书和私钥能否加载，存储路径是否可写，
+    /// 以及 ACL 文件能否加载。启动服务之前调用，尽早发现配置的问题
+    pub fn validate(&self) -> Result<(), KvError> {
+        let listeners = self.effective_listeners();
+        for listener in listeners.iter() {
+            match listener {
+                ListenerConfig::Tls { addr } | ListenerConfig::Tcp { addr } => {
+                    addr.to_socket_addrs()
+                        .map_err(|e| KvError::InvalidConfig(format!("address {}: {}", addr, e)))?;
+                }
+                ListenerConfig::Unix { path } => {
+                    let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+                    check_dir("unix socket directory", &dir.to_string_lossy())?;
+                }
+            }
+        }
+
+        if listeners
+            .iter()
+            .any(|v| matches!(v, ListenerConfig::Tls { .. }))
+        {
+            let tls = &self.tls;
+            TlsServerAcceptor::new(&tls.cert, &tls.key, tls.ca.as_deref())
+                .map_err(|e| KvError::InvalidConfig(format!("tls: {}", e)))?;
+        }
+
+        match &self.storage {
+            StorageConfig::MemTable => {}
+            StorageConfig::SledDb(path) => check_dir("storage path", path)?,
+            StorageConfig::WalMemTable(wal) => check_dir("storage path", &wal.path)?,
+        }
+
+        if let ReplicationConfig::Replica(replica) = &self.replication {
+            let tls = &replica.tls;
+            let identity = tls.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
+            TlsClientConnector::new(&tls.domain, identity, tls.ca.as_deref())
+                .map_err(|e| KvError::InvalidConfig(format!("replication tls: {}", e)))?;
+        }
+
+        if let Some(metrics) = &self.metrics {
+            let addr = &metrics.addr;
+            addr.to_socket_addrs()
+                .map_err(|e| KvError::InvalidConfig(format!("metrics address {}: {}", addr, e)))?;
+        }
+
+        let limits = &self.limits;
+        for limit in [&limits.connection, &limits.principal]
+            .into_iter()
+            .flatten()
+        {
+            if limit.rate.is_nan() || limit.rate <= 0.0 || limit.burst == 0 {
+                return Err(KvError::InvalidConfig(format!(
+                    "rate limit {:?}: rate and burst must be positive",
+                    limit
+                )));
+            }
+        }
+
+        if let Some((topic, _)) = self.topics.retain.iter().find(|(_, n)| **n == 0) {
+            return Err(KvError::InvalidConfig(format!(
+                "topic {}: retain must be positive",
+                topic
+            )));
+        }
+
+        if let Some(path) = &self.acl {
+            AclConfig::load(path)
+                .map_err(|e| KvError::InvalidConfig(format!("acl {}: {}", path, e)))?;
+        }
+
+        Ok(())
+    }
+
+    /// 实际监听的地址，没有配置 listeners 时使用 general.addr 上的 TLS
+    pub fn effective_listeners(&self) -> Vec<ListenerConfig> {
+        default_listeners(&self.listeners, &self.general)
+    }
+
+    /// 用命令行或者环境变量中的地址覆盖监听地址：没有配置 listeners 时覆盖 general.addr，
+    /// 否则覆盖唯一的 TLS/TCP listener 的地址，有多个 TLS/TCP listener 时无法确定覆盖哪一个，返回错误
+    pub fn override_addr(&mut self, addr: String) -> Result<(), KvError> {
+        if self.listeners.is_empty() {
+            self.general.addr = addr;
+            return Ok(());
+        }
+
+        let mut targets = self.listeners.iter_mut().filter_map(|v| match v {
+            ListenerConfig::Tls { addr } | ListenerConfig::Tcp { addr } => Some(addr),
+            ListenerConfig::Unix { .. } => None,
+        });
+        match (targets.next(), targets.next()) {
+            (Some(target), None) => {
+                *target = addr;
+                Ok(())
+            }
+            (None, _) => Err(KvError::InvalidConfig(format!(
+                "address {}: no tls or tcp listener to override",
+                addr
+            ))),
+            (Some(_), Some(_)) => Err(KvError::InvalidConfig(format!(
+                "address {}: more than one tls or tcp listener to override",
+                addr
+            ))),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// 实际连接的地址，没有配置 listeners 时使用 general.addr 上的 TLS
+    pub fn effective_listeners(&self) -> Vec<ListenerConfig> {
+        default_listeners(&self.listeners, &self.general)
+    }
+}
+
+fn default_listeners(listeners: &[ListenerConfig], general: &GeneralConfig) -> Vec<ListenerConfig> {
+    match listeners {
+        [] => vec![ListenerConfig::Tls {
+            addr: general.addr.clone(),
+        }],
+        _ => listeners.to_vec(),
+    }
+}
+
+/// 目录要么已经存在，要么会在第一个已经存在的上级目录中创建，它们都必须是可写的目录
+fn check_dir(name: &str, path: &str) -> Result<(), KvError> {
+    let invalid = |reason: &str| KvError::InvalidConfig(format!("{} {}: {}", name, path, reason));
+
+    let mut dir = PathBuf::from(path);
+    while !dir.exists() {
+        dir = match dir.parent() {
+            Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+    }
+
+    let metadata = fs::metadata(&dir)?;
+    if !metadata.is_dir() {
+        return Err(invalid(&format!("{} is not a directory", dir.display())));
+    }
+    if metadata.permissions().readonly() {
+        return Err(invalid(&format!("{} is read only", dir.display())));
+    }
+    Ok(())
+}
+
+/// 命令行中的存储配置：memtable、sled:<path> 或者 wal:<path>
+impl FromStr for StorageConfig {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s.eq_ignore_ascii_case("memtable") => Ok(Self::MemTable),
+            Some(("sled", path)) if !path.is_empty() => Ok(Self::SledDb(path.into())),
+            Some(("wal", path)) if !path.is_empty() => Ok(Self::WalMemTable(WalConfig {
+                path: path.into(),
+                fsync: FsyncPolicy::default(),
+                snapshot_threshold: default_snapshot_threshold(),
+            })),
+            _ => Err(KvError::InvalidConfig(format!(
+                "storage {}: expect memtable, sled:<path> or wal:<path>",
+                s
+            ))),
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn load(path: &str) -> Result<Self, KvError> {
+        let config = fs::read_to_string(path)?;
+        let config: Self = toml::from_str(&config)?;
+        Ok(config)
+    }
+}
+
+impl AclConfig {
+    pub fn load(path: &str) -> Result<Self, KvError> {
+        let config = fs::read_to_string(path)?;
+        let config: Self = toml::from_str(&config)?;
+        Ok(config)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -62,6 +555,260 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn wal_storage_config_should_be_loaded() {
+        let config = r#"
+            type = "WalMemTable"
+            args = { path = "/tmp/kv_wal", fsync = "always" }
+        "#;
+        let result: StorageConfig = toml::from_str(config).unwrap();
+        assert_eq!(
+            result,
+            StorageConfig::WalMemTable(WalConfig {
+                path: "/tmp/kv_wal".into(),
+                fsync: FsyncPolicy::Always,
+                snapshot_threshold: 10000,
+            })
+        );
+    }
+
+    #[test]
+    fn replication_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.replication, ReplicationConfig::Standalone);
+
+        let config = r#"
+            role = "replica"
+            addr = "127.0.0.1:9527"
+            tls = { domain = "kvserver.acme.inc" }
+        "#;
+        let result: ReplicationConfig = toml::from_str(config).unwrap();
+        assert_eq!(
+            result,
+            ReplicationConfig::Replica(ReplicaConfig {
+                addr: "127.0.0.1:9527".into(),
+                tls: ClientTlsConfig {
+                    domain: "kvserver.acme.inc".into(),
+                    identity: None,
+                    ca: None,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn acl_config_should_be_loaded() {
+        let config: AclConfig = toml::from_str(include_str!("../fixtures/acl.conf")).unwrap();
+        assert_eq!(config.tokens["admin-secret-token"], "admin");
+
+        let grants = &config.principals["awesome-device-id"];
+        assert_eq!(
+            grants.tables["device"],
+            vec![Permission::Read, Permission::Write]
+        );
+        assert_eq!(grants.topics["device-events"], vec![Permission::Publish]);
+        assert!(config.principals["anonymous"].topics.is_empty());
+    }
+
+    #[test]
+    fn storage_config_should_be_parsed_from_str() {
+        assert_eq!(
+            "memtable".parse::<StorageConfig>().unwrap(),
+            StorageConfig::MemTable
+        );
+        assert_eq!(
+            "sled:/tmp/kv".parse::<StorageConfig>().unwrap(),
+            StorageConfig::SledDb("/tmp/kv".into())
+        );
+        let wal = "wal:/tmp/kv_wal".parse::<StorageConfig>().unwrap();
+        assert!(matches!(wal, StorageConfig::WalMemTable(v) if v.path == "/tmp/kv_wal"));
+
+        assert!("sled:".parse::<StorageConfig>().is_err());
+        assert!("redis:/tmp".parse::<StorageConfig>().is_err());
+    }
+
+    #[test]
+    fn server_config_should_be_validated() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert!(config.validate().is_ok());
+
+        let mut bad = config.clone();
+        bad.tls.key = "not a key".into();
+        let err = bad.validate().unwrap_err();
+        assert!(err.to_string().contains("tls"));
+
+        // 存储路径的上级是一个文件，无法创建目录
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().join("db").to_string_lossy().into_owned();
+        let mut bad = config.clone();
+        bad.storage = StorageConfig::SledDb(path);
+        let err = bad.validate().unwrap_err();
+        assert!(err.to_string().contains("not a directory"));
+
+        let mut bad = config.clone();
+        bad.acl = Some("/nonexistent/acl.conf".into());
+        assert!(bad.validate().is_err());
+
+        let mut bad = config;
+        bad.metrics = Some(MetricsConfig {
+            addr: "not an address".into(),
+        });
+        let err = bad.validate().unwrap_err();
+        assert!(err.to_string().contains("metrics address"));
+    }
+
+    #[test]
+    fn addr_should_override_listeners() {
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        config.override_addr("127.0.0.1:1234".into()).unwrap();
+        assert_eq!(config.general.addr, "127.0.0.1:1234");
+
+        // 只有一个 TLS/TCP listener 时覆盖它的地址
+        config.listeners = vec![
+            ListenerConfig::Tcp {
+                addr: "127.0.0.1:5678".into(),
+            },
+            ListenerConfig::Unix {
+                path: "/tmp/kv.sock".into(),
+            },
+        ];
+        config.override_addr("127.0.0.1:1234".into()).unwrap();
+        assert_eq!(
+            config.effective_listeners()[0],
+            ListenerConfig::Tcp {
+                addr: "127.0.0.1:1234".into()
+            }
+        );
+
+        // 多个 TLS/TCP listener 时无法确定覆盖哪一个
+        config.listeners.push(ListenerConfig::Tls {
+            addr: "127.0.0.1:9527".into(),
+        });
+        let err = config.override_addr("127.0.0.1:1234".into()).unwrap_err();
+        assert!(err.to_string().contains("more than one"));
+    }
+
+    #[test]
+    fn listeners_config_should_be_loaded() {
+        let config = r#"
+            [storage]
+            type = "MemTable"
+
+            [[listeners]]
+            type = "tcp"
+            addr = "127.0.0.1:9528"
+
+            [[listeners]]
+            type = "unix"
+            path = "/tmp/kv.sock"
+        "#;
+        let config: ServerConfig = toml::from_str(config).unwrap();
+        assert_eq!(
+            config.effective_listeners(),
+            vec![
+                ListenerConfig::Tcp {
+                    addr: "127.0.0.1:9528".into()
+                },
+                ListenerConfig::Unix {
+                    path: "/tmp/kv.sock".into()
+                },
+            ]
+        );
+        // 没有 TLS listener 时不需要证书
+        assert!(config.validate().is_ok());
+
+        // 没有配置 listeners 时使用 general.addr 上的 TLS
+        let config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf")).unwrap();
+        assert_eq!(
+            config.effective_listeners(),
+            vec![ListenerConfig::Tls {
+                addr: "127.0.0.1:9527".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn limits_config_should_be_loaded() {
+        let config = r#"
+            connection = { rate = 100.0, burst = 200 }
+            [tables]
+            "*" = 10000
+            device = 100
+        "#;
+        let limits: LimitsConfig = toml::from_str(config).unwrap();
+        assert_eq!(
+            limits.connection,
+            Some(RateLimit {
+                rate: 100.0,
+                burst: 200
+            })
+        );
+        assert_eq!(limits.principal, None);
+        assert_eq!(limits.tables["device"], 100);
+
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.limits, LimitsConfig::default());
+        config.limits.principal = Some(RateLimit {
+            rate: 0.0,
+            burst: 10,
+        });
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn topics_config_should_be_loaded() {
+        let config = r#"
+            [retain]
+            "*" = 100
+            orders = 10000
+        "#;
+        let topics: TopicsConfig = toml::from_str(config).unwrap();
+        assert_eq!(topics.backpressure, Backpressure::Block);
+        assert_eq!(topics.retain("orders"), Some(10000));
+        assert_eq!(topics.retain("lobby"), Some(100));
+        assert_eq!(TopicsConfig::default().retain("lobby"), None);
+
+        let topics: TopicsConfig = toml::from_str(r#"backpressure = "drop_oldest""#).unwrap();
+        assert_eq!(topics.backpressure, Backpressure::DropOldest);
+
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.topics, TopicsConfig::default());
+        config.topics.retain.insert("orders".into(), 0);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn compression_config_should_be_loaded() {
+        let config: CompressionConfig = toml::from_str(r#"codec = "zstd""#).unwrap();
+        assert_eq!(config.codec, Codec::Zstd);
+        assert_eq!(config.threshold, CompressionConfig::default().threshold);
+        assert_eq!("lz4".parse::<Codec>().unwrap(), Codec::Lz4);
+        assert!("snappy".parse::<Codec>().is_err());
+
+        // 对端不支持时退回 gzip，threshold 不变
+        assert_eq!(config.negotiate(&Codec::ALL), config);
+        let legacy = config.negotiate(&Codec::LEGACY);
+        assert_eq!(legacy.codec, Codec::Gzip);
+        assert_eq!(legacy.threshold, config.threshold);
+
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.compression, CompressionConfig::default());
+    }
+
+    #[test]
+    fn keyspace_config_should_be_loaded() {
+        let keyspace: KeyspaceConfig = toml::from_str(r#"tables = ["users"]"#).unwrap();
+        assert!(keyspace.enabled("users"));
+        assert!(!keyspace.enabled("orders"));
+
+        let keyspace: KeyspaceConfig = toml::from_str(r#"tables = ["*"]"#).unwrap();
+        assert!(keyspace.enabled("orders"));
+        assert!(!KeyspaceConfig::default().enabled("users"));
+    }
+
     #[test]
     fn client_config_should_be_loaded() {
         let result: Result<ClientConfig, toml::de::Error> =
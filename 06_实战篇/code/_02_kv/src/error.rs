@@ -6,6 +6,22 @@ pub enum KvError {
     NotFound(String),
     #[error("Frame is larger than max size")]
     FrameError,
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Transaction aborted at command {0}")]
+    TransactionAborted(usize),
+    #[error("Cannot execute write command on a read only replica")]
+    ReadOnly,
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Too many requests: {0}")]
+    RateLimited(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Subscriber is too slow: {0}")]
+    SlowSubscriber(String),
     #[error("Command is invalid: `{0}`")]
     InvalidCommand(String),
     #[error("Cannot convert value {0} to {1}")]
@@ -29,7 +45,16 @@ pub enum KvError {
     YamuxConnectionError(#[from] yamux::ConnectionError),
     #[error("Parse config error")]
     ConfigError(#[from] toml::de::Error),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
 
     #[error("Internal error: {0}")]
     Internal(String),
 }
+
+/// 不会失败的转换（比如 Value 转换成 Value）也可以用在需要返回 KvError 的地方
+impl From<std::convert::Infallible> for KvError {
+    fn from(e: std::convert::Infallible) -> Self {
+        match e {}
+    }
+}
@@ -1,83 +1,572 @@
 mod config;
 mod error;
+pub mod metrics;
 mod network;
 mod pb;
 mod service;
 mod storage;
 
-use std::time::Duration;
-
 pub use config::*;
 pub use error::*;
 pub use network::*;
 pub use pb::abi::*;
+pub use pb::Json;
 pub use service::*;
 pub use storage::*;
 
-use anyhow::Result;
-use tokio::{
-    net::{TcpListener, TcpStream},
-    time,
-};
-use tokio_rustls::client;
-use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tracing::info;
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use tokio::{net::TcpListener, signal, time};
+use tokio_util::{compat::FuturesAsyncReadCompatExt, sync::CancellationToken, task::TaskTracker};
+use tracing::{info, warn};
+
+/// 后台清理过期 key 的间隔
+const EXPIRATION_INTERVAL: Duration = Duration::from_secs(1);
+/// 从节点和主节点断开后，重新连接的间隔
+const REPLICA_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// 服务器关闭时，等待正在处理的 stream 结束的最长时间
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// accept 失败后第一次重试前等待的时间，之后每次翻倍
+const ACCEPT_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+/// accept 失败后重试前最多等待的时间
+const ACCEPT_MAX_BACKOFF: Duration = Duration::from_secs(1);
 
-/// 通过配置创建KV服务器
+/// 通过配置创建KV服务器，收到 ctrl-c 或者 SIGTERM 后关闭服务器并返回
 pub async fn start_server_with_config(config: &ServerConfig) -> Result<()> {
-    let acceptor =
-        TlsServerAcceptor::new(&config.tls.cert, &config.tls.key, config.tls.ca.as_deref())?;
+    let shutdown = CancellationToken::new();
+    let token = shutdown.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Received shutdown signal");
+        token.cancel();
+    });
 
-    let addr = &config.general.addr;
+    start_server_with_shutdown(config, shutdown).await
+}
+
+/// 通过配置创建KV服务器，所有的 listener 共享同一个 Service。
+/// shutdown 被 cancel 后不再接受新的连接，等待正在处理的 stream 结束（最多 SHUTDOWN_TIMEOUT），把数据写到磁盘后返回
+pub async fn start_server_with_shutdown(
+    config: &ServerConfig,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let mut listeners = Vec::new();
+    for listener in config.effective_listeners() {
+        listeners.push(Listener::bind(&listener, &config.tls).await?);
+    }
+    let metrics = match &config.metrics {
+        Some(metrics) => Some(TcpListener::bind(&metrics.addr).await?),
+        None => None,
+    };
 
     match &config.storage {
-        StorageConfig::MemTable => start_tls_server(addr, MemTable::new(), acceptor).await?,
-        StorageConfig::SledDb(path) => start_tls_server(addr, SledDb::new(path), acceptor).await?,
+        StorageConfig::MemTable => {
+            let store = MemTable::new();
+            start_server_with_store(config, store, listeners, metrics, shutdown).await?
+        }
+        StorageConfig::SledDb(path) => {
+            let store = SledDb::new(path);
+            start_server_with_store(config, store, listeners, metrics, shutdown).await?
+        }
+        StorageConfig::WalMemTable(wal) => {
+            let store = WalMemTable::new(wal)?;
+            start_server_with_store(config, store, listeners, metrics, shutdown).await?
+        }
     }
 
     Ok(())
 }
 
-/// 通过配置创建KV客户端
+/// 通过配置创建KV客户端，依次尝试配置中的地址，使用第一个连接成功的
 pub async fn start_client_with_config(
     config: &ClientConfig,
-) -> Result<YamuxCtrl<client::TlsStream<TcpStream>>> {
-    let addr = &config.general.addr;
-    let tls = &config.tls;
-    let identity = tls.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
-    let connector = TlsClientConnector::new(&tls.domain, identity, tls.ca.as_deref())?;
-    let stream = TcpStream::connect(addr).await?;
-    let stream = connector.connect(stream).await?;
-    Ok(YamuxCtrl::new_client(stream, None))
+) -> Result<YamuxCtrl<Box<dyn Transport>>> {
+    let (ctrl, _) = connect_client(config).await?;
+    Ok(ctrl)
 }
 
-async fn start_tls_server<Store: Storage>(
-    addr: &str,
+/// 根据主从复制的角色创建 Service，然后启动服务器
+async fn start_server_with_store<Store: Storage>(
+    config: &ServerConfig,
     store: Store,
-    acceptor: TlsServerAcceptor,
+    listeners: Vec<Listener>,
+    metrics: Option<TcpListener>,
+    shutdown: CancellationToken,
 ) -> Result<()> {
-    let service: Service<Store> = ServiceInner::new(store).into();
-    let listener = TcpListener::bind(addr).await?;
-    info!("Start listening on {addr}");
+    let acl = config.acl.as_deref().map(AclConfig::load).transpose()?;
+    let limits = config.limits.clone();
+    let topics = config.topics.clone();
+    let keyspace = config.keyspace.clone();
+    let compression = config.compression;
+    let enabled = metrics.is_some();
+    match &config.replication {
+        ReplicationConfig::Standalone => {
+            let inner = ServiceInner::new(store)
+                .acl(acl)
+                .limits(limits)
+                .topics(topics)
+                .keyspace(keyspace)
+                .compression(compression)
+                .metrics(enabled);
+            let service: Service<Store> = inner.into();
+            serve(listeners, metrics, service, shutdown).await
+        }
+        ReplicationConfig::Primary => {
+            let log = ReplicationLog::default();
+            let store = ReplicatedStorage::new(store, log.clone());
+            let role = ReplicationRole::Primary(log);
+            let inner = ServiceInner::new(store)
+                .replication(role)
+                .acl(acl)
+                .limits(limits)
+                .topics(topics)
+                .keyspace(keyspace)
+                .compression(compression)
+                .metrics(enabled);
+            let service: Service<_> = inner.into();
+            serve(listeners, metrics, service, shutdown).await
+        }
+        ReplicationConfig::Replica(config) => {
+            let state = Arc::new(ReplicaState::default());
+            let role = ReplicationRole::Replica(state.clone());
+            let inner = ServiceInner::new(store)
+                .replication(role)
+                .acl(acl)
+                .limits(limits)
+                .topics(topics)
+                .keyspace(keyspace)
+                .compression(compression)
+                .metrics(enabled);
+            let service: Service<Store> = inner.into();
+            let replication =
+                tokio::spawn(start_replication(service.clone(), state, config.clone()));
+            let result = serve(listeners, metrics, service, shutdown).await;
+            replication.abort();
+            result
+        }
+    }
+}
 
+/// 从节点持续从主节点同步数据，断开后自动重新连接并全量同步
+async fn start_replication<Store: Storage>(
+    service: Service<Store>,
+    state: Arc<ReplicaState>,
+    config: ReplicaConfig,
+) {
     loop {
-        let tls = acceptor.clone();
-        let (stream, addr) = listener.accept().await?;
-        info!("Client {addr:?} connected");
+        match replicate_from(&service, &config).await {
+            Ok(_) => warn!("Replication stream from {} is closed", config.addr),
+            Err(e) => warn!("Failed to replicate from {}: {:?}", config.addr, e),
+        }
+        state.disconnected();
+        time::sleep(REPLICA_RECONNECT_INTERVAL).await;
+    }
+}
 
-        let svc = service.clone();
-        tokio::spawn(async move {
-            let stream = tls.accept(stream).await.unwrap();
-            YamuxCtrl::new_server(stream, None, move |stream| {
-                let svc1 = svc.clone();
-                async move {
-                    let stream = ProstServerStream::new(stream.compat(), svc1.clone());
-                    // 延迟100ms处理
-                    // time::sleep(Duration::from_millis(100)).await;
-                    stream.process().await.unwrap();
-                    Ok(())
+async fn replicate_from<Store: Storage>(
+    service: &Service<Store>,
+    config: &ReplicaConfig,
+) -> Result<()> {
+    let listener = ListenerConfig::Tls {
+        addr: config.addr.clone(),
+    };
+    let stream = connect(&listener, &config.tls).await?;
+    let mut ctrl = YamuxCtrl::new_client(stream, None);
+    let client = ctrl.open_stream().await?;
+    let mut stream = client
+        .execute_chunked(&CommandRequest::new_replicate())
+        .await?;
+    info!("Start replicating from {}", config.addr);
+
+    // 主节点空闲时也会发送心跳，太久没有收到数据说明连接已经不可用了
+    let timeout = HEARTBEAT_INTERVAL * 3;
+    while let Some(res) = time::timeout(timeout, stream.next())
+        .await
+        .map_err(|_| anyhow!("No data from primary in {:?}", timeout))?
+    {
+        service.apply_replication(&res?)?;
+    }
+
+    Ok(())
+}
+
+/// 所有的 listener 共享的状态
+struct ServerState<Store> {
+    service: Service<Store>,
+    /// 记录所有正在处理的 stream，关闭服务器时等待它们结束
+    tracker: TaskTracker,
+    shutdown: CancellationToken,
+    /// 等待结束之后（或者超时），关闭所有的连接
+    closed: CancellationToken,
+}
+
+impl<Store> Clone for ServerState<Store> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            tracker: self.tracker.clone(),
+            shutdown: self.shutdown.clone(),
+            closed: self.closed.clone(),
+        }
+    }
+}
+
+async fn serve<Store: Storage>(
+    listeners: Vec<Listener>,
+    metrics: Option<TcpListener>,
+    service: Service<Store>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let expiration = service.start_expiration(EXPIRATION_INTERVAL);
+    let state = ServerState {
+        service,
+        tracker: TaskTracker::new(),
+        shutdown,
+        closed: CancellationToken::new(),
+    };
+
+    let mut accepting: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| tokio::spawn(state.clone().accept(listener)))
+        .collect();
+    if let Some(listener) = metrics {
+        let service = state.service.clone();
+        let metrics = metrics::serve_metrics(listener, service, state.shutdown.clone());
+        accepting.push(tokio::spawn(metrics));
+    }
+    // shutdown 之后每个 listener 都会停止接受新的连接并被释放
+    for handle in accepting {
+        handle.await?;
+    }
+
+    // 等待正在处理的 stream 结束
+    let tracker = &state.tracker;
+    tracker.close();
+    info!("Waiting for {} streams to finish", tracker.len());
+    let drained = time::timeout(SHUTDOWN_TIMEOUT, tracker.wait()).await;
+    if drained.is_err() {
+        warn!("{} streams are not finished in time", tracker.len());
+    }
+
+    state.closed.cancel();
+    expiration.abort();
+    state.service.flush()?;
+    info!("Server is stopped");
+    Ok(())
+}
+
+/// accept 失败后重试的退避：比如文件描述符用完了（EMFILE），马上重试还是会失败，等一会儿再试。
+/// 每次失败等待的时间翻倍，accept 成功后重置
+pub(crate) struct AcceptBackoff(Duration);
+
+impl Default for AcceptBackoff {
+    fn default() -> Self {
+        Self(ACCEPT_INITIAL_BACKOFF)
+    }
+}
+
+impl AcceptBackoff {
+    /// 下次重试前要等待的时间
+    pub(crate) fn delay(&self) -> Duration {
+        self.0
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.0 = ACCEPT_INITIAL_BACKOFF;
+    }
+
+    /// 等待之后再重试，等待期间 shutdown 被 cancel 时返回 false
+    pub(crate) async fn wait(&mut self, shutdown: &CancellationToken) -> bool {
+        tokio::select! {
+            _ = time::sleep(self.0) => {}
+            _ = shutdown.cancelled() => return false,
+        }
+        self.0 = (self.0 * 2).min(ACCEPT_MAX_BACKOFF);
+        true
+    }
+}
+
+impl<Store: Storage> ServerState<Store> {
+    async fn accept(self, listener: Listener) {
+        let mut backoff = AcceptBackoff::default();
+        loop {
+            let res = tokio::select! {
+                res = listener.accept() => res,
+                _ = self.shutdown.cancelled() => break,
+            };
+            let (incoming, addr) = match res {
+                Ok(v) => {
+                    backoff.reset();
+                    v
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to accept connection on {}, retry in {:?}: {:?}",
+                        listener.name(),
+                        backoff.delay(),
+                        e
+                    );
+                    if !backoff.wait(&self.shutdown).await {
+                        break;
+                    }
+                    continue;
                 }
-            });
+            };
+            info!("Client {addr} connected");
+            tokio::spawn(self.clone().handle(incoming, addr));
+        }
+    }
+
+    async fn handle(self, incoming: Incoming, addr: String) {
+        let (stream, principal) = match incoming.establish().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to establish connection with {addr}: {:?}", e);
+                return;
+            }
+        };
+
+        // 客户端证书的 subject 作为连接的 principal，之后也可以通过 AUTH 命令修改
+        let session = Session::new(principal);
+        info!("Client {addr} is authenticated as {}", session.principal());
+
+        let state = self.clone();
+        let peer = addr.clone();
+        let connection = metrics::track_connection();
+        let mut ctrl = YamuxCtrl::new_server(stream, None, move |stream| {
+            // 连接断开时 closure 被释放，connection 随之减一
+            let _ = &connection;
+            let stream = ProstServerStream::new(stream.compat(), state.service.clone())
+                .with_session(session.clone())
+                .with_shutdown(state.shutdown.clone());
+            let peer = peer.clone();
+            state.tracker.track_future(async move {
+                let _stream = metrics::track_stream();
+                if let Err(e) = stream.process().await {
+                    warn!("Failed to process stream from {peer}: {:?}", e);
+                }
+                Ok(())
+            })
         });
+
+        // 客户端断开后 task 就退出，不用等到服务器关闭
+        let disconnected = ctrl.closed();
+        tokio::select! {
+            _ = disconnected => {
+                info!("Client {addr} disconnected");
+                return;
+            }
+            _ = self.closed.cancelled() => {}
+        }
+        if let Err(e) = ctrl.close().await {
+            warn!("Failed to close connection with {addr}: {:?}", e);
+        }
+    }
+}
+
+/// 等待 ctrl-c 或者 SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen to SIGTERM: {:?}", e);
+                futures::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn replica_should_sync_from_primary() -> Result<()> {
+        let mut primary: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+        let mut client: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+        primary.general.addr = free_addr().await?;
+        primary.storage = StorageConfig::MemTable;
+        primary.replication = ReplicationConfig::Primary;
+
+        let mut replica = primary.clone();
+        replica.general.addr = free_addr().await?;
+        replica.replication = ReplicationConfig::Replica(ReplicaConfig {
+            addr: primary.general.addr.clone(),
+            tls: client.tls.clone(),
+        });
+
+        client.general.addr = primary.general.addr.clone();
+        tokio::spawn(async move { start_server_with_config(&primary).await.unwrap() });
+        time::sleep(Duration::from_millis(100)).await;
+
+        // 先写入的数据通过全量同步到达从节点
+        let mut ctrl = start_client_with_config(&client).await?;
+        let mut stream = ctrl.open_stream().await?;
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_eq!(stream.execute_unary(&cmd).await?.status, 200);
+
+        let replica_addr = replica.general.addr.clone();
+        tokio::spawn(async move { start_server_with_config(&replica).await.unwrap() });
+        time::sleep(Duration::from_millis(100)).await;
+
+        // 之后的修改通过增量同步到达从节点
+        let cmd = CommandRequest::new_hset("t1", "k2", "v2".into());
+        assert_eq!(stream.execute_unary(&cmd).await?.status, 200);
+
+        client.general.addr = replica_addr;
+        let mut ctrl = start_client_with_config(&client).await?;
+        let mut stream = ctrl.open_stream().await?;
+        let cmd = CommandRequest::new_hmget("t1", vec!["k1".into(), "k2".into()]);
+        let mut values = vec![];
+        for _ in 0..20 {
+            values = stream.execute_unary(&cmd).await?.values;
+            if values == [Value::from("v1"), Value::from("v2")] {
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(values, [Value::from("v1"), Value::from("v2")]);
+
+        // 从节点拒绝写入
+        let cmd = CommandRequest::new_hset("t1", "k3", "v3".into());
+        assert_eq!(stream.execute_unary(&cmd).await?.status, 403);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_shutdown_gracefully() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db").to_string_lossy().into_owned();
+        let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+        let mut client: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+        config.general.addr = free_addr().await?;
+        config.storage = StorageConfig::SledDb(path.clone());
+        client.general.addr = config.general.addr.clone();
+
+        let shutdown = CancellationToken::new();
+        let token = shutdown.clone();
+        let server = tokio::spawn(async move { start_server_with_shutdown(&config, token).await });
+        time::sleep(Duration::from_millis(100)).await;
+
+        let mut ctrl = start_client_with_config(&client).await?;
+        let mut stream = ctrl.open_stream().await?;
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_eq!(stream.execute_unary(&cmd).await?.status, 200);
+
+        // 空闲的 stream 会被关闭，服务器不用等到超时就能返回
+        shutdown.cancel();
+        time::timeout(Duration::from_secs(5), server).await???;
+        assert!(stream.execute_unary(&cmd).await.is_err());
+        assert!(TcpStream::connect(&client.general.addr).await.is_err());
+
+        // 服务器关闭所有的连接后 Service 被释放，重新打开数据库可以读到之前写入的数据
+        time::sleep(Duration::from_millis(100)).await;
+        let store = SledDb::new(&path);
+        assert_eq!(store.get("t1", "k1")?, Some("v1".into()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_serve_all_listeners() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let socket = dir.path().join("kv.sock").to_string_lossy().into_owned();
+        let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+        config.storage = StorageConfig::MemTable;
+        config.listeners = vec![
+            ListenerConfig::Tcp {
+                addr: free_addr().await?,
+            },
+            ListenerConfig::Unix {
+                path: socket.clone(),
+            },
+        ];
+        let listeners = config.listeners.clone();
+
+        let shutdown = CancellationToken::new();
+        let token = shutdown.clone();
+        let server = tokio::spawn(async move { start_server_with_shutdown(&config, token).await });
+        time::sleep(Duration::from_millis(100)).await;
+
+        // 通过 TCP 写入的数据可以通过 Unix domain socket 读到，它们使用同一个 Service
+        let mut client = ClientConfig {
+            general: GeneralConfig::default(),
+            tls: ClientTlsConfig::default(),
+            listeners: vec![listeners[0].clone()],
+            pool: PoolConfig::default(),
+            compression: Default::default(),
+        };
+        let mut ctrl = start_client_with_config(&client).await?;
+        let mut stream = ctrl.open_stream().await?;
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_eq!(stream.execute_unary(&cmd).await?.status, 200);
+
+        client.listeners = vec![listeners[1].clone()];
+        let mut ctrl = start_client_with_config(&client).await?;
+        let mut stream = ctrl.open_stream().await?;
+        let res = stream
+            .execute_unary(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.values, [Value::from("v1")]);
+
+        // 连接不上、或者连接上了但协商失败的地址都会被跳过
+        let bogus = TcpListener::bind("127.0.0.1:0").await?;
+        let bogus_addr = bogus.local_addr()?.to_string();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = bogus.accept().await {
+                drop(stream);
+            }
+        });
+        client.listeners = vec![
+            ListenerConfig::Unix {
+                path: dir.path().join("none.sock").to_string_lossy().into_owned(),
+            },
+            ListenerConfig::Tcp { addr: bogus_addr },
+            listeners[0].clone(),
+        ];
+        assert!(start_client_with_config(&client).await.is_ok());
+
+        // 关闭服务器时删除 socket 文件
+        shutdown.cancel();
+        time::timeout(Duration::from_secs(5), server).await???;
+        assert!(!Path::new(&socket).exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_backoff_should_double_after_each_wait() {
+        let shutdown = CancellationToken::new();
+        let mut backoff = AcceptBackoff::default();
+        for _ in 0..3 {
+            assert!(backoff.wait(&shutdown).await);
+        }
+        assert_eq!(backoff.delay(), ACCEPT_INITIAL_BACKOFF * 8);
+        backoff.reset();
+        assert_eq!(backoff.delay(), ACCEPT_INITIAL_BACKOFF);
+
+        shutdown.cancel();
+        assert!(!backoff.wait(&shutdown).await);
+    }
+
+    async fn free_addr() -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        Ok(listener.local_addr()?.to_string())
     }
 }
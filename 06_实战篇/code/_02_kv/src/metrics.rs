@@ -0,0 +1,350 @@
+use std::{io, sync::LazyLock, time::Duration};
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder, TEXT_FORMAT,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task, time,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    AcceptBackoff, CommandRequest, CommandResponse, KvError, Service, ServiceInner, Storage,
+};
+
+/// HTTP 请求头的最大长度，超过的请求直接断开
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// 读取 HTTP 请求的超时时间
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// 服务器的所有 metrics，注册在同一个 Registry 中
+struct Metrics {
+    registry: Registry,
+    /// 每种命令收到的次数
+    commands: IntCounterVec,
+    /// 每种 status 的 response 数量
+    responses: IntCounterVec,
+    /// 从收到命令到发出第一个 response 的时间
+    latency: HistogramVec,
+    connections: IntGauge,
+    streams: IntGauge,
+    topics: IntGauge,
+    subscriptions: IntGauge,
+    /// 每个 table 中 key 的数量
+    table_keys: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("kv".into()), None).expect("namespace of metrics is valid");
+
+        let commands = IntCounterVec::new(
+            Opts::new("commands_total", "Number of received commands"),
+            &["command"],
+        )
+        .expect("metric is valid");
+        let responses = IntCounterVec::new(
+            Opts::new("responses_total", "Number of responses by status"),
+            &["status"],
+        )
+        .expect("metric is valid");
+        let latency = HistogramVec::new(
+            HistogramOpts::new(
+                "command_duration_seconds",
+                "Time from receiving a command to sending its first response",
+            ),
+            &["command"],
+        )
+        .expect("metric is valid");
+        let connections = IntGauge::new("connections", "Number of active client connections")
+            .expect("metric is valid");
+        let streams =
+            IntGauge::new("streams", "Number of active yamux streams").expect("metric is valid");
+        let topics =
+            IntGauge::new("topics", "Number of topics with subscribers").expect("metric is valid");
+        let subscriptions = IntGauge::new("subscriptions", "Number of active subscriptions")
+            .expect("metric is valid");
+        let table_keys = IntGaugeVec::new(
+            Opts::new("table_keys", "Number of keys in each table"),
+            &["table"],
+        )
+        .expect("metric is valid");
+
+        let metrics = Self {
+            registry,
+            commands,
+            responses,
+            latency,
+            connections,
+            streams,
+            topics,
+            subscriptions,
+            table_keys,
+        };
+        metrics
+            .register()
+            .expect("metrics are registered only once");
+        metrics
+    }
+
+    fn register(&self) -> prometheus::Result<()> {
+        self.registry.register(Box::new(self.commands.clone()))?;
+        self.registry.register(Box::new(self.responses.clone()))?;
+        self.registry.register(Box::new(self.latency.clone()))?;
+        self.registry.register(Box::new(self.connections.clone()))?;
+        self.registry.register(Box::new(self.streams.clone()))?;
+        self.registry.register(Box::new(self.topics.clone()))?;
+        self.registry
+            .register(Box::new(self.subscriptions.clone()))?;
+        self.registry.register(Box::new(self.table_keys.clone()))
+    }
+}
+
+/// on_received 的 hook，统计每种命令的数量
+pub fn on_received(cmd: &CommandRequest) {
+    METRICS.commands.with_label_values(&[cmd.name()]).inc();
+}
+
+/// on_executed 的 hook，统计每种 status 的数量
+pub fn on_executed(res: &CommandResponse) {
+    let status = res.status.to_string();
+    METRICS.responses.with_label_values(&[&status]).inc();
+}
+
+/// 开始统计命令的延迟，timer 被 drop 或者 observe_duration 时记录
+pub fn start_timer(cmd: &CommandRequest) -> HistogramTimer {
+    METRICS
+        .latency
+        .with_label_values(&[cmd.name()])
+        .start_timer()
+}
+
+/// 活跃连接或者 stream 的计数，guard 被 drop 时减一
+pub struct GaugeGuard(&'static IntGauge);
+
+impl GaugeGuard {
+    fn new(gauge: &'static IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// 记录一个活跃的客户端连接
+pub fn track_connection() -> GaugeGuard {
+    GaugeGuard::new(&METRICS.connections)
+}
+
+/// 记录一个活跃的 yamux stream
+pub fn track_stream() -> GaugeGuard {
+    GaugeGuard::new(&METRICS.streams)
+}
+
+/// 生成 Prometheus 文本格式的 metrics，topic 和 storage 的状态在这时才读取。
+/// 会遍历 storage 中所有的 table，在 async 代码中要放到 spawn_blocking 里调用
+pub fn gather<Store: Storage>(service: &Service<Store>) -> Result<String, KvError> {
+    let broadcaster = service.broadcaster();
+    METRICS.topics.set(broadcaster.topic_count() as i64);
+    METRICS
+        .subscriptions
+        .set(broadcaster.subscription_count() as i64);
+
+    // 删除的 table 不再出现在结果中
+    METRICS.table_keys.reset();
+    for (table, size) in service.table_sizes()? {
+        METRICS
+            .table_keys
+            .with_label_values(&[&table])
+            .set(size as i64);
+    }
+
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&METRICS.registry.gather(), &mut buf)
+        .map_err(|e| KvError::Internal(e.to_string()))?;
+    String::from_utf8(buf).map_err(|e| KvError::Internal(e.to_string()))
+}
+
+impl<Store: Storage> ServiceInner<Store> {
+    /// 通过 on_received/on_executed 统计命令和 response
+    pub fn metrics(self, enabled: bool) -> Self {
+        if !enabled {
+            return self;
+        }
+        self.fn_received(on_received).fn_executed(on_executed)
+    }
+}
+
+/// 提供 HTTP 的 GET /metrics，shutdown 被 cancel 后退出
+pub async fn serve_metrics<Store: Storage>(
+    listener: TcpListener,
+    service: Service<Store>,
+    shutdown: CancellationToken,
+) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Start serving metrics on http://{}/metrics", addr);
+    }
+
+    let mut backoff = AcceptBackoff::default();
+    loop {
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(v) => {
+                    backoff.reset();
+                    v
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to accept metrics connection, retry in {:?}: {:?}",
+                        backoff.delay(),
+                        e
+                    );
+                    if !backoff.wait(&shutdown).await {
+                        break;
+                    }
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
+
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &service).await {
+                warn!("Failed to serve metrics to {}: {:?}", addr, e);
+            }
+        });
+    }
+}
+
+/// 处理一个 HTTP 请求，只支持 GET /metrics，返回后关闭连接
+async fn respond<Store: Storage>(
+    mut stream: TcpStream,
+    service: &Service<Store>,
+) -> io::Result<()> {
+    let request = match time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => request?,
+        Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+    };
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            // table_sizes 可能要遍历整个 storage（比如 sled），不能在 async runtime 上做
+            let service = service.clone();
+            let res = task::spawn_blocking(move || gather(&service))
+                .await
+                .unwrap_or_else(|e| Err(KvError::Internal(e.to_string())));
+            match res {
+                Ok(body) => ("200 OK", body),
+                Err(e) => ("500 Internal Server Error", format!("{}\n", e)),
+            }
+        }
+        _ => ("404 Not Found", "Not Found\n".into()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        TEXT_FORMAT,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// 读取 HTTP 请求头，我们不需要 body
+async fn read_request(stream: &mut TcpStream) -> io::Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|v| v == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request is too large",
+            ));
+        }
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, Value};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn hooks_should_update_metrics() {
+        let service: Service = ServiceInner::new(MemTable::new()).metrics(true).into();
+        let cmd = CommandRequest::new_hset("metrics", "k1", "v1".into());
+        service.execute(cmd).next().await.unwrap();
+        let cmd = CommandRequest::new_hget("metrics", "missing");
+        service.execute(cmd).next().await.unwrap();
+
+        let text = gather(&service).unwrap();
+        assert!(text.contains(r#"kv_commands_total{command="hset"}"#));
+        assert!(text.contains(r#"kv_responses_total{status="404"}"#));
+        assert!(text.contains(r#"kv_table_keys{table="metrics"} 1"#));
+        assert!(text.contains("kv_topics 0"));
+    }
+
+    #[tokio::test]
+    async fn gauge_guard_should_decrease_on_drop() {
+        let before = METRICS.streams.get();
+        let guard = track_stream();
+        assert_eq!(METRICS.streams.get(), before + 1);
+        drop(guard);
+        assert_eq!(METRICS.streams.get(), before);
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_should_work() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).metrics(true).into();
+        let cmd = CommandRequest::new_hset("t1", "k1", Value::from("v1"));
+        service.execute(cmd).next().await.unwrap();
+
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve_metrics(listener, service, shutdown.clone()));
+
+        let res = http_get(&addr.to_string(), "/metrics").await?;
+        assert!(res.starts_with("HTTP/1.1 200 OK"));
+        assert!(res.contains(r#"kv_table_keys{table="t1"} 1"#));
+
+        let res = http_get(&addr.to_string(), "/").await?;
+        assert!(res.starts_with("HTTP/1.1 404"));
+
+        shutdown.cancel();
+        server.await?;
+        Ok(())
+    }
+
+    async fn http_get(addr: &str, path: &str) -> io::Result<String> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await?;
+        Ok(res)
+    }
+}
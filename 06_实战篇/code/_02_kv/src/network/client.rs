@@ -0,0 +1,215 @@
+use futures::{stream, Stream, StreamExt};
+
+use super::KvPool;
+use crate::{ClientConfig, CommandRequest, CommandResponse, KvError, Value};
+
+/// 类型化的客户端，不需要自己构造 CommandRequest 和检查 CommandResponse
+/// 命令通过 KvPool 执行，连接断开后自动重连
+#[derive(Clone)]
+pub struct KvClient {
+    pool: KvPool,
+}
+
+impl KvClient {
+    /// 创建客户端，第一次执行命令时才连接服务器
+    pub fn new(config: ClientConfig) -> Self {
+        KvPool::new(config).into()
+    }
+
+    /// 底层的连接池，用来执行 KvClient 没有封装的命令
+    pub fn pool(&self) -> &KvPool {
+        &self.pool
+    }
+
+    /// 读取 key 的值并转换成 T，key 不存在时返回 None
+    pub async fn get<T>(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<Option<T>, KvError>
+    where
+        T: TryFrom<Value>,
+        KvError: From<T::Error>,
+    {
+        let cmd = CommandRequest::new_hget(table, key);
+        match self.execute(&cmd).await {
+            Ok(res) => Ok(Some(first(res)?.try_into()?)),
+            Err(KvError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 写入 key 的值
+    pub async fn set(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<(), KvError> {
+        let cmd = CommandRequest::new_hset(table, key, value.into());
+        self.execute(&cmd).await?;
+        Ok(())
+    }
+
+    /// 读取多个 key 的值，结果和 keys 的顺序一致，不存在的 key 对应 None
+    pub async fn mget<T>(
+        &self,
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<Vec<Option<T>>, KvError>
+    where
+        T: TryFrom<Value>,
+        KvError: From<T::Error>,
+    {
+        let keys = keys.into_iter().map(Into::into).collect();
+        let res = self
+            .execute(&CommandRequest::new_hmget(table, keys))
+            .await?;
+        res.values
+            .into_iter()
+            .map(|v| {
+                if v == Value::default() {
+                    return Ok(None);
+                }
+                Ok(Some(v.try_into()?))
+            })
+            .collect()
+    }
+
+    /// 删除 key，返回 key 删除之前是否存在
+    pub async fn del(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<bool, KvError> {
+        let res = self.execute(&CommandRequest::new_hdel(table, key)).await?;
+        Ok(first(res)? != Value::default())
+    }
+
+    /// key 是否存在
+    pub async fn exists(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<bool, KvError> {
+        let res = self
+            .execute(&CommandRequest::new_hexist(table, key))
+            .await?;
+        first(res)?.try_into()
+    }
+
+    /// 订阅主题，返回推送的每一个 Value；连接断开后自动重新订阅，drop 之后取消订阅
+    pub async fn subscribe(
+        &self,
+        topic: impl Into<String>,
+    ) -> Result<impl Stream<Item = Value>, KvError> {
+        let subscription = self.pool.subscribe(topic).await?;
+        // 出错的 response（比如订阅者太慢被断开）中没有数据，会被跳过
+        Ok(subscription.flat_map(|res| stream::iter(res.values)))
+    }
+
+    /// 向主题发布数据
+    pub async fn publish(
+        &self,
+        topic: impl Into<String>,
+        values: impl IntoIterator<Item = impl Into<Value>>,
+    ) -> Result<(), KvError> {
+        let values = values.into_iter().map(Into::into).collect();
+        self.execute(&CommandRequest::new_publish(topic, values))
+            .await?;
+        Ok(())
+    }
+
+    /// 执行命令，出错的 response 转换成对应的 KvError
+    async fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        let res = self.pool.execute_unary(cmd).await?;
+        if !(200..300).contains(&res.status) {
+            return Err(res.into());
+        }
+        Ok(res)
+    }
+}
+
+impl From<KvPool> for KvClient {
+    fn from(pool: KvPool) -> Self {
+        Self { pool }
+    }
+}
+
+/// 只返回一个 Value 的命令的结果
+fn first(res: CommandResponse) -> Result<Value, KvError> {
+    match res.values.into_iter().next() {
+        Some(v) => Ok(v),
+        None => Err(KvError::Internal("Response has no value".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{
+        utils::{start_yamux_server, tcp_client_config},
+        Codec, CompressionConfig,
+    };
+
+    #[tokio::test]
+    async fn typed_client_should_work() -> Result<()> {
+        let client = KvClient::new(client_config(&start_yamux_server().await?));
+
+        client.set("t1", "k1", "v1").await?;
+        client.set("t1", "k2", 42).await?;
+        assert_eq!(client.get::<String>("t1", "k1").await?, Some("v1".into()));
+        assert_eq!(client.get::<i64>("t1", "k2").await?, Some(42));
+        assert_eq!(client.get::<i64>("t1", "k3").await?, None);
+
+        let values = client.mget::<i64>("t1", ["k2", "k3"]).await?;
+        assert_eq!(values, [Some(42), None]);
+
+        assert!(client.exists("t1", "k1").await?);
+        assert!(client.del("t1", "k1").await?);
+        assert!(!client.del("t1", "k1").await?);
+        assert!(!client.exists("t1", "k1").await?);
+
+        // 类型不对时返回转换错误
+        client.set("t1", "k4", "not a number").await?;
+        let res = client.get::<i64>("t1", "k4").await;
+        assert!(matches!(res, Err(KvError::ConvertError(..))));
+
+        let mut messages = client.subscribe("lobby").await?;
+        client.publish("lobby", ["hello", "world"]).await?;
+        assert_eq!(messages.next().await, Some("hello".into()));
+        assert_eq!(messages.next().await, Some("world".into()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn error_response_should_be_converted_to_kv_error() {
+        let errors = [
+            KvError::NotFound("table t1, key k1".into()),
+            KvError::Conflict("k1".into()),
+            KvError::ReadOnly,
+            KvError::PermissionDenied("alice".into()),
+            KvError::RateLimited("alice".into()),
+            KvError::InvalidCommand("hget".into()),
+        ];
+        for e in errors {
+            let expected = e.to_string();
+            let res: CommandResponse = e.into();
+            assert_eq!(KvError::from(res).to_string(), expected);
+        }
+    }
+
+    fn client_config(addr: &str) -> ClientConfig {
+        ClientConfig {
+            // 所有的请求都用 zstd 压缩
+            compression: CompressionConfig {
+                codec: Codec::Zstd,
+                threshold: 0,
+            },
+            ..tcp_client_config(addr)
+        }
+    }
+}
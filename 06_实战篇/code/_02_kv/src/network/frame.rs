@@ -1,6 +1,6 @@
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 
-use crate::{CommandRequest, CommandResponse, KvError};
+use crate::{Codec, CommandRequest, CommandResponse, CompressionConfig, KvError};
 use bytes::{Buf, BufMut, BytesMut};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use prost::Message;
@@ -9,90 +9,154 @@ use tracing::debug;
 
 /// 长度整个占用 4 个字节
 pub const LEN_LEN: usize = 4;
-/// 长度占 31 bit，所以最大的 frame 是 2G
-const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
-/// 如果 payload 超过了 1436 字节，就做压缩
-const COMPRESSION_LIMIT: usize = 1436;
-/// 代表压缩的 bit（整个长度 4 字节的最高位）
-const COMPRESSION_BIT: usize = 1 << 31;
+/// 长度 4 字节中最高的 2 bit 代表压缩算法
+const CODEC_SHIFT: usize = 30;
+/// 长度占 30 bit，所以最大的 frame 是 1G
+const MAX_FRAME: usize = (1 << CODEC_SHIFT) - 1;
+/// 旧版本固定在 payload 超过 1436 字节时做 gzip 压缩，也是 CompressionConfig 的默认值
+pub(crate) const COMPRESSION_LIMIT: usize = 1436;
 
 /// 处理 Frame 的 encode/decode
 pub trait FrameCoder
 where
     Self: Message + Sized + Default,
 {
-    /// 把一个 Message encode 成一个 frame
+    /// 把一个 Message encode 成一个 frame，使用默认的压缩配置，和旧版本兼容
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
+        self.encode_frame_with(buf, &CompressionConfig::default())
+    }
+
+    /// 把一个 Message encode 成一个 frame，payload 超过 threshold 时用 codec 压缩
+    fn encode_frame_with(
+        &self,
+        buf: &mut BytesMut,
+        config: &CompressionConfig,
+    ) -> Result<(), KvError> {
         let size = self.encoded_len();
 
         if size > MAX_FRAME {
             return Err(KvError::FrameError);
         }
 
-        // 我们先写入长度，如果需要压缩，再重写压缩后的长度
-        buf.put_u32(size as _);
-
-        if size > COMPRESSION_LIMIT {
-            let mut buf1 = Vec::with_capacity(size);
-            self.encode(&mut buf1)?;
-
-            // BytesMut 支持逻辑上的 split（之后还能 unsplit）
-            // 所以我们先把长度这 4 字节拿走，清除
-            let payload = buf.split_off(LEN_LEN);
-            buf.clear();
-
-            // 处理 gzip 压缩，具体可以参考 flate2 文档
-            let mut encoder = GzEncoder::new(payload.writer(), Compression::default());
-            encoder.write_all(&buf1[..])?;
-
-            // 压缩完成后，从 gzip encoder 中把 BytesMut 再拿回来
-            let payload = encoder.finish()?.into_inner();
-            debug!("Encode a frame: size {}({})", size, payload.len());
-
-            // 写入压缩后的长度
-            buf.put_u32((payload.len() | COMPRESSION_BIT) as _);
-
-            // 把 BytesMut 再合并回来
-            buf.unsplit(payload);
-
-            Ok(())
-        } else {
+        if size <= config.threshold || config.codec == Codec::None {
+            buf.put_u32(size as _);
             self.encode(buf)?;
-            Ok(())
+            return Ok(());
         }
+
+        let mut data = Vec::with_capacity(size);
+        self.encode(&mut data)?;
+        let payload = compress(config.codec, &data)?;
+        debug!(
+            "Encode a frame: size {}({}), codec {:?}",
+            size,
+            payload.len(),
+            config.codec
+        );
+
+        // 压缩之后没有变小（比如数据本身已经压缩过了），就发送原始的数据
+        let (codec, payload) = if payload.len() < size {
+            (config.codec, payload)
+        } else {
+            (Codec::None, data)
+        };
+        buf.put_u32((payload.len() | encode_codec(codec)) as _);
+        buf.put_slice(&payload);
+
+        Ok(())
     }
 
     /// 把一个完整的 frame decode 成一个 Message
     fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
-        // 先取 4 字节，从中拿出长度和 compression bit
+        // 先取 4 字节，从中拿出长度和压缩算法
         let header = buf.get_u32() as usize;
-        let (len, compressed) = decode_header(header);
-        debug!("Got a frame: msg len {}, compressed {}", len, compressed);
-
-        if compressed {
-            // 解压缩
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            let mut buf1 = Vec::with_capacity(len * 2);
-            decoder.read_to_end(&mut buf1)?;
-            buf.advance(len);
-
-            // decode 成相应的消息
-            Ok(Self::decode(&buf1[..buf1.len()])?)
-        } else {
-            let msg = Self::decode(&buf[..len])?;
-            buf.advance(len);
-            Ok(msg)
-        }
+        let (len, codec) = decode_header(header);
+        debug!("Got a frame: msg len {}, codec {:?}", len, codec);
+
+        let msg = match codec {
+            Codec::None => Self::decode(&buf[..len])?,
+            // 解压缩之后 decode 成相应的消息
+            codec => Self::decode(&decompress(codec, &buf[..len])?[..])?,
+        };
+        buf.advance(len);
+        Ok(msg)
     }
 }
 
 impl FrameCoder for CommandRequest {}
 impl FrameCoder for CommandResponse {}
 
-pub fn decode_header(header: usize) -> (usize, bool) {
-    let len = header & !COMPRESSION_BIT;
-    let compressed = header & COMPRESSION_BIT == COMPRESSION_BIT;
-    (len, compressed)
+/// gzip 只设置最高位，和只认识最高位（压缩 bit）的旧版本兼容
+fn encode_codec(codec: Codec) -> usize {
+    let bits = match codec {
+        Codec::None => 0b00,
+        Codec::Gzip => 0b10,
+        Codec::Lz4 => 0b01,
+        Codec::Zstd => 0b11,
+    };
+    bits << CODEC_SHIFT
+}
+
+pub fn decode_header(header: usize) -> (usize, Codec) {
+    let len = header & MAX_FRAME;
+    let codec = match header >> CODEC_SHIFT & 0b11 {
+        0b10 => Codec::Gzip,
+        0b01 => Codec::Lz4,
+        0b11 => Codec::Zstd,
+        _ => Codec::None,
+    };
+    (len, codec)
+}
+
+fn compress(codec: Codec, data: &[u8]) -> Result<Vec<u8>, KvError> {
+    let payload = match codec {
+        Codec::None => data.to_vec(),
+        Codec::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+        Codec::Lz4 => lz4_flex::compress_prepend_size(data),
+        // level 为 0 时使用 zstd 默认的压缩级别
+        Codec::Zstd => zstd::bulk::compress(data, 0)?,
+    };
+    Ok(payload)
+}
+
+/// 解压缩 payload，解压后超过 MAX_FRAME 时返回 FrameError，避免对方用很小的 frame 让我们分配大量内存
+fn decompress(codec: Codec, data: &[u8]) -> Result<Vec<u8>, KvError> {
+    let limit = MAX_FRAME as u64 + 1;
+    let payload = match codec {
+        Codec::None => data.to_vec(),
+        Codec::Gzip => {
+            let mut payload = Vec::with_capacity((data.len() * 2).min(MAX_FRAME));
+            GzDecoder::new(data).take(limit).read_to_end(&mut payload)?;
+            payload
+        }
+        Codec::Lz4 => {
+            // lz4_flex 会按照前 4 个字节声称的大小分配内存，先检查它
+            let size = data
+                .get(..4)
+                .map(|v| u32::from_le_bytes([v[0], v[1], v[2], v[3]]) as usize)
+                .ok_or(KvError::FrameError)?;
+            if size > MAX_FRAME {
+                return Err(KvError::FrameError);
+            }
+            lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        }
+        Codec::Zstd => {
+            let mut payload = Vec::new();
+            zstd::stream::read::Decoder::new(data)?
+                .take(limit)
+                .read_to_end(&mut payload)?;
+            payload
+        }
+    };
+    if payload.len() > MAX_FRAME {
+        return Err(KvError::FrameError);
+    }
+    Ok(payload)
 }
 
 /// 从 stream 中读取一个完整的 frame
@@ -101,7 +165,7 @@ where
     S: AsyncRead + Unpin + Send,
 {
     let header = stream.read_u32().await? as usize;
-    let (len, _compressed) = decode_header(header);
+    let (len, _codec) = decode_header(header);
     // 如果没有这么大的内存，就分配至少一个 frame 的内存，保证它可用
     buf.reserve(LEN_LEN + len);
     buf.put_u32(header as _);
@@ -163,6 +227,49 @@ mod tests {
         assert_eq!(res, res1);
     }
 
+    #[test]
+    fn frames_should_be_encoded_with_all_codecs() {
+        let value: Value = Bytes::from(vec![0u8; 4096]).into();
+        let res: CommandResponse = value.into();
+        for codec in Codec::ALL {
+            let mut buf = BytesMut::new();
+            let config = CompressionConfig {
+                codec,
+                threshold: 0,
+            };
+            res.encode_frame_with(&mut buf, &config).unwrap();
+
+            // header 中记录了压缩算法
+            let (_, codec1) = decode_header(buf.clone().get_u32() as _);
+            assert_eq!(codec1, codec);
+
+            let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
+            assert_eq!(res, res1);
+        }
+
+        // 超过 threshold 的 payload 才压缩
+        let mut buf = BytesMut::new();
+        let config = CompressionConfig {
+            codec: Codec::Zstd,
+            threshold: 4096,
+        };
+        CommandRequest::new_hget("t1", "k1")
+            .encode_frame_with(&mut buf, &config)
+            .unwrap();
+        assert_eq!(decode_header(buf.clone().get_u32() as _).1, Codec::None);
+
+        // 压缩之后没有变小的数据按原样发送
+        let mut buf = BytesMut::new();
+        let config = CompressionConfig {
+            codec: Codec::Lz4,
+            threshold: 0,
+        };
+        CommandRequest::new_hget("t1", "k1")
+            .encode_frame_with(&mut buf, &config)
+            .unwrap();
+        assert_eq!(decode_header(buf.clone().get_u32() as _).1, Codec::None);
+    }
+
     #[tokio::test]
     async fn read_frame_should_work() {
         let mut buf = BytesMut::new();
@@ -177,6 +284,24 @@ mod tests {
         assert_eq!(cmd, cmd1);
     }
 
+    #[test]
+    fn oversized_lz4_frame_should_be_rejected() {
+        // 声称解压后有 4G，实际只有几个字节
+        let mut payload = u32::MAX.to_le_bytes().to_vec();
+        payload.extend_from_slice(b"hello");
+        let mut buf = BytesMut::new();
+        buf.put_u32((payload.len() | encode_codec(Codec::Lz4)) as _);
+        buf.put_slice(&payload);
+
+        let res = CommandRequest::decode_frame(&mut buf);
+        assert!(matches!(res, Err(KvError::FrameError)));
+        // 连 size 前缀都没有的 payload 也是错误的
+        assert!(matches!(
+            decompress(Codec::Lz4, b"he"),
+            Err(KvError::FrameError)
+        ));
+    }
+
     fn is_compressed(data: &[u8]) -> bool {
         if let [v] = data[..1] {
             v >> 7 == 1
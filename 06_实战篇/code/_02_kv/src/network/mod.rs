@@ -1,24 +1,40 @@
+mod client;
 mod frame;
 mod multiplex;
+mod pipeline;
+mod pool;
 mod stream;
 mod stream_result;
 mod tls;
+mod transport;
 
+pub use client::KvClient;
+pub(crate) use frame::COMPRESSION_LIMIT;
 pub use frame::{read_frame, FrameCoder};
 pub use multiplex::YamuxCtrl;
+pub use pipeline::PipelinedClient;
+pub use pool::{KvPool, Subscription};
 pub use stream::ProstStream;
 pub use stream_result::StreamResult;
-pub use tls::{TlsClientConnector, TlsServerAcceptor};
-
-use crate::{CommandRequest, CommandResponse, KvError, Service, Storage};
-use futures::{SinkExt, StreamExt};
+pub use tls::{peer_principal, TlsClientConnector, TlsServerAcceptor};
+pub use transport::{connect, connect_client, Incoming, Listener, Transport};
+
+use crate::{
+    metrics, Codec, CommandRequest, CommandResponse, CompressionConfig, KvError, Kvpair, Service,
+    Session, Storage, StreamingResponse,
+};
+use futures::{stream::SelectAll, SinkExt, Stream, StreamExt};
+use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::sync::CancellationToken;
 use tracing::info;
 
 /// 处理服务器端的某个 accept 下来的 socket 的读写
 pub struct ProstServerStream<S, Store> {
     inner: ProstStream<S, CommandRequest, CommandResponse>,
     service: Service<Store>,
+    session: Session,
+    shutdown: CancellationToken,
 }
 
 /// 处理客户端 socket 的读写
@@ -35,23 +51,83 @@ where
         Self {
             inner: ProstStream::new(stream),
             service,
+            session: Session::default(),
+            shutdown: CancellationToken::new(),
         }
     }
 
+    /// 使用连接的会话，同一个连接上的 stream 共享认证的结果
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// 服务器关闭时不再读取新的命令，正在返回的结果会继续发送完
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// 读取并执行命令，id 为 0 的命令按顺序执行，发送完结果后才读取下一个命令；
+    /// id 不为 0 的命令并发执行，谁先有结果先发送谁的，response 中带上请求的 id
     pub async fn process(mut self) -> Result<(), KvError> {
         let stream = &mut self.inner;
-        while let Some(Ok(cmd)) = stream.next().await {
+        let mut inflight: SelectAll<StreamingResponse> = SelectAll::new();
+        loop {
+            // 同一个连接上的任何一个 stream 执行 HELLO 之后，都会使用协商的压缩算法
+            stream.set_compression(self.service.compression(&self.session));
+            let cmd = tokio::select! {
+                cmd = stream.next() => cmd,
+                Some(data) = inflight.next() => {
+                    stream.send(&data).await?;
+                    continue;
+                }
+                _ = self.shutdown.cancelled() => break,
+            };
+            let Some(Ok(cmd)) = cmd else {
+                break;
+            };
+
             info!("Got a new command: {:?}", cmd);
-            let mut res = self.service.execute(cmd);
+            let id = cmd.id;
+            let mut timer = Some(metrics::start_timer(&cmd));
+            let res = self.service.execute_with_session(cmd, &self.session);
+            // 延迟只统计到第一个 response，订阅之后推送的数据不算在内
+            let mut res = res.map(move |data| {
+                if let Some(timer) = timer.take() {
+                    timer.observe_duration();
+                }
+                with_id(data, id)
+            });
+            if id != 0 {
+                inflight.push(Box::pin(res));
+                continue;
+            }
             while let Some(data) = res.next().await {
-                stream.send(&data).await.unwrap();
+                stream.send(&data).await?;
             }
         }
-        // info!("Client {:?} disconnected", self.addr);
+        // 不再读取新的命令，把并发执行的命令的结果发送完
+        while let Some(data) = inflight.next().await {
+            stream.send(&data).await?;
+        }
+        // 客户端关闭写入端后，关闭 stream，让分批返回的命令（比如 HGETALL）知道结果已经结束
+        // 客户端可能已经断开了，这时关闭失败没有关系
+        let _ = stream.close().await;
         Ok(())
     }
 }
 
+/// 在 response 中带上请求的 id
+fn with_id(data: Arc<CommandResponse>, id: u64) -> Arc<CommandResponse> {
+    if id == 0 {
+        return data;
+    }
+    let mut res = data.as_ref().clone();
+    res.id = id;
+    Arc::new(res)
+}
+
 impl<S> ProstClientStream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
@@ -62,6 +138,33 @@ where
         }
     }
 
+    /// 设置发送请求时使用的压缩配置
+    pub fn set_compression(&mut self, config: CompressionConfig) {
+        self.inner.set_compression(config);
+    }
+
+    /// 通过 HELLO 和服务器交换支持的压缩算法，返回之后发送请求使用的压缩配置
+    /// 旧版本的服务器不认识 HELLO 会返回错误，这时只使用它也支持的算法
+    pub async fn negotiate(
+        &mut self,
+        config: CompressionConfig,
+    ) -> Result<CompressionConfig, KvError> {
+        let res = self
+            .execute_unary(&CommandRequest::new_hello(&Codec::ALL))
+            .await?;
+        let peer: Vec<Codec> = if res.status == 200 {
+            res.values
+                .into_iter()
+                .filter_map(|v| String::try_from(v).ok()?.parse().ok())
+                .collect()
+        } else {
+            Codec::LEGACY.to_vec()
+        };
+        let config = config.negotiate(&peer);
+        self.set_compression(config);
+        Ok(config)
+    }
+
     pub async fn execute_unary(
         &mut self,
         cmd: &CommandRequest,
@@ -83,14 +186,56 @@ where
 
         StreamResult::new(stream).await
     }
+
+    /// 发送分批返回结果的命令（比如 chunk_size 大于 0 的 HGETALL），返回所有批次组成的 Stream
+    /// 服务器发送完所有的批次后会关闭 stream，Stream 随之结束
+    pub async fn execute_chunked(
+        self,
+        cmd: &CommandRequest,
+    ) -> Result<impl Stream<Item = Result<CommandResponse, KvError>>, KvError> {
+        let mut stream = self.inner;
+
+        stream.send(cmd).await?;
+        stream.close().await?;
+
+        Ok(stream)
+    }
+
+    /// 分批读取 table 中所有的 kv pair，每批最多 chunk_size 个
+    pub async fn hgetall_chunked(
+        self,
+        table: impl Into<String>,
+        chunk_size: u32,
+    ) -> Result<impl Stream<Item = Result<Vec<Kvpair>, KvError>>, KvError> {
+        let cmd = CommandRequest::new_hgetall_chunked(table, chunk_size);
+        let stream = self.execute_chunked(&cmd).await?;
+
+        Ok(stream.map(|res| match res {
+            Ok(res) if res.status == 200 => Ok(res.pairs),
+            Ok(res) => Err(KvError::Internal(format!(
+                "{}: {}",
+                res.status, res.message
+            ))),
+            Err(e) => Err(e),
+        }))
+    }
 }
 
 #[cfg(test)]
 pub mod utils {
     use anyhow::Result;
     use bytes::{BufMut, BytesMut};
-    use std::{cmp::min, task::Poll};
-    use tokio::io::{AsyncRead, AsyncWrite};
+    use std::{cmp::min, net::SocketAddr, sync::Arc, task::Poll};
+    use tokio::{
+        io::{AsyncRead, AsyncWrite},
+        net::TcpListener,
+        sync::Notify,
+    };
+    use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+    use crate::{
+        ClientConfig, ListenerConfig, MemTable, ProstServerStream, Service, ServiceInner, YamuxCtrl,
+    };
 
     #[derive(Default)]
     pub struct DummyStream {
@@ -135,17 +280,73 @@ pub mod utils {
             Poll::Ready(Ok(()))
         }
     }
+
+    /// 测试用的服务器，每个 TCP 连接直接处理 ProstServerStream
+    pub async fn start_server() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let server = ProstServerStream::new(stream, service.clone());
+                tokio::spawn(server.process());
+            }
+        });
+
+        Ok(addr)
+    }
+
+    /// 测试用的服务器，每个 TCP 连接上跑 yamux，每个 yamux stream 处理 ProstServerStream
+    pub async fn start_yamux_server() -> Result<String> {
+        start_killable_yamux_server(Arc::new(Notify::new())).await
+    }
+
+    /// 和 start_yamux_server 一样，kill 被通知时关闭所有的连接，但是继续接受新的连接
+    pub async fn start_killable_yamux_server(kill: Arc<Notify>) -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = service.clone();
+                let kill = kill.clone();
+                tokio::spawn(async move {
+                    let mut ctrl = YamuxCtrl::new_server(stream, None, move |stream| {
+                        let stream = ProstServerStream::new(stream.compat(), service.clone());
+                        async move {
+                            let _ = stream.process().await;
+                            Ok(())
+                        }
+                    });
+                    kill.notified().await;
+                    let _ = ctrl.close().await;
+                });
+            }
+        });
+
+        Ok(addr)
+    }
+
+    /// 只有一个 TCP listener 的客户端配置，其它都用默认值
+    pub fn tcp_client_config(addr: &str) -> ClientConfig {
+        ClientConfig {
+            general: Default::default(),
+            tls: Default::default(),
+            listeners: vec![ListenerConfig::Tcp { addr: addr.into() }],
+            pool: Default::default(),
+            compression: Default::default(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::SocketAddr;
-
     use super::*;
-    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
-    use anyhow::Result;
+    use crate::{assert_res_ok, utils::start_server, Value};
     use bytes::Bytes;
-    use tokio::net::{TcpListener, TcpStream};
+    use tokio::net::TcpStream;
 
     #[tokio::test]
     async fn client_server_basic_communication_should_work() -> anyhow::Result<()> {
@@ -199,19 +400,45 @@ mod tests {
         Ok(())
     }
 
-    async fn start_server() -> Result<SocketAddr> {
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
+    #[tokio::test]
+    async fn client_server_should_negotiate_compression() -> anyhow::Result<()> {
+        let addr = start_server().await?;
 
-        tokio::spawn(async move {
-            loop {
-                let (stream, _) = listener.accept().await.unwrap();
-                let service: Service = ServiceInner::new(MemTable::new()).into();
-                let server = ProstServerStream::new(stream, service);
-                tokio::spawn(server.process());
-            }
-        });
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let config = CompressionConfig {
+            codec: Codec::Zstd,
+            threshold: 0,
+        };
+        assert_eq!(client.negotiate(config).await?, config);
 
-        Ok(addr)
+        // 协商之后请求和 response 都可以使用 zstd
+        let v: Value = Bytes::from(vec![0u8; 16384]).into();
+        let cmd = CommandRequest::new_hset("t2", "k2", v.clone());
+        client.execute_unary(&cmd).await?;
+        let res = client
+            .execute_unary(&CommandRequest::new_hget("t2", "k2"))
+            .await?;
+        assert_res_ok(&res, &[v], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_chunked_hgetall_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let pairs = (0..100).map(|i| Kvpair::new(format!("k{}", i), i.into()));
+        let cmd = CommandRequest::new_hmset("t1", pairs.collect());
+        client.execute_unary(&cmd).await?;
+
+        let chunks: Vec<_> = client.hgetall_chunked("t1", 30).await?.collect().await;
+        assert_eq!(chunks.len(), 4);
+        let total: usize = chunks.into_iter().map(|v| v.unwrap().len()).sum();
+        assert_eq!(total, 100);
+
+        Ok(())
     }
 }
@@ -1,16 +1,23 @@
 use futures::{future, Future, TryStreamExt};
 use std::marker::PhantomData;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tokio_util::{
+    compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt},
+    sync::CancellationToken,
+};
 use tracing::instrument;
 use yamux::{Config, Connection, ConnectionError, Control, Mode, WindowUpdateMode};
 
-use crate::ProstClientStream;
+use crate::{CompressionConfig, KvError, ProstClientStream};
 
 /// Yamux 控制结构
 pub struct YamuxCtrl<S> {
     /// yamux control，用于创建新的 stream
     ctrl: Control,
+    /// 协商后的压缩配置，新打开的 stream 都使用它发送请求
+    compression: CompressionConfig,
+    /// 连接断开、所有的 stream 都处理完之后被 cancel
+    closed: CancellationToken,
     _conn: PhantomData<S>,
 }
 
@@ -57,12 +64,21 @@ where
         // 创建 yamux ctrl
         let ctrl = conn.control();
 
-        // pull 所有 stream 下的数据
-        tokio::spawn(yamux::into_stream(conn).try_for_each_concurrent(None, f));
+        // pull 所有 stream 下的数据，结束（或者 panic）时 guard 被释放，closed 随之被 cancel
+        let closed = CancellationToken::new();
+        let guard = closed.clone().drop_guard();
+        tokio::spawn(async move {
+            let _guard = guard;
+            yamux::into_stream(conn)
+                .try_for_each_concurrent(None, f)
+                .await
+        });
 
         Self {
             ctrl,
-            _conn: PhantomData::default(),
+            compression: CompressionConfig::default(),
+            closed,
+            _conn: PhantomData,
         }
     }
 
@@ -72,7 +88,26 @@ where
         &mut self,
     ) -> Result<ProstClientStream<Compat<yamux::Stream>>, ConnectionError> {
         let stream = self.ctrl.open_stream().await?;
-        Ok(ProstClientStream::new(stream.compat()))
+        let mut stream = ProstClientStream::new(stream.compat());
+        stream.set_compression(self.compression);
+        Ok(stream)
+    }
+
+    /// 在一个新的 stream 上和服务器协商压缩算法，之后打开的 stream 都使用协商的结果
+    pub async fn negotiate(&mut self, config: CompressionConfig) -> Result<(), KvError> {
+        let mut stream = self.open_stream().await?;
+        self.compression = stream.negotiate(config).await?;
+        Ok(())
+    }
+
+    /// 连接断开，并且所有的 stream 都处理完之后返回，不会借用 self
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.closed.clone().cancelled_owned()
+    }
+
+    /// 关闭连接，所有的 stream 都会被关闭
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.ctrl.close().await
     }
 }
 
@@ -155,6 +190,29 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn yamux_ctrl_should_be_closed_after_peer_disconnected() -> Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let server = YamuxCtrl::new_server(server, None, move |s| {
+            let stream = ProstServerStream::new(s.compat(), service.clone());
+            async move {
+                let _ = stream.process().await;
+                Ok(())
+            }
+        });
+
+        let mut client = YamuxCtrl::new_client(client, None);
+        let mut stream = client.open_stream().await?;
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        stream.execute_unary(&cmd).await?;
+
+        let closed = server.closed();
+        client.close().await?;
+        tokio::time::timeout(std::time::Duration::from_secs(1), closed).await?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn yamux_ctrl_client_server_should_work() -> Result<()> {
         // 创建使用了 TLS 的 yamux server
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, oneshot},
+};
+use tracing::warn;
+
+use crate::{CommandRequest, CommandResponse, KvError, ProstStream};
+
+/// 同时最多有多少个等待发送的请求
+const MAX_PENDING_REQUESTS: usize = 128;
+
+type Responder = oneshot::Sender<Result<CommandResponse, KvError>>;
+
+/// 在一个 stream 上同时发送多个请求的客户端，用请求的 id 匹配 response
+/// 可以 clone 之后在多个 task 中并发使用，只支持返回一个 response 的命令，订阅请使用 ProstClientStream
+#[derive(Clone)]
+pub struct PipelinedClient {
+    requests: mpsc::Sender<(CommandRequest, Responder)>,
+}
+
+impl PipelinedClient {
+    /// 在后台 task 中读写 stream，所有的 PipelinedClient 都被 drop 后 task 退出
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(MAX_PENDING_REQUESTS);
+        tokio::spawn(run(ProstStream::new(stream), rx));
+        Self { requests: tx }
+    }
+
+    /// 发送命令，等待对应的 response，请求的 id 由客户端分配
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let (tx, rx) = oneshot::channel();
+        self.requests.send((cmd, tx)).await.map_err(|_| closed())?;
+        rx.await.map_err(|_| closed())?
+    }
+}
+
+fn closed() -> KvError {
+    KvError::Internal("Pipelined stream is closed".into())
+}
+
+/// 发送请求，并把读到的 response 交给 id 对应的请求
+/// stream 出错或者被关闭后退出，还在等待的请求会收到错误
+async fn run<S>(
+    mut stream: ProstStream<S, CommandResponse, CommandRequest>,
+    mut requests: mpsc::Receiver<(CommandRequest, Responder)>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut pending: HashMap<u64, Responder> = HashMap::new();
+    let mut next_id = 0;
+    loop {
+        tokio::select! {
+            req = requests.recv() => {
+                let Some((mut cmd, tx)) = req else {
+                    break;
+                };
+                next_id += 1;
+                cmd.id = next_id;
+                if let Err(e) = stream.send(&cmd).await {
+                    let _ = tx.send(Err(e));
+                    break;
+                }
+                pending.insert(cmd.id, tx);
+            }
+            res = stream.next() => match res {
+                Some(Ok(res)) => match pending.remove(&res.id) {
+                    Some(tx) => {
+                        let _ = tx.send(Ok(res));
+                    }
+                    None => warn!("Got a response for unknown request {}", res.id),
+                },
+                Some(Err(e)) => {
+                    warn!("Failed to read response: {:?}", e);
+                    break;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::{assert_res_ok, utils::start_server, ProstClientStream, Value};
+
+    #[tokio::test]
+    async fn pipelined_requests_should_match_responses() {
+        let addr = start_server().await.unwrap();
+        let client = PipelinedClient::new(TcpStream::connect(addr).await.unwrap());
+
+        let tasks: Vec<_> = (0..100i64)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let key = format!("k{}", i);
+                    let cmd = CommandRequest::new_hset("t1", key.clone(), i.into());
+                    client.execute(cmd).await.unwrap();
+                    client.execute(CommandRequest::new_hget("t1", key)).await
+                })
+            })
+            .collect();
+
+        for (i, task) in tasks.into_iter().enumerate() {
+            let res = task.await.unwrap().unwrap();
+            assert_res_ok(&res, &[Value::from(i as i64)], &[]);
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_should_not_wait_for_subscription() {
+        let addr = start_server().await.unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = ProstStream::<_, CommandResponse, CommandRequest>::new(stream);
+
+        // 订阅的结果一直不会结束，之后的请求也不需要等待它
+        let mut cmd = CommandRequest::new_subscribe("lobby");
+        cmd.id = 1;
+        stream.send(&cmd).await.unwrap();
+        let res = stream.next().await.unwrap().unwrap();
+        assert_eq!(res.id, 1);
+
+        let mut cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        cmd.id = 2;
+        stream.send(&cmd).await.unwrap();
+        let res = tokio::time::timeout(Duration::from_secs(1), stream.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.id, 2);
+
+        // 推送的消息带着订阅请求的 id
+        let mut client = ProstClientStream::new(TcpStream::connect(addr).await.unwrap());
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        client.execute_unary(&cmd).await.unwrap();
+        let res = stream.next().await.unwrap().unwrap();
+        assert_eq!(res.id, 1);
+        assert_res_ok(&res, &["hello".into()], &[]);
+    }
+}
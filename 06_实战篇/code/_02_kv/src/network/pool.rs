@@ -0,0 +1,487 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex as StdMutex, Weak,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{Stream, StreamExt};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time,
+};
+use tokio_util::{compat::Compat, sync::CancellationToken};
+use tracing::{info, warn};
+
+use crate::{
+    connect_client, ClientConfig, CommandRequest, CommandResponse, KvError, PoolConfig,
+    ProstClientStream, StreamResult, Transport, YamuxCtrl,
+};
+
+/// 订阅者还没有取走的数据
+const SUBSCRIPTION_CAPACITY: usize = 128;
+
+type ClientStream = ProstClientStream<Compat<yamux::Stream>>;
+
+/// 自动重连的客户端：第一次使用时才连接，连接断开后按指数退避重连，
+/// 空闲的 stream 会留给之后的请求使用，订阅在重连后会自动重新订阅
+#[derive(Clone)]
+pub struct KvPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    config: ClientConfig,
+    conn: Mutex<Option<Connection>>,
+    /// 空闲的 stream 以及它所在连接的 generation
+    idle: StdMutex<Vec<(u64, ClientStream)>>,
+    /// 每次连接成功后加一，用来区分旧连接上的 stream
+    generation: AtomicU64,
+    /// 健康检查的任务是否已经启动
+    checking: AtomicBool,
+}
+
+struct Connection {
+    ctrl: YamuxCtrl<Box<dyn Transport>>,
+    generation: u64,
+}
+
+/// 指数退避：每次等待的时间翻倍，直到 max_backoff_ms
+struct Backoff {
+    delay: Duration,
+    max: Duration,
+    /// 剩下的重试次数，None 表示一直重试
+    retries: Option<u32>,
+}
+
+impl Backoff {
+    fn new(config: &PoolConfig, retries: Option<u32>) -> Self {
+        Self {
+            delay: Duration::from_millis(config.initial_backoff_ms),
+            max: Duration::from_millis(config.max_backoff_ms),
+            retries,
+        }
+    }
+
+    /// 下一次重试前等待的时间，没有重试次数时返回 None
+    fn next(&mut self) -> Option<Duration> {
+        if let Some(retries) = &mut self.retries {
+            *retries = retries.checked_sub(1)?;
+        }
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(self.max);
+        Some(delay)
+    }
+}
+
+impl KvPool {
+    /// 创建客户端，这时还不会连接服务器
+    pub fn new(config: ClientConfig) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                config,
+                conn: Mutex::new(None),
+                idle: StdMutex::new(Vec::new()),
+                generation: AtomicU64::new(0),
+                checking: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// 打开一个新的 stream，连接断开时先重连，最多重连 max_retries 次
+    pub async fn open_stream(&self) -> Result<ClientStream, KvError> {
+        let (_, stream) = self.open().await?;
+        Ok(stream)
+    }
+
+    /// 使用空闲的 stream 执行命令，成功后 stream 放回空闲列表
+    /// 请求发送之后连接断开时直接返回错误，不会重试，因为服务器可能已经执行了这个命令
+    pub async fn execute_unary(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        let (generation, mut stream) = match self.inner.take_idle() {
+            Some(v) => v,
+            None => self.open().await?,
+        };
+
+        match stream.execute_unary(cmd).await {
+            Ok(res) => {
+                self.inner.release(generation, stream);
+                Ok(res)
+            }
+            Err(e) => {
+                self.inner.invalidate(generation).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// 订阅主题，连接断开后自动重新订阅；持久化的主题会从收到的最后一条消息之后继续
+    pub async fn subscribe(&self, topic: impl Into<String>) -> Result<Subscription, KvError> {
+        let topic = topic.into();
+        let (_, stream) = self.open().await?;
+        let stream = stream
+            .execute_streaming(&CommandRequest::new_subscribe(topic.as_str()))
+            .await?;
+
+        let (tx, rx) = mpsc::channel(SUBSCRIPTION_CAPACITY);
+        let id = Arc::new(AtomicU32::new(stream.id));
+        let cancel = CancellationToken::new();
+        let task = Resubscribe {
+            pool: self.clone(),
+            topic: topic.clone(),
+            id: id.clone(),
+            cancel: cancel.clone(),
+            tx,
+        };
+        tokio::spawn(task.run(stream));
+
+        Ok(Subscription {
+            pool: self.clone(),
+            topic,
+            id,
+            cancel,
+            rx,
+        })
+    }
+
+    async fn open(&self) -> Result<(u64, ClientStream), KvError> {
+        self.start_health_check();
+        let config = &self.inner.config.pool;
+        let mut backoff = Backoff::new(config, Some(config.max_retries));
+        loop {
+            match self.inner.try_open().await {
+                Ok(v) => return Ok(v),
+                Err(e) => match backoff.next() {
+                    Some(delay) => {
+                        warn!("Failed to open stream: {:?}, retry in {:?}", e, delay);
+                        time::sleep(delay).await;
+                    }
+                    None => return Err(e),
+                },
+            }
+        }
+    }
+
+    fn start_health_check(&self) {
+        let interval = self.inner.config.pool.health_check_interval_ms;
+        if interval == 0 || self.inner.checking.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(health_check(inner, Duration::from_millis(interval)));
+    }
+}
+
+impl PoolInner {
+    /// 在当前的连接上打开 stream，没有连接时先连接
+    async fn try_open(&self) -> Result<(u64, ClientStream), KvError> {
+        let mut guard = self.conn.lock().await;
+        if guard.is_none() {
+            *guard = Some(self.connect().await?);
+        }
+        let conn = guard.as_mut().expect("connection is set above");
+
+        match conn.ctrl.open_stream().await {
+            Ok(stream) => Ok((conn.generation, stream)),
+            Err(e) => {
+                // 连接已经断开，下次重新连接
+                let generation = conn.generation;
+                self.drop_connection(&mut guard, generation);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// 依次尝试配置中的地址，连接成功后先协商压缩算法，如果配置了 token 再认证
+    async fn connect(&self) -> Result<Connection, KvError> {
+        let (mut ctrl, listener) = connect_client(&self.config).await?;
+        if let Some(token) = &self.config.pool.token {
+            let mut stream = ctrl.open_stream().await?;
+            let res = stream
+                .execute_unary(&CommandRequest::new_auth(token))
+                .await?;
+            if res.status != 200 {
+                return Err(KvError::Unauthorized(res.message));
+            }
+        }
+
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        info!("Connected to {:?}, generation {}", listener, generation);
+        Ok(Connection { ctrl, generation })
+    }
+
+    /// 当前连接的 generation，没有连接时返回 None
+    async fn current(&self) -> Option<u64> {
+        self.conn.lock().await.as_ref().map(|conn| conn.generation)
+    }
+
+    /// 连接不可用了，关闭它，下次使用时重新连接
+    async fn invalidate(&self, generation: u64) {
+        let mut conn = self.conn.lock().await;
+        self.drop_connection(&mut conn, generation);
+    }
+
+    fn drop_connection(&self, conn: &mut Option<Connection>, generation: u64) {
+        if conn.as_ref().map(|v| v.generation) != Some(generation) {
+            return;
+        }
+        if let Some(mut conn) = conn.take() {
+            warn!("Connection {} is closed", generation);
+            tokio::spawn(async move { conn.ctrl.close().await });
+        }
+        self.lock_idle().clear();
+    }
+
+    fn take_idle(&self) -> Option<(u64, ClientStream)> {
+        self.lock_idle().pop()
+    }
+
+    /// 只保留当前连接上的空闲 stream
+    fn release(&self, generation: u64, stream: ClientStream) {
+        let current = self.generation.load(Ordering::Relaxed);
+        let mut idle = self.lock_idle();
+        if generation == current && idle.len() < self.config.pool.max_idle_streams {
+            idle.push((generation, stream));
+        }
+    }
+
+    fn lock_idle(&self) -> std::sync::MutexGuard<'_, Vec<(u64, ClientStream)>> {
+        self.idle.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// 定期在当前连接上执行一个命令，失败或者超时就关闭连接；KvPool 被释放后退出
+async fn health_check(inner: Weak<PoolInner>, interval: Duration) {
+    let mut ticker = time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            break;
+        };
+        let Some(generation) = inner.current().await else {
+            continue;
+        };
+
+        // 任何 response 都说明连接是好的，包括没有权限之类的错误
+        let ping = async {
+            let (_, mut stream) = inner.try_open().await?;
+            let cmd = CommandRequest::new_replication_info();
+            stream.execute_unary(&cmd).await
+        };
+        match time::timeout(interval, ping).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                warn!("Health check failed: {:?}", e);
+                inner.invalidate(generation).await;
+            }
+            Err(_) => {
+                warn!("Health check timed out after {:?}", interval);
+                inner.invalidate(generation).await;
+            }
+        }
+    }
+}
+
+/// 在后台转发订阅的数据，订阅断开后重新订阅
+struct Resubscribe {
+    pool: KvPool,
+    topic: String,
+    id: Arc<AtomicU32>,
+    cancel: CancellationToken,
+    tx: mpsc::Sender<CommandResponse>,
+}
+
+impl Resubscribe {
+    async fn run(self, mut stream: StreamResult) {
+        let mut last_seq = 0;
+        loop {
+            loop {
+                let res = tokio::select! {
+                    res = stream.next() => res,
+                    _ = self.cancel.cancelled() => return,
+                };
+                match res {
+                    Some(Ok(res)) => {
+                        last_seq = last_seq.max(res.seq);
+                        if self.tx.send(res).await.is_err() {
+                            // Subscription 已经被释放
+                            return;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        warn!("Subscription to {} is broken: {:?}", self.topic, e);
+                        break;
+                    }
+                    None => break,
+                }
+            }
+
+            stream = tokio::select! {
+                stream = self.resubscribe(last_seq) => stream,
+                _ = self.cancel.cancelled() => return,
+            };
+            self.id.store(stream.id, Ordering::Relaxed);
+            info!("Resubscribed to {} with id {}", self.topic, stream.id);
+        }
+    }
+
+    /// 一直重试，直到重新订阅成功
+    async fn resubscribe(&self, last_seq: u64) -> StreamResult {
+        let inner = &self.pool.inner;
+        let mut backoff = Backoff::new(&inner.config.pool, None);
+        loop {
+            let cmd = match last_seq {
+                0 => CommandRequest::new_subscribe(self.topic.as_str()),
+                seq => CommandRequest::new_subscribe_from(self.topic.as_str(), seq + 1),
+            };
+            let result = match inner.try_open().await {
+                Ok((_, stream)) => stream.execute_streaming(&cmd).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(stream) => return stream,
+                Err(e) => {
+                    let delay = backoff.next().unwrap_or(backoff.max);
+                    warn!("Failed to resubscribe to {}: {:?}", self.topic, e);
+                    time::sleep(delay).await;
+                }
+            }
+        }
+    }
+}
+
+/// KvPool 中的订阅，重连后 id 会改变；被释放时停止接收数据
+pub struct Subscription {
+    pool: KvPool,
+    topic: String,
+    id: Arc<AtomicU32>,
+    cancel: CancellationToken,
+    rx: mpsc::Receiver<CommandResponse>,
+}
+
+impl Subscription {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// 当前的 subscription id
+    pub fn id(&self) -> u32 {
+        self.id.load(Ordering::Relaxed)
+    }
+
+    /// 取消订阅，不再重新订阅
+    pub async fn unsubscribe(self) -> Result<(), KvError> {
+        self.cancel.cancel();
+        let cmd = CommandRequest::new_unsubscribe(self.topic.as_str(), self.id());
+        let res = self.pool.execute_unary(&cmd).await?;
+        match res.status {
+            200 => Ok(()),
+            _ => Err(KvError::Internal(format!(
+                "{}: {}",
+                res.status, res.message
+            ))),
+        }
+    }
+}
+
+impl Stream for Subscription {
+    type Item = CommandResponse;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::{net::TcpListener, sync::Notify};
+
+    use super::*;
+    use crate::{
+        utils::{start_killable_yamux_server, tcp_client_config},
+        Value,
+    };
+
+    #[test]
+    fn backoff_should_double_until_max() {
+        let config = PoolConfig {
+            initial_backoff_ms: 10,
+            max_backoff_ms: 25,
+            ..Default::default()
+        };
+        let mut backoff = Backoff::new(&config, Some(3));
+        let delays: Vec<_> = std::iter::from_fn(|| backoff.next()).collect();
+        let expected = [10, 20, 25].map(Duration::from_millis);
+        assert_eq!(delays, expected);
+    }
+
+    #[tokio::test]
+    async fn pool_should_connect_lazily() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        drop(listener);
+
+        // 创建时不连接，使用时重试 max_retries 次之后返回错误
+        let mut config = client_config(&addr);
+        config.pool.max_retries = 2;
+        let pool = KvPool::new(config);
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        assert!(pool.execute_unary(&cmd).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pool_should_reconnect_and_resubscribe() -> Result<()> {
+        let kill = Arc::new(Notify::new());
+        let addr = start_killable_yamux_server(kill.clone()).await?;
+        let pool = KvPool::new(client_config(&addr));
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_eq!(pool.execute_unary(&cmd).await?.status, 200);
+        let mut sub = pool.subscribe("lobby").await?;
+        let id = sub.id();
+
+        // 服务器关闭所有的连接之后，订阅会自动重新订阅
+        kill.notify_waiters();
+        time::timeout(Duration::from_secs(2), async {
+            while sub.id() == id {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        assert_eq!(pool.execute_unary(&cmd).await?.status, 200);
+        let res = sub.next().await.unwrap();
+        assert_eq!(res.values, [Value::from("hello")]);
+
+        let res = pool
+            .execute_unary(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.values, [Value::from("v1")]);
+
+        sub.unsubscribe().await?;
+        Ok(())
+    }
+
+    fn client_config(addr: &str) -> ClientConfig {
+        ClientConfig {
+            pool: PoolConfig {
+                initial_backoff_ms: 10,
+                max_backoff_ms: 100,
+                ..Default::default()
+            },
+            ..tcp_client_config(addr)
+        }
+    }
+}
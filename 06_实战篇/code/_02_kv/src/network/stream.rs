@@ -1,13 +1,16 @@
 use bytes::BytesMut;
-use futures::{ready, FutureExt, Sink, Stream};
+use futures::{ready, Sink, Stream};
 use std::{
+    io::ErrorKind,
     marker::PhantomData,
     pin::Pin,
     task::{Context, Poll},
 };
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::poll_read_buf;
 
-use crate::{read_frame, FrameCoder, KvError};
+use super::frame::{decode_header, LEN_LEN};
+use crate::{CompressionConfig, FrameCoder, KvError};
 
 /// 处理 KV server prost frame 的 stream
 pub struct ProstStream<S, In, Out> {
@@ -19,6 +22,8 @@ pub struct ProstStream<S, In, Out> {
     written: usize,
     // 读缓存
     rbuf: BytesMut,
+    // 发送时的压缩配置
+    compression: CompressionConfig,
 
     // 类型占位符
     _in: PhantomData<In>,
@@ -34,22 +39,43 @@ where
     /// 当调用 next() 时，得到 Result<In, KvError>
     type Item = Result<In, KvError>;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        // 上一次调用结束后 rbuf 应该为空
-        assert!(self.rbuf.is_empty());
-
-        // 从 rbuf 中分离出 rest（摆脱对 self 的引用）
-        let mut rest = self.rbuf.split_off(0);
-
-        // 使用 read_frame 来获取数据
-        let fut = read_frame(&mut self.stream, &mut rest);
-        ready!(Box::pin(fut).poll_unpin(cx))?;
-
-        // 拿到一个 frame 的数据，把 buffer 合并回去
-        self.rbuf.unsplit(rest);
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            // rbuf 中已经有一个完整的 frame，分离出来 decode
+            let need = frame_len(&this.rbuf);
+            if this.rbuf.len() >= need {
+                let mut frame = this.rbuf.split_to(need);
+                return Poll::Ready(Some(In::decode_frame(&mut frame)));
+            }
+
+            // 否则继续从 stream 中读取，读了一半的 frame 留在 rbuf 中，下次调用时接着读
+            this.rbuf.reserve(need - this.rbuf.len());
+            let n = ready!(poll_read_buf(
+                Pin::new(&mut this.stream),
+                cx,
+                &mut this.rbuf
+            ))?;
+            if n == 0 {
+                // 在两个 frame 之间对方关闭了连接，stream 正常结束
+                if this.rbuf.is_empty() {
+                    return Poll::Ready(None);
+                }
+                let e = std::io::Error::from(ErrorKind::UnexpectedEof);
+                return Poll::Ready(Some(Err(e.into())));
+            }
+        }
+    }
+}
 
-        // 调用 decode_frame 获取解包后的数据
-        Poll::Ready(Some(In::decode_frame(&mut self.rbuf)))
+/// 读到下一个完整的 frame 需要 rbuf 中有多少数据，还没有读到长度时先读长度
+fn frame_len(buf: &BytesMut) -> usize {
+    match buf.get(..LEN_LEN) {
+        Some(header) => {
+            let header = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+            LEN_LEN + decode_header(header).0
+        }
+        None => LEN_LEN,
     }
 }
 
@@ -69,7 +95,7 @@ where
 
     fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
         let this = self.get_mut();
-        item.encode_frame(&mut this.wbuf)?;
+        item.encode_frame_with(&mut this.wbuf, &this.compression)?;
 
         Ok(())
     }
@@ -117,10 +143,16 @@ where
             written: 0,
             wbuf: BytesMut::new(),
             rbuf: BytesMut::new(),
-            _in: PhantomData::default(),
-            _out: PhantomData::default(),
+            compression: CompressionConfig::default(),
+            _in: PhantomData,
+            _out: PhantomData,
         }
     }
+
+    /// 修改之后发送的 frame 使用的压缩配置，收到的 frame 根据 header 中的算法解压
+    pub fn set_compression(&mut self, config: CompressionConfig) {
+        self.compression = config;
+    }
 }
 
 #[cfg(test)]
@@ -149,6 +181,9 @@ mod tests {
         } else {
             assert!(false);
         }
+
+        // 没有更多数据了，stream 结束
+        assert!(stream.next().await.is_none());
         Ok(())
     }
 }
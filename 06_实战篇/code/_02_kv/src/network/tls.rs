@@ -2,6 +2,7 @@ use std::io::Cursor;
 use std::sync::Arc;
 
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::Session as _;
 use tokio_rustls::rustls::{internal::pemfile, Certificate, ClientConfig, ServerConfig};
 use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore};
 use tokio_rustls::webpki::DNSNameRef;
@@ -10,6 +11,7 @@ use tokio_rustls::{
     client::TlsStream as ClientTlsStream, server::TlsStream as ServerTlsStream, TlsAcceptor,
 };
 use tracing::instrument;
+use x509_parser::parse_x509_certificate;
 
 use crate::KvError;
 
@@ -153,6 +155,15 @@ fn load_key(key: &str) -> Result<PrivateKey, KvError> {
     Err(KvError::CertifcateParseError("private", "key"))
 }
 
+/// 从客户端证书的 subject 中取出 CN 作为 principal，没有客户端证书时返回 None
+pub fn peer_principal<S>(stream: &ServerTlsStream<S>) -> Option<String> {
+    let (_, session) = stream.get_ref();
+    let cert = session.get_peer_certificates()?.into_iter().next()?;
+    let (_, cert) = parse_x509_certificate(&cert.0).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(|v| v.to_string())
+}
+
 #[cfg(test)]
 pub mod tls_utils {
     use crate::{KvError, TlsClientConnector, TlsServerAcceptor};
@@ -184,6 +195,7 @@ pub mod tls_utils {
 
 #[cfg(test)]
 mod tests {
+    use super::peer_principal;
     use super::tls_utils::tls_acceptor;
     use crate::network::tls::tls_utils::tls_connector;
     use anyhow::Result;
@@ -222,6 +234,28 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn peer_principal_should_be_client_cert_subject() -> Result<()> {
+        for client_cert in [true, false] {
+            let acceptor = tls_acceptor(client_cert)?;
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stream = acceptor.accept(stream).await.unwrap();
+                peer_principal(&stream)
+            });
+
+            let stream = TcpStream::connect(addr).await?;
+            let _stream = tls_connector(client_cert)?.connect(stream).await?;
+            let principal = server.await?;
+            let expected = client_cert.then(|| "awesome-device-id".to_string());
+            assert_eq!(principal, expected);
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn tls_with_bad_domain_should_not_work() -> Result<()> {
         let addr = start_server(false).await?;
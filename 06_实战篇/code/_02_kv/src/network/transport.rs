@@ -0,0 +1,217 @@
+use std::io;
+#[cfg(unix)]
+use std::{os::unix::fs::FileTypeExt, path::PathBuf};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{info, warn};
+
+use super::tls::peer_principal;
+use crate::{
+    ClientConfig, ClientTlsConfig, KvError, ListenerConfig, ServerTlsConfig, TlsClientConnector,
+    TlsServerAcceptor, YamuxCtrl,
+};
+
+/// yamux 下面的连接：TLS、TCP 或者 Unix domain socket
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+/// 服务器监听的一个地址
+pub enum Listener {
+    Tls(TcpListener, TlsServerAcceptor),
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// 刚接受的连接，TLS 连接还需要完成握手
+pub enum Incoming {
+    Tls(TcpStream, TlsServerAcceptor),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    /// 在 config 指定的地址上监听，TLS listener 使用 tls 中的证书
+    pub async fn bind(config: &ListenerConfig, tls: &ServerTlsConfig) -> Result<Self, KvError> {
+        let listener = match config {
+            ListenerConfig::Tls { addr } => {
+                let acceptor = TlsServerAcceptor::new(&tls.cert, &tls.key, tls.ca.as_deref())?;
+                Self::Tls(TcpListener::bind(addr).await?, acceptor)
+            }
+            ListenerConfig::Tcp { addr } => Self::Tcp(TcpListener::bind(addr).await?),
+            #[cfg(unix)]
+            ListenerConfig::Unix { path } => {
+                // 上次退出时没有删除的 socket 文件会让 bind 失败，但不是 socket 的文件不能删
+                match std::fs::symlink_metadata(path) {
+                    Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+                    Ok(_) => {
+                        return Err(KvError::InvalidConfig(format!(
+                            "{path} exists and is not a unix socket"
+                        )))
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+                Self::Unix(UnixListener::bind(path)?, path.into())
+            }
+            #[cfg(not(unix))]
+            ListenerConfig::Unix { .. } => {
+                return Err(KvError::InvalidConfig(
+                    "unix domain socket is not supported".into(),
+                ))
+            }
+        };
+        info!("Start listening on {}", listener.name());
+
+        Ok(listener)
+    }
+
+    /// 接受一个新的连接，同时返回客户端的地址
+    pub async fn accept(&self) -> io::Result<(Incoming, String)> {
+        match self {
+            Self::Tls(listener, acceptor) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Incoming::Tls(stream, acceptor.clone()), addr.to_string()))
+            }
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Incoming::Tcp(stream), addr.to_string()))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Incoming::Unix(stream), path.display().to_string()))
+            }
+        }
+    }
+
+    /// 用于日志的名字，比如 tls://127.0.0.1:9527
+    pub fn name(&self) -> String {
+        let addr = |listener: &TcpListener| match listener.local_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => "unknown".into(),
+        };
+        match self {
+            Self::Tls(listener, _) => format!("tls://{}", addr(listener)),
+            Self::Tcp(listener) => format!("tcp://{}", addr(listener)),
+            #[cfg(unix)]
+            Self::Unix(_, path) => format!("unix://{}", path.display()),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Incoming {
+    /// 完成握手，返回连接和客户端证书中的 principal
+    pub async fn establish(self) -> Result<(Box<dyn Transport>, Option<String>), KvError> {
+        match self {
+            Self::Tls(stream, acceptor) => {
+                let stream = acceptor.accept(stream).await?;
+                let principal = peer_principal(&stream);
+                Ok((Box::new(stream), principal))
+            }
+            Self::Tcp(stream) => Ok((Box::new(stream), None)),
+            #[cfg(unix)]
+            Self::Unix(stream) => Ok((Box::new(stream), None)),
+        }
+    }
+}
+
+/// 连接 config 指定的服务器地址，TLS 连接使用 tls 中的配置
+pub async fn connect(
+    config: &ListenerConfig,
+    tls: &ClientTlsConfig,
+) -> Result<Box<dyn Transport>, KvError> {
+    match config {
+        ListenerConfig::Tls { addr } => {
+            let identity = tls.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
+            let connector = TlsClientConnector::new(&tls.domain, identity, tls.ca.as_deref())?;
+            let stream = TcpStream::connect(addr).await?;
+            Ok(Box::new(connector.connect(stream).await?))
+        }
+        ListenerConfig::Tcp { addr } => Ok(Box::new(TcpStream::connect(addr).await?)),
+        #[cfg(unix)]
+        ListenerConfig::Unix { path } => Ok(Box::new(UnixStream::connect(path).await?)),
+        #[cfg(not(unix))]
+        ListenerConfig::Unix { .. } => Err(KvError::InvalidConfig(
+            "unix domain socket is not supported".into(),
+        )),
+    }
+}
+
+/// 依次尝试 config 中的地址，返回第一个连接成功并且协商好压缩算法的 yamux 客户端，以及它的地址
+pub async fn connect_client(
+    config: &ClientConfig,
+) -> Result<(YamuxCtrl<Box<dyn Transport>>, ListenerConfig), KvError> {
+    let mut error = KvError::InvalidConfig("No server address is configured".into());
+    for listener in config.effective_listeners() {
+        let stream = match connect(&listener, &config.tls).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to connect to {:?}: {:?}", listener, e);
+                error = e;
+                continue;
+            }
+        };
+
+        let mut ctrl = YamuxCtrl::new_client(stream, None);
+        if let Err(e) = ctrl.negotiate(config.compression).await {
+            warn!("Failed to negotiate with {:?}: {:?}", listener, e);
+            error = e;
+            continue;
+        }
+        return Ok((ctrl, listener));
+    }
+    Err(error)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn unix(path: &std::path::Path) -> ListenerConfig {
+        ListenerConfig::Unix {
+            path: path.to_string_lossy().into_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_should_replace_stale_socket() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("kv.sock");
+        // 模拟上次没有正常退出留下的 socket 文件
+        std::mem::forget(UnixListener::bind(&path)?);
+
+        let listener = Listener::bind(&unix(&path), &ServerTlsConfig::default()).await?;
+        UnixStream::connect(&path).await?;
+        drop(listener);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bind_should_not_remove_regular_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("kv.sock");
+        std::fs::write(&path, "data")?;
+
+        let res = Listener::bind(&unix(&path), &ServerTlsConfig::default()).await;
+        assert!(matches!(res, Err(KvError::InvalidConfig(_))));
+        assert_eq!(std::fs::read_to_string(&path)?, "data");
+        Ok(())
+    }
+}
@@ -2,7 +2,12 @@
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12")]
+    /// 请求的 id, 服务器在这个请求的所有 response 中带上同样的 id
+    /// 为 0 时按顺序处理, 不为 0 时同一个 stream 上的请求可以并发执行, 客户端用 id 匹配 response
+    /// 字段号和 request_data 中的命令分开, 留出增加命令的空间
+    #[prost(uint64, tag="1000")]
+    pub id: u64,
+    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28")]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
@@ -34,6 +39,38 @@ pub mod command_request {
         Unsubscribe(super::Unsubscribe),
         #[prost(message, tag="12")]
         Publish(super::Publish),
+        #[prost(message, tag="13")]
+        Hexpire(super::Hexpire),
+        #[prost(message, tag="14")]
+        Httl(super::Httl),
+        #[prost(message, tag="15")]
+        Hpersist(super::Hpersist),
+        #[prost(message, tag="16")]
+        Hincrby(super::Hincrby),
+        #[prost(message, tag="17")]
+        Hincrbyfloat(super::Hincrbyfloat),
+        #[prost(message, tag="18")]
+        Hcas(super::Hcas),
+        #[prost(message, tag="19")]
+        Hsetnx(super::Hsetnx),
+        #[prost(message, tag="20")]
+        Multi(super::Multi),
+        #[prost(message, tag="21")]
+        Hscan(super::Hscan),
+        #[prost(message, tag="22")]
+        Hprefix(super::Hprefix),
+        #[prost(message, tag="23")]
+        Replicate(super::Replicate),
+        #[prost(message, tag="24")]
+        ReplicationInfo(super::ReplicationInfo),
+        #[prost(message, tag="25")]
+        Auth(super::Auth),
+        #[prost(message, tag="26")]
+        Psubscribe(super::Psubscribe),
+        #[prost(message, tag="27")]
+        Punsubscribe(super::Punsubscribe),
+        #[prost(message, tag="28")]
+        Hello(super::Hello),
     }
 }
 /// 服务器的响应
@@ -52,12 +89,27 @@ pub struct CommandResponse {
     /// 成功返回的Kvpair
     #[prost(message, repeated, tag="4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// Multi 命令中每个命令各自的返回结果
+    #[prost(message, repeated, tag="5")]
+    pub responses: ::prost::alloc::vec::Vec<CommandResponse>,
+    /// 持久化主题中消息的序号, 从 1 开始单调递增, 其它的 response 中为 0
+    #[prost(uint64, tag="6")]
+    pub seq: u64,
+    /// 推送给订阅者的消息中, 在这条消息之前因为订阅者太慢而丢弃的消息数量
+    #[prost(uint64, tag="7")]
+    pub lost: u64,
+    /// 对应的请求的 id
+    #[prost(uint64, tag="8")]
+    pub id: u64,
+    /// pattern 订阅收到的消息所属的主题, 其它的 response 中为空
+    #[prost(string, tag="9")]
+    pub topic: ::prost::alloc::string::String,
 }
 /// 返回的值
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5")]
+    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5, 6, 7")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
@@ -75,8 +127,27 @@ pub mod value {
         Float(f64),
         #[prost(bool, tag="5")]
         Bool(bool),
+        /// 嵌套的 Value 组成的列表和 map, 用来存放 JSON 这样的结构化数据
+        #[prost(message, tag="6")]
+        List(super::ValueList),
+        #[prost(message, tag="7")]
+        Map(super::ValueMap),
     }
 }
+/// Value 的列表
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueList {
+    #[prost(message, repeated, tag="1")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// key 为字符串的 Value 的 map, 按 key 排序
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueMap {
+    #[prost(btree_map="string, message", tag="1")]
+    pub entries: ::prost::alloc::collections::BTreeMap<::prost::alloc::string::String, Value>,
+}
 /// 返回的Kvpair
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -96,11 +167,15 @@ pub struct Hget {
     pub key: ::prost::alloc::string::String,
 }
 /// 总table中获取所有的Kvpair
+/// chunk_size 大于 0 时, 结果会分成多个 CommandResponse 返回, 每个最多包含 chunk_size 个 Kvpair,
+/// 所有的结果发送完后服务器关闭这个 stream. 大的 table 应该用这种方式读取, 以免单个 frame 过大
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
+    #[prost(uint32, tag="2")]
+    pub chunk_size: u32,
 }
 /// 从table中获取一组key, 返回它们的value
 #[derive(PartialOrd)]
@@ -167,11 +242,17 @@ pub struct Hmexist {
 }
 /// subscribe到某个主题, 任何发布到这个主题的数据都会被收到
 /// 成功后, 第一个返回的CommandResponse, 我们返回一个唯一的subscription id
+/// 对于持久化的主题, 可以先重放保留的消息: from_seq 不为 0 时从这个序号开始,
+/// 否则 last_n 不为 0 时重放最后 last_n 条消息, 重放完后继续推送新的消息
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Subscribe {
     #[prost(string, tag="1")]
     pub topic: ::prost::alloc::string::String,
+    #[prost(uint64, tag="2")]
+    pub from_seq: u64,
+    #[prost(uint32, tag="3")]
+    pub last_n: u32,
 }
 /// 取消第某个主题的订阅
 #[derive(PartialOrd)]
@@ -182,6 +263,25 @@ pub struct Unsubscribe {
     #[prost(uint32, tag="2")]
     pub id: u32,
 }
+/// 用 pattern 订阅所有匹配的主题, 主题按 '.' 分成 segment, `*` 匹配一个 segment,
+/// `**` 只能是最后一个 segment, 匹配剩下的零个或多个 segment, 比如 orders.*.created
+/// 和 Subscribe 一样, 第一个返回的 CommandResponse 是 subscription id,
+/// 之后推送的 CommandResponse 在 message 里带上数据所属的主题
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Psubscribe {
+    #[prost(string, tag="1")]
+    pub pattern: ::prost::alloc::string::String,
+}
+/// 取消某个 pattern 的订阅
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Punsubscribe {
+    #[prost(string, tag="1")]
+    pub pattern: ::prost::alloc::string::String,
+    #[prost(uint32, tag="2")]
+    pub id: u32,
+}
 /// 发布数据到某个主题
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -191,3 +291,138 @@ pub struct Publish {
     #[prost(message, repeated, tag="2")]
     pub data: ::prost::alloc::vec::Vec<Value>,
 }
+/// 给 table 中的某个 key 设置过期时间, 过期后 key 会被自动删除
+/// 返回 key 是否存在（即是否设置成功）
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpire {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    /// 存活时间, 单位毫秒
+    #[prost(uint64, tag="3")]
+    pub ttl: u64,
+}
+/// 查看 key 剩余的存活时间（毫秒）, 没有设置过期时间返回 -1
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Httl {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 清除 key 的过期时间, 返回之前是否设置过
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hpersist {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 给 table 中 key 的整数 value 原子地加上 delta, key 不存在时当作 0, 返回新的值
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrby {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub delta: i64,
+}
+/// 给 table 中 key 的浮点数 value 原子地加上 delta, key 不存在时当作 0, 返回新的值
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrbyfloat {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(double, tag="3")]
+    pub delta: f64,
+}
+/// 只有 key 当前的值等于 expected 时, 才把它更新成 value, 否则返回 409
+/// expected 为空表示 key 必须不存在. 成功时返回之前的值
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hcas {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub expected: ::core::option::Option<Value>,
+    #[prost(message, optional, tag="4")]
+    pub value: ::core::option::Option<Value>,
+}
+/// 只有 key 不存在时才设置 Kvpair, key 已经存在返回 409
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetnx {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="2")]
+    pub pair: ::core::option::Option<Kvpair>,
+}
+/// 在一个事务中执行一组命令, 要么全部生效, 要么全部不生效
+/// 只支持读写具体 key 的命令（HGET/HSET/HDEL/HINCRBY/HCAS 等）
+/// 任何一个命令失败（非 2xx）都会让整个事务回滚
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Multi {
+    #[prost(message, repeated, tag="1")]
+    pub commands: ::prost::alloc::vec::Vec<CommandRequest>,
+}
+/// 按 key 的顺序分页遍历 table, 返回 cursor 之后（不包括 cursor）的最多 limit 个 Kvpair
+/// cursor 为空表示从头开始, 下一页的 cursor 是这一页最后一个 key
+/// 返回的个数小于 limit 表示已经遍历完了, limit 为 0 表示不限制个数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hscan {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub cursor: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub limit: u32,
+}
+/// 按 key 的顺序返回 table 中所有以 prefix 开头的 Kvpair
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hprefix {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub prefix: ::prost::alloc::string::String,
+}
+/// 从节点向主节点发起复制, 主节点先返回所有的数据（snapshot）, 然后持续返回之后的修改
+/// 每个 CommandResponse 的 values 里是一个编码后的 ReplicationFrame
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Replicate {
+}
+/// 查看主从复制的状态, 在 pairs 里返回 role/seq/lag_ms 等信息
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReplicationInfo {
+}
+/// 用 token 认证当前连接, 成功后连接上的所有命令都以 token 对应的 principal 执行
+/// 成功时在 values 里返回 principal
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Auth {
+    #[prost(string, tag="1")]
+    pub token: ::prost::alloc::string::String,
+}
+/// 连接建立时协商 frame 的压缩算法, 连接上的所有 stream 共享协商的结果
+/// 成功时在 values 里返回服务器能解压的压缩算法
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hello {
+    /// 客户端能解压的压缩算法: none, gzip, lz4, zstd
+    #[prost(string, repeated, tag="1")]
+    pub codecs: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
@@ -0,0 +1,161 @@
+//! Value 和 serde 之间的转换：列表和 map 对应 JSON 的数组和对象，
+//! 实现了 Serialize/DeserializeOwned 的类型可以通过 Json 存成 Value
+
+use std::{any::type_name, collections::BTreeMap};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::abi::{value, Value, ValueList, ValueMap};
+use crate::KvError;
+
+/// 通过 serde 存取任意类型的数据，比如 `Value::try_from(Json(user))` 和 `client.get::<Json<User>>()`
+/// 结构体和 map 存成 Value 的 map，序列和元组存成 Value 的列表
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Self {
+        Self {
+            value: Some(value::Value::List(ValueList { values })),
+        }
+    }
+}
+
+impl From<BTreeMap<String, Value>> for Value {
+    fn from(entries: BTreeMap<String, Value>) -> Self {
+        Self {
+            value: Some(value::Value::Map(ValueMap { entries })),
+        }
+    }
+}
+
+/// null 转换成空的 Value，在 i64 范围内的整数转换成 integer，其它的数转换成 float
+impl From<serde_json::Value> for Value {
+    fn from(v: serde_json::Value) -> Self {
+        match v {
+            serde_json::Value::Null => Value::default(),
+            serde_json::Value::Bool(b) => b.into(),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => i.into(),
+                None => n.as_f64().unwrap_or_default().into(),
+            },
+            serde_json::Value::String(s) => s.into(),
+            serde_json::Value::Array(v) => {
+                v.into_iter().map(Value::from).collect::<Vec<_>>().into()
+            }
+            serde_json::Value::Object(v) => v
+                .into_iter()
+                .map(|(k, v)| (k, v.into()))
+                .collect::<BTreeMap<_, _>>()
+                .into(),
+        }
+    }
+}
+
+/// 空的 Value 转换成 null；JSON 中没有二进制，binary 转换成字节的数组；NaN 和无穷大转换成 null
+impl From<Value> for serde_json::Value {
+    fn from(v: Value) -> Self {
+        match v.value {
+            None => serde_json::Value::Null,
+            Some(value::Value::String(s)) => s.into(),
+            Some(value::Value::Binary(b)) => b.iter().copied().collect(),
+            Some(value::Value::Integer(i)) => i.into(),
+            Some(value::Value::Float(f)) => f.into(),
+            Some(value::Value::Bool(b)) => b.into(),
+            Some(value::Value::List(v)) => v.values.into_iter().map(Self::from).collect(),
+            Some(value::Value::Map(v)) => {
+                Self::Object(v.entries.into_iter().map(|(k, v)| (k, v.into())).collect())
+            }
+        }
+    }
+}
+
+impl<T: Serialize> TryFrom<Json<T>> for Value {
+    type Error = KvError;
+
+    fn try_from(v: Json<T>) -> Result<Self, Self::Error> {
+        match serde_json::to_value(v.0) {
+            Ok(v) => Ok(v.into()),
+            Err(e) => Err(KvError::ConvertError(
+                format!("{} ({})", type_name::<T>(), e),
+                "Value",
+            )),
+        }
+    }
+}
+
+impl<T: DeserializeOwned> TryFrom<Value> for Json<T> {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        let json = serde_json::Value::from(v);
+        match T::deserialize(&json) {
+            Ok(v) => Ok(Json(v)),
+            Err(e) => Err(KvError::ConvertError(
+                format!("{} ({})", json, e),
+                type_name::<T>(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, serde::Deserialize)]
+    struct User {
+        name: String,
+        age: i64,
+        tags: Vec<String>,
+        score: Option<f64>,
+    }
+
+    #[test]
+    fn json_should_be_converted_to_value() {
+        let json = serde_json::json!({
+            "name": "alice",
+            "age": 30,
+            "tags": ["admin", 1.5, true, null],
+        });
+        let v = Value::from(json.clone());
+
+        let tags = vec!["admin".into(), 1.5.into(), true.into(), Value::default()];
+        let expected = BTreeMap::from([
+            ("name".to_string(), "alice".into()),
+            ("age".to_string(), 30.into()),
+            ("tags".to_string(), tags.into()),
+        ]);
+        assert_eq!(v, expected.into());
+        assert_eq!(serde_json::Value::from(v), json);
+
+        // binary 转换成字节的数组
+        let v = Value::from(Bytes::from_static(b"\x01\xff"));
+        assert_eq!(serde_json::Value::from(v), serde_json::json!([1, 255]));
+    }
+
+    #[test]
+    fn serde_types_should_be_converted_to_value() {
+        let user = User {
+            name: "alice".into(),
+            age: 30,
+            tags: vec!["admin".into()],
+            score: None,
+        };
+        let v = Value::try_from(Json(user)).unwrap();
+        let Some(value::Value::Map(map)) = &v.value else {
+            panic!("expect a map: {:?}", v);
+        };
+        assert_eq!(map.entries["age"], 30.into());
+
+        let Json(user) = Json::<User>::try_from(v.clone()).unwrap();
+        assert_eq!(user.name, "alice");
+        assert_eq!(user.tags, ["admin"]);
+
+        // 类型不匹配时返回转换错误
+        let res = Json::<Vec<i64>>::try_from(v);
+        assert!(matches!(res, Err(KvError::ConvertError(..))));
+    }
+}
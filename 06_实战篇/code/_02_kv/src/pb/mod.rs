@@ -1,13 +1,21 @@
 pub mod abi;
+mod json;
+mod text;
+pub(crate) mod wal;
 
-use std::convert::{TryFrom, TryInto};
+use std::{
+    convert::{TryFrom, TryInto},
+    time::Duration,
+};
 
 use abi::{command_request::RequestData, *};
 use bytes::Bytes;
 use http::StatusCode;
 use prost::Message;
 
-use crate::KvError;
+pub use json::Json;
+
+use crate::{Codec, KvError};
 
 impl CommandRequest {
     pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
@@ -16,6 +24,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -23,7 +32,20 @@ impl CommandRequest {
         Self {
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into(),
+                chunk_size: 0,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 获取 table 中所有的 kv pair，结果分批返回，每批最多 chunk_size 个
+    pub fn new_hgetall_chunked(table: impl Into<String>, chunk_size: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table.into(),
+                chunk_size,
             })),
+            ..Default::default()
         }
     }
 
@@ -33,6 +55,7 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
@@ -42,6 +65,7 @@ impl CommandRequest {
                 table: table.into(),
                 pair: Some(Kvpair::new(key, value)),
             })),
+            ..Default::default()
         }
     }
 
@@ -51,6 +75,7 @@ impl CommandRequest {
                 table: table.into(),
                 pairs,
             })),
+            ..Default::default()
         }
     }
 
@@ -60,6 +85,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -69,6 +95,7 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
@@ -78,6 +105,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -87,12 +115,41 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
     pub fn new_subscribe(name: impl Into<String>) -> Self {
         Self {
-            request_data: Some(RequestData::Subscribe(Subscribe { topic: name.into() })),
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: name.into(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 订阅持久化的主题，先重放序号从 from_seq 开始的消息
+    pub fn new_subscribe_from(name: impl Into<String>, from_seq: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: name.into(),
+                from_seq,
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 订阅持久化的主题，先重放最后 last_n 条消息
+    pub fn new_subscribe_last(name: impl Into<String>, last_n: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: name.into(),
+                last_n,
+                ..Default::default()
+            })),
+            ..Default::default()
         }
     }
 
@@ -102,6 +159,26 @@ impl CommandRequest {
                 topic: name.into(),
                 id,
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_psubscribe(pattern: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Psubscribe(Psubscribe {
+                pattern: pattern.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_punsubscribe(pattern: impl Into<String>, id: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Punsubscribe(Punsubscribe {
+                pattern: pattern.into(),
+                id,
+            })),
+            ..Default::default()
         }
     }
 
@@ -111,6 +188,204 @@ impl CommandRequest {
                 topic: name.into(),
                 data,
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hexpire(table: impl Into<String>, key: impl Into<String>, ttl: Duration) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpire(Hexpire {
+                table: table.into(),
+                key: key.into(),
+                ttl: ttl.as_millis() as _,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_httl(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Httl(Httl {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hpersist(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hpersist(Hpersist {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hincrby(table: impl Into<String>, key: impl Into<String>, delta: i64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrby(Hincrby {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hincrbyfloat(table: impl Into<String>, key: impl Into<String>, delta: f64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrbyfloat(Hincrbyfloat {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hcas(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hcas(Hcas {
+                table: table.into(),
+                key: key.into(),
+                expected,
+                value: Some(value),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hsetnx(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hsetnx(Hsetnx {
+                table: table.into(),
+                pair: Some(Kvpair::new(key, value)),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_multi(commands: Vec<CommandRequest>) -> Self {
+        Self {
+            request_data: Some(RequestData::Multi(Multi { commands })),
+            ..Default::default()
+        }
+    }
+
+    /// 分页遍历 table，返回 cursor 之后的最多 limit 个 kv pair
+    pub fn new_hscan(table: impl Into<String>, cursor: impl Into<String>, limit: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Hscan(Hscan {
+                table: table.into(),
+                cursor: cursor.into(),
+                limit,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hprefix(table: impl Into<String>, prefix: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hprefix(Hprefix {
+                table: table.into(),
+                prefix: prefix.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_replicate() -> Self {
+        Self {
+            request_data: Some(RequestData::Replicate(Replicate {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_replication_info() -> Self {
+        Self {
+            request_data: Some(RequestData::ReplicationInfo(ReplicationInfo {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_auth(token: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                token: token.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 告诉服务器客户端能解压的压缩算法
+    pub fn new_hello(codecs: &[Codec]) -> Self {
+        Self {
+            request_data: Some(RequestData::Hello(Hello {
+                codecs: codecs.iter().map(|v| v.as_str().into()).collect(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 是否是修改数据的命令
+    pub fn is_write(&self) -> bool {
+        match &self.request_data {
+            Some(RequestData::Multi(v)) => v.commands.iter().any(|cmd| cmd.is_write()),
+            Some(
+                RequestData::Hset(_)
+                | RequestData::Hmset(_)
+                | RequestData::Hdel(_)
+                | RequestData::Hmdel(_)
+                | RequestData::Hexpire(_)
+                | RequestData::Hpersist(_)
+                | RequestData::Hincrby(_)
+                | RequestData::Hincrbyfloat(_)
+                | RequestData::Hcas(_)
+                | RequestData::Hsetnx(_),
+            ) => true,
+            _ => false,
+        }
+    }
+
+    /// 命令的名字，用于 metrics 的 label
+    pub fn name(&self) -> &'static str {
+        match &self.request_data {
+            Some(RequestData::Hget(_)) => "hget",
+            Some(RequestData::Hgetall(_)) => "hgetall",
+            Some(RequestData::Hmget(_)) => "hmget",
+            Some(RequestData::Hset(_)) => "hset",
+            Some(RequestData::Hmset(_)) => "hmset",
+            Some(RequestData::Hdel(_)) => "hdel",
+            Some(RequestData::Hmdel(_)) => "hmdel",
+            Some(RequestData::Hexist(_)) => "hexist",
+            Some(RequestData::Hmexist(_)) => "hmexist",
+            Some(RequestData::Subscribe(_)) => "subscribe",
+            Some(RequestData::Unsubscribe(_)) => "unsubscribe",
+            Some(RequestData::Publish(_)) => "publish",
+            Some(RequestData::Hexpire(_)) => "hexpire",
+            Some(RequestData::Httl(_)) => "httl",
+            Some(RequestData::Hpersist(_)) => "hpersist",
+            Some(RequestData::Hincrby(_)) => "hincrby",
+            Some(RequestData::Hincrbyfloat(_)) => "hincrbyfloat",
+            Some(RequestData::Hcas(_)) => "hcas",
+            Some(RequestData::Hsetnx(_)) => "hsetnx",
+            Some(RequestData::Multi(_)) => "multi",
+            Some(RequestData::Hscan(_)) => "hscan",
+            Some(RequestData::Hprefix(_)) => "hprefix",
+            Some(RequestData::Replicate(_)) => "replicate",
+            Some(RequestData::ReplicationInfo(_)) => "replication_info",
+            Some(RequestData::Auth(_)) => "auth",
+            Some(RequestData::Psubscribe(_)) => "psubscribe",
+            Some(RequestData::Punsubscribe(_)) => "punsubscribe",
+            Some(RequestData::Hello(_)) => "hello",
+            None => "none",
         }
     }
 
@@ -120,6 +395,62 @@ impl CommandRequest {
     }
 }
 
+impl wal::WalRecord {
+    pub fn new_set(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+        Self {
+            op: Some(wal::wal_record::Op::Set(wal::Set {
+                table: table.into(),
+                key: key.into(),
+                value: Some(value),
+            })),
+        }
+    }
+
+    pub fn new_del(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            op: Some(wal::wal_record::Op::Del(wal::Del {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    pub fn new_update(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+        Self {
+            op: Some(wal::wal_record::Op::Update(wal::Update {
+                table: table.into(),
+                key: key.into(),
+                value: Some(value),
+            })),
+        }
+    }
+
+    pub fn new_expire_at(table: impl Into<String>, key: impl Into<String>, at: u64) -> Self {
+        Self {
+            op: Some(wal::wal_record::Op::ExpireAt(wal::ExpireAt {
+                table: table.into(),
+                key: key.into(),
+                at,
+            })),
+        }
+    }
+
+    pub fn new_persist(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            op: Some(wal::wal_record::Op::Persist(wal::Persist {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    pub fn new_batch(records: Vec<wal::WalRecord>) -> Self {
+        Self {
+            op: Some(wal::wal_record::Op::Batch(wal::Batch { records })),
+        }
+    }
+}
+
 impl CommandResponse {
     pub fn ok() -> Self {
         CommandResponse {
@@ -230,11 +561,28 @@ impl From<KvError> for CommandResponse {
             message: e.to_string(),
             values: vec![],
             pairs: vec![],
+            responses: vec![],
+            seq: 0,
+            lost: 0,
+            id: 0,
+            topic: String::new(),
         };
 
         match e {
             KvError::NotFound(_) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
-            KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::Conflict(_) => result.status = StatusCode::CONFLICT.as_u16() as _,
+            KvError::ReadOnly | KvError::PermissionDenied(_) => {
+                result.status = StatusCode::FORBIDDEN.as_u16() as _
+            }
+            KvError::Unauthorized(_) => result.status = StatusCode::UNAUTHORIZED.as_u16() as _,
+            KvError::RateLimited(_) => result.status = StatusCode::TOO_MANY_REQUESTS.as_u16() as _,
+            KvError::QuotaExceeded(_) => {
+                result.status = StatusCode::INSUFFICIENT_STORAGE.as_u16() as _
+            }
+            KvError::SlowSubscriber(_) => result.status = StatusCode::GONE.as_u16() as _,
+            KvError::InvalidCommand(_) | KvError::ConvertError(..) => {
+                result.status = StatusCode::BAD_REQUEST.as_u16() as _
+            }
             _ => {}
         }
 
@@ -242,6 +590,43 @@ impl From<KvError> for CommandResponse {
     }
 }
 
+/// 从出错的 CommandResponse 转换回 KvError，客户端根据状态码还原错误的类型
+impl From<CommandResponse> for KvError {
+    fn from(res: CommandResponse) -> Self {
+        // message 是 KvError 的 Display，去掉前面的错误类型，只保留错误的详情
+        let detail = match res.message.split_once(": ") {
+            Some((_, detail)) => detail.trim_matches('`').to_string(),
+            None => res.message.clone(),
+        };
+
+        match StatusCode::from_u16(res.status as _).unwrap_or_default() {
+            StatusCode::NOT_FOUND => KvError::NotFound(detail),
+            StatusCode::CONFLICT => KvError::Conflict(detail),
+            StatusCode::FORBIDDEN if res.message == KvError::ReadOnly.to_string() => {
+                KvError::ReadOnly
+            }
+            StatusCode::FORBIDDEN => KvError::PermissionDenied(detail),
+            StatusCode::UNAUTHORIZED => KvError::Unauthorized(detail),
+            StatusCode::TOO_MANY_REQUESTS => KvError::RateLimited(detail),
+            StatusCode::INSUFFICIENT_STORAGE => KvError::QuotaExceeded(detail),
+            StatusCode::GONE => KvError::SlowSubscriber(detail),
+            StatusCode::BAD_REQUEST => KvError::InvalidCommand(detail),
+            _ => KvError::Internal(res.message),
+        }
+    }
+}
+
+/// 从 Multi 中每个命令的结果转换成 CommandResponse
+impl From<Vec<CommandResponse>> for CommandResponse {
+    fn from(v: Vec<CommandResponse>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            responses: v,
+            ..Default::default()
+        }
+    }
+}
+
 impl From<Vec<Value>> for CommandResponse {
     fn from(v: Vec<Value>) -> Self {
         Self {
@@ -301,6 +686,28 @@ impl TryFrom<Value> for f64 {
     }
 }
 
+impl TryFrom<&Value> for f64 {
+    type Error = KvError;
+
+    fn try_from(v: &Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::Float(f)) => Ok(f),
+            _ => Err(KvError::ConvertError(v.format(), "Float")),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::String(s)) => Ok(s),
+            _ => Err(KvError::ConvertError(v.format(), "String")),
+        }
+    }
+}
+
 impl TryFrom<Value> for Bytes {
     type Error = KvError;
 
@@ -348,7 +755,7 @@ impl TryFrom<&CommandResponse> for i64 {
         if value.status != StatusCode::OK.as_u16() as u32 {
             return Err(KvError::ConvertError(value.format(), "CommandResponse"));
         }
-        match value.values.get(0) {
+        match value.values.first() {
             Some(v) => v.try_into(),
             None => Err(KvError::ConvertError(value.format(), "CommandResponse")),
         }
@@ -0,0 +1,493 @@
+//! 命令和结果的文本格式：把 `hset t1 k1 v1` 这样的文本解析成 CommandRequest，
+//! 把 CommandResponse 格式化成便于阅读的文本，kvc 使用
+
+use std::{fmt, ops::Deref, str::FromStr, time::Duration};
+
+use super::abi::{value, CommandRequest, CommandResponse, Kvpair, Value};
+use crate::{Codec, KvError};
+
+impl CommandRequest {
+    /// 所有支持的文本命令
+    pub const USAGE: &'static str = "\
+hget <table> <key>
+hgetall <table> [chunk_size]
+hmget <table> <key>...
+hset <table> <key> <value>
+hmset <table> <key> <value> [<key> <value>]...
+hdel <table> <key>
+hmdel <table> <key>...
+hexist <table> <key>
+hmexist <table> <key>...
+hexpire <table> <key> <ttl_ms>
+httl <table> <key>
+hpersist <table> <key>
+hincrby <table> <key> <delta>
+hincrbyfloat <table> <key> <delta>
+hcas <table> <key> <expected|nil> <value>
+hsetnx <table> <key> <value>
+hscan <table> [cursor] [limit]
+hprefix <table> <prefix>
+subscribe <topic> [from <seq> | last <n>]
+unsubscribe <topic> <id>
+psubscribe <pattern>
+punsubscribe <pattern> <id>
+publish <topic> <value>...
+auth <token>
+hello <codec>...
+info";
+}
+
+impl FromStr for CommandRequest {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = split_args(s)
+            .ok_or_else(|| KvError::InvalidCommand(format!("Unbalanced quotes: {}", s)))?;
+        let Some((name, args)) = args.split_first() else {
+            return Err(KvError::InvalidCommand("Empty command".into()));
+        };
+        let name = name.0.to_lowercase();
+        let args: Vec<_> = args
+            .iter()
+            .map(|(text, quoted)| Arg {
+                text,
+                quoted: *quoted,
+            })
+            .collect();
+
+        let cmd = match (name.as_str(), args.as_slice()) {
+            ("hget", [table, key]) => Self::new_hget(*table, *key),
+            ("hgetall", [table]) => Self::new_hgetall(*table),
+            ("hgetall", [table, size]) => Self::new_hgetall_chunked(*table, parse_number(size)?),
+            ("hmget", [table, keys @ ..]) if !keys.is_empty() => {
+                Self::new_hmget(*table, to_strings(keys))
+            }
+            ("hset", [table, key, value]) => Self::new_hset(*table, *key, value.value()),
+            ("hmset", [table, pairs @ ..]) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+                let pairs = pairs
+                    .chunks(2)
+                    .map(|v| Kvpair::new(v[0], v[1].value()))
+                    .collect();
+                Self::new_hmset(*table, pairs)
+            }
+            ("hdel", [table, key]) => Self::new_hdel(*table, *key),
+            ("hmdel", [table, keys @ ..]) if !keys.is_empty() => {
+                Self::new_hmdel(*table, to_strings(keys))
+            }
+            ("hexist", [table, key]) => Self::new_hexist(*table, *key),
+            ("hmexist", [table, keys @ ..]) if !keys.is_empty() => {
+                Self::new_hmexist(*table, to_strings(keys))
+            }
+            ("hexpire", [table, key, ttl]) => {
+                Self::new_hexpire(*table, *key, Duration::from_millis(parse_number(ttl)?))
+            }
+            ("httl", [table, key]) => Self::new_httl(*table, *key),
+            ("hpersist", [table, key]) => Self::new_hpersist(*table, *key),
+            ("hincrby", [table, key, delta]) => {
+                Self::new_hincrby(*table, *key, parse_number(delta)?)
+            }
+            ("hincrbyfloat", [table, key, delta]) => {
+                Self::new_hincrbyfloat(*table, *key, parse_number(delta)?)
+            }
+            ("hcas", [table, key, expected, value]) => {
+                let nil = !expected.quoted && expected.eq_ignore_ascii_case("nil");
+                let expected = (!nil).then(|| expected.value());
+                Self::new_hcas(*table, *key, expected, value.value())
+            }
+            ("hsetnx", [table, key, value]) => Self::new_hsetnx(*table, *key, value.value()),
+            ("hscan", [table]) => Self::new_hscan(*table, "", 0),
+            ("hscan", [table, cursor]) => Self::new_hscan(*table, *cursor, 0),
+            ("hscan", [table, cursor, limit]) => {
+                Self::new_hscan(*table, *cursor, parse_number(limit)?)
+            }
+            ("hprefix", [table, prefix]) => Self::new_hprefix(*table, *prefix),
+            ("subscribe", [topic]) => Self::new_subscribe(*topic),
+            ("subscribe", [topic, from, seq]) if from.eq_ignore_ascii_case("from") => {
+                Self::new_subscribe_from(*topic, parse_number(seq)?)
+            }
+            ("subscribe", [topic, last, n]) if last.eq_ignore_ascii_case("last") => {
+                Self::new_subscribe_last(*topic, parse_number(n)?)
+            }
+            ("unsubscribe", [topic, id]) => Self::new_unsubscribe(*topic, parse_number(id)?),
+            ("psubscribe", [pattern]) => Self::new_psubscribe(*pattern),
+            ("punsubscribe", [pattern, id]) => Self::new_punsubscribe(*pattern, parse_number(id)?),
+            ("publish", [topic, data @ ..]) if !data.is_empty() => {
+                Self::new_publish(*topic, data.iter().map(|v| v.value()).collect())
+            }
+            ("auth", [token]) => Self::new_auth(*token),
+            ("hello", codecs) if !codecs.is_empty() => {
+                let codecs: Result<Vec<Codec>, _> = codecs.iter().map(|v| v.parse()).collect();
+                Self::new_hello(&codecs?)
+            }
+            ("info", []) => Self::new_replication_info(),
+            (name, _) => {
+                // 命令存在但参数不对时，提示正确的用法
+                let usage = Self::USAGE
+                    .lines()
+                    .find(|v| v.split_whitespace().next() == Some(name));
+                return Err(KvError::InvalidCommand(match usage {
+                    Some(usage) => format!("Usage: {}", usage),
+                    None => format!("Unknown command: {}", name),
+                }));
+            }
+        };
+
+        Ok(cmd)
+    }
+}
+
+/// 命令行中的一个参数，记录了它是否用了引号
+#[derive(Debug, Clone, Copy)]
+struct Arg<'a> {
+    text: &'a str,
+    quoted: bool,
+}
+
+impl Deref for Arg<'_> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.text
+    }
+}
+
+impl From<Arg<'_>> for String {
+    fn from(arg: Arg<'_>) -> Self {
+        arg.text.into()
+    }
+}
+
+impl Arg<'_> {
+    /// 用了引号的参数总是字符串，这样才能写入 "42"、"true" 这样的字符串
+    fn value(&self) -> Value {
+        match self.quoted {
+            true => self.text.into(),
+            false => parse_value(self.text),
+        }
+    }
+}
+
+/// 按照 shell 的规则拆分命令行，返回每个参数和它是否（部分）用了引号，引号不匹配时返回 None
+fn split_args(s: &str) -> Option<Vec<(String, bool)>> {
+    let mut args = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Some(args);
+        }
+
+        let (mut arg, mut quoted) = (String::new(), false);
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            match c {
+                '\'' => {
+                    quoted = true;
+                    loop {
+                        match chars.next()? {
+                            '\'' => break,
+                            c => arg.push(c),
+                        }
+                    }
+                }
+                '"' => {
+                    quoted = true;
+                    loop {
+                        match chars.next()? {
+                            '"' => break,
+                            // 双引号中的反斜杠只转义这几个字符
+                            '\\' => match chars.next()? {
+                                c @ ('$' | '`' | '"' | '\\') => arg.push(c),
+                                '\n' => {}
+                                c => {
+                                    arg.push('\\');
+                                    arg.push(c);
+                                }
+                            },
+                            c => arg.push(c),
+                        }
+                    }
+                }
+                '\\' => match chars.next()? {
+                    '\n' => {}
+                    c => arg.push(c),
+                },
+                c => arg.push(c),
+            }
+        }
+        args.push((arg, quoted));
+    }
+}
+
+/// 把文本解析成 Value：整数、浮点数和 true/false 解析成对应的类型，
+/// JSON 的数组和对象解析成列表和 map，其它的都是字符串
+fn parse_value(s: &str) -> Value {
+    if s.starts_with(['[', '{']) {
+        if let Ok(v) = serde_json::from_str::<serde_json::Value>(s) {
+            return v.into();
+        }
+    }
+    if let Ok(v) = s.parse::<i64>() {
+        return v.into();
+    }
+    // 避免把 inf/nan 这样的字符串当成浮点数
+    if s.bytes().any(|c| c.is_ascii_digit()) {
+        if let Ok(v) = s.parse::<f64>() {
+            return v.into();
+        }
+    }
+    match s {
+        "true" => true.into(),
+        "false" => false.into(),
+        _ => s.into(),
+    }
+}
+
+fn parse_number<T: FromStr>(s: &str) -> Result<T, KvError> {
+    s.parse()
+        .map_err(|_| KvError::ConvertError(s.into(), std::any::type_name::<T>()))
+}
+
+fn to_strings(v: &[Arg]) -> Vec<String> {
+    v.iter().map(|v| v.to_string()).collect()
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            None => write!(f, "(nil)"),
+            Some(value::Value::String(v)) => write!(f, "{:?}", v),
+            Some(value::Value::Binary(v)) => {
+                write!(f, "(binary) 0x")?;
+                v.iter().try_for_each(|c| write!(f, "{:02x}", c))
+            }
+            Some(value::Value::Integer(v)) => write!(f, "(integer) {}", v),
+            Some(value::Value::Float(v)) => write!(f, "(float) {}", v),
+            Some(value::Value::Bool(v)) => write!(f, "(bool) {}", v),
+            Some(value::Value::List(v)) => {
+                let items: Vec<_> = v.values.iter().map(|v| v.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Some(value::Value::Map(v)) => {
+                let items: Vec<_> = v
+                    .entries
+                    .iter()
+                    .map(|(k, v)| format!("{:?}: {}", k, v))
+                    .collect();
+                write!(f, "{{{}}}", items.join(", "))
+            }
+        }
+    }
+}
+
+/// 和 redis-cli 类似的格式：错误以 (error) 开头，多个结果按序号逐行列出
+impl fmt::Display for CommandResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 持久化主题的消息带上序号，重新订阅时可以从这个序号继续
+        if self.seq > 0 {
+            write!(f, "(seq {}) ", self.seq)?;
+        }
+        // pattern 订阅收到的消息带上主题的名字
+        if !self.topic.is_empty() {
+            write!(f, "({}) ", self.topic)?;
+        }
+        // 订阅者太慢时丢弃了之前的消息
+        if self.lost > 0 {
+            write!(f, "(lost {}) ", self.lost)?;
+        }
+        write!(f, "{}", response_lines(self).join("\n"))
+    }
+}
+
+fn response_lines(res: &CommandResponse) -> Vec<String> {
+    if !(200..300).contains(&res.status) {
+        return vec![format!("(error) {} {}", res.status, res.message)];
+    }
+
+    if !res.responses.is_empty() {
+        numbered(res.responses.iter().map(response_lines))
+    } else if !res.pairs.is_empty() {
+        numbered(res.pairs.iter().map(|pair| {
+            let value = pair.value.clone().unwrap_or_default();
+            vec![format!("{} => {}", pair.key, value)]
+        }))
+    } else {
+        match res.values.as_slice() {
+            [] => vec!["OK".into()],
+            [value] => vec![value.to_string()],
+            values => numbered(values.iter().map(|v| vec![v.to_string()])),
+        }
+    }
+}
+
+/// 给每一项加上序号，一项有多行时，后面的行和第一行对齐
+fn numbered(items: impl Iterator<Item = Vec<String>>) -> Vec<String> {
+    let mut result = Vec::new();
+    for (i, lines) in items.enumerate() {
+        let prefix = format!("{}) ", i + 1);
+        for (j, line) in lines.into_iter().enumerate() {
+            match j {
+                0 => result.push(format!("{}{}", prefix, line)),
+                _ => result.push(format!("{}{}", " ".repeat(prefix.len()), line)),
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_request::RequestData;
+
+    #[test]
+    fn text_commands_should_be_parsed() {
+        let cmd: CommandRequest = "hset t1 k1 v1".parse().unwrap();
+        assert_eq!(cmd, CommandRequest::new_hset("t1", "k1", "v1".into()));
+
+        // 命令不区分大小写，引号里的空格不会拆分参数
+        let cmd: CommandRequest = r#"HSET t1 k1 "hello world""#.parse().unwrap();
+        assert_eq!(
+            cmd,
+            CommandRequest::new_hset("t1", "k1", "hello world".into())
+        );
+
+        let cmd: CommandRequest = "hmset t1 a 1 b 1.5 c true".parse().unwrap();
+        let pairs = vec![
+            Kvpair::new("a", 1.into()),
+            Kvpair::new("b", 1.5.into()),
+            Kvpair::new("c", true.into()),
+        ];
+        assert_eq!(cmd, CommandRequest::new_hmset("t1", pairs));
+
+        let cmd: CommandRequest = "hgetall t1 100".parse().unwrap();
+        assert_eq!(cmd, CommandRequest::new_hgetall_chunked("t1", 100));
+
+        let cmd: CommandRequest = "hcas t1 k1 nil v1".parse().unwrap();
+        assert_eq!(cmd, CommandRequest::new_hcas("t1", "k1", None, "v1".into()));
+
+        let cmd: CommandRequest = "hexpire t1 k1 1500".parse().unwrap();
+        let expected = CommandRequest::new_hexpire("t1", "k1", Duration::from_millis(1500));
+        assert_eq!(cmd, expected);
+
+        let cmd: CommandRequest = "subscribe lobby".parse().unwrap();
+        assert_eq!(cmd, CommandRequest::new_subscribe("lobby"));
+        let cmd: CommandRequest = "subscribe orders FROM 42".parse().unwrap();
+        assert_eq!(cmd, CommandRequest::new_subscribe_from("orders", 42));
+        let cmd: CommandRequest = "subscribe orders last 10".parse().unwrap();
+        assert_eq!(cmd, CommandRequest::new_subscribe_last("orders", 10));
+        let cmd: CommandRequest = "PSUBSCRIBE orders.*.created".parse().unwrap();
+        assert_eq!(cmd, CommandRequest::new_psubscribe("orders.*.created"));
+
+        let cmd: CommandRequest = "hello zstd lz4".parse().unwrap();
+        assert_eq!(cmd, CommandRequest::new_hello(&[Codec::Zstd, Codec::Lz4]));
+
+        let cmd: CommandRequest = "info".parse().unwrap();
+        assert!(matches!(
+            cmd.request_data,
+            Some(RequestData::ReplicationInfo(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_text_commands_should_fail() {
+        let err = "hset t1 k1".parse::<CommandRequest>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Command is invalid: `Usage: hset <table> <key> <value>`"
+        );
+
+        let err = "hmset t1 k1".parse::<CommandRequest>().unwrap_err();
+        assert!(err.to_string().contains("Usage: hmset"));
+
+        let err = "greet world".parse::<CommandRequest>().unwrap_err();
+        assert!(err.to_string().contains("Unknown command: greet"));
+
+        // hello 的参数必须是支持的压缩算法
+        let err = "hello world".parse::<CommandRequest>().unwrap_err();
+        assert!(matches!(err, KvError::ConvertError(..)));
+
+        let err = "hincrby t1 k1 abc".parse::<CommandRequest>().unwrap_err();
+        assert!(matches!(err, KvError::ConvertError(..)));
+
+        assert!("hset t1 'k1 v1".parse::<CommandRequest>().is_err());
+        assert!("".parse::<CommandRequest>().is_err());
+    }
+
+    #[test]
+    fn values_should_be_parsed_by_type() {
+        assert_eq!(parse_value("42"), 42.into());
+        assert_eq!(parse_value("-0.5"), (-0.5).into());
+        assert_eq!(parse_value("false"), false.into());
+        assert_eq!(parse_value("nan"), "nan".into());
+        assert_eq!(parse_value("hello"), "hello".into());
+        let list = Value::from(vec![Value::from(1), "a".into()]);
+        assert_eq!(parse_value(r#"[1, "a"]"#), list);
+        assert_eq!(parse_value("[1, 2"), "[1, 2".into());
+
+        // 用了引号的参数总是字符串
+        let cmd: CommandRequest = r#"hmset t1 zip '01234' n "42" b 'true' m 42"#.parse().unwrap();
+        let pairs = vec![
+            Kvpair::new("zip", "01234".into()),
+            Kvpair::new("n", "42".into()),
+            Kvpair::new("b", "true".into()),
+            Kvpair::new("m", 42.into()),
+        ];
+        assert_eq!(cmd, CommandRequest::new_hmset("t1", pairs));
+
+        let cmd: CommandRequest = "hcas t1 k1 'nil' x\\ y".parse().unwrap();
+        let expected = CommandRequest::new_hcas("t1", "k1", Some("nil".into()), "x y".into());
+        assert_eq!(cmd, expected);
+    }
+
+    #[test]
+    fn args_should_be_split_like_shell() {
+        let args = split_args(r#" a 'b c'  "d \"e\" \n" f\ g h"i"j "#).unwrap();
+        let expected = [
+            ("a", false),
+            ("b c", true),
+            ("d \"e\" \\n", true),
+            ("f g", false),
+            ("hij", true),
+        ];
+        let args: Vec<_> = args.iter().map(|(a, q)| (a.as_str(), *q)).collect();
+        assert_eq!(args, expected);
+        assert!(split_args("a 'b").is_none());
+        assert!(split_args("a \"b\\\"").is_none());
+    }
+
+    #[test]
+    fn responses_should_be_formatted() {
+        let res: CommandResponse = Value::from("v1").into();
+        assert_eq!(res.to_string(), r#""v1""#);
+
+        let res: CommandResponse = Value::default().into();
+        assert_eq!(res.to_string(), "(nil)");
+
+        let res: CommandResponse = vec![1.into(), Value::from(b"\x01\xff")].into();
+        assert_eq!(res.to_string(), "1) (integer) 1\n2) (binary) 0x01ff");
+
+        let res: CommandResponse = vec![Kvpair::new("k1", true.into())].into();
+        assert_eq!(res.to_string(), "1) k1 => (bool) true");
+
+        let res: CommandResponse = Value::from(serde_json::json!({"a": [1, "b"]})).into();
+        assert_eq!(res.to_string(), r#"{"a": [(integer) 1, "b"]}"#);
+
+        let mut res: CommandResponse = Value::from("v1").into();
+        res.seq = 7;
+        assert_eq!(res.to_string(), r#"(seq 7) "v1""#);
+        res.lost = 3;
+        assert_eq!(res.to_string(), r#"(seq 7) (lost 3) "v1""#);
+        res.topic = "news.tech".into();
+        assert_eq!(res.to_string(), r#"(seq 7) (news.tech) (lost 3) "v1""#);
+
+        let res: CommandResponse = KvError::NotFound("t1:k1".into()).into();
+        assert_eq!(res.to_string(), "(error) 404 Not found: t1:k1");
+
+        let multi = vec![
+            CommandResponse::from(Value::from(1.5)),
+            CommandResponse::from(vec![Value::from("a"), Value::from("b")]),
+        ];
+        let res = CommandResponse::from(multi);
+        assert_eq!(res.to_string(), "1) (float) 1.5\n2) 1) \"a\"\n   2) \"b\"");
+    }
+}
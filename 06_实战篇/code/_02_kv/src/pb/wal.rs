@@ -0,0 +1,102 @@
+/// WalMemTable 的 WAL 记录, 每条记录对应 storage 的一次修改
+/// snapshot 也使用同样的记录（Set/ExpireAt）来保存所有数据, 主从复制时也用它来传输修改
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WalRecord {
+    #[prost(oneof="wal_record::Op", tags="1, 2, 3, 4, 5, 6")]
+    pub op: ::core::option::Option<wal_record::Op>,
+}
+/// Nested message and enum types in `WalRecord`.
+pub mod wal_record {
+    #[derive(PartialOrd)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Op {
+        #[prost(message, tag="1")]
+        Set(super::Set),
+        #[prost(message, tag="2")]
+        Del(super::Del),
+        #[prost(message, tag="3")]
+        Update(super::Update),
+        #[prost(message, tag="4")]
+        ExpireAt(super::ExpireAt),
+        #[prost(message, tag="5")]
+        Persist(super::Persist),
+        #[prost(message, tag="6")]
+        Batch(super::Batch),
+    }
+}
+/// 设置 key 的 value, 同时清除过期时间
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Set {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<super::abi::Value>,
+}
+/// 删除 key
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Del {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 更新 key 的 value, 过期时间保持不变
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Update {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<super::abi::Value>,
+}
+/// 设置 key 的过期时刻（UNIX 时间戳, 毫秒）
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ExpireAt {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub at: u64,
+}
+/// 清除 key 的过期时间
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Persist {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 一个事务里的所有修改, 回放时整体生效
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Batch {
+    #[prost(message, repeated, tag="1")]
+    pub records: ::prost::alloc::vec::Vec<WalRecord>,
+}
+/// 主节点发给从节点的复制数据
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReplicationFrame {
+    /// 主节点上已经产生的修改的序号
+    #[prost(uint64, tag="1")]
+    pub seq: u64,
+    /// 主节点发送时的时刻（UNIX 时间戳, 毫秒）, 从节点用它来计算复制延迟
+    #[prost(uint64, tag="2")]
+    pub timestamp: u64,
+    /// 为 true 表示这是全量同步（snapshot）的数据, 之后收到的都是增量的修改
+    #[prost(bool, tag="3")]
+    pub snapshot: bool,
+    /// 没有修改时, 主节点会定时发送不包含记录的心跳
+    #[prost(message, repeated, tag="4")]
+    pub records: ::prost::alloc::vec::Vec<WalRecord>,
+}
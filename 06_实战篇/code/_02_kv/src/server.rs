@@ -1,50 +1,58 @@
-// use anyhow::Result;
-// use kv::{MemTable, ProstServerStream, Service, ServiceInner, TlsServerAcceptor, YamuxCtrl};
-// use tokio::net::TcpListener;
-// use tokio_util::compat::FuturesAsyncReadCompatExt;
-// use tracing::info;
-
-// #[tokio::main]
-// async fn main() -> Result<()> {
-//     tracing_subscriber::fmt::init();
-//     let addr = "127.0.0.1:9527";
-
-//     // 以后从配置文件取
-//     let server_cert = include_str!("../fixtures/server.cert");
-//     let server_key = include_str!("../fixtures/server.key");
-
-//     let acceptor = TlsServerAcceptor::new(server_cert, server_key, None)?;
-
-//     let service: Service = ServiceInner::new(MemTable::new()).into();
-//     let listener = TcpListener::bind(addr).await?;
-//     info!("Start listening on {}", addr);
-//     loop {
-//         let tls = acceptor.clone();
-//         let (stream, addr) = listener.accept().await?;
-//         info!("Client {:?} connected", addr);
-
-//         let svc = service.clone();
-//         tokio::spawn(async move {
-//             let stream = tls.accept(stream).await.unwrap();
-//             YamuxCtrl::new_server(stream, None, move |stream| {
-//                 let svc1 = svc.clone();
-//                 async move {
-//                     let stream = ProstServerStream::new(stream.compat(), svc1.clone());
-//                     stream.process().await.unwrap();
-//                     Ok(())
-//                 }
-//             });
-//         });
-//     }
-// }
+use std::process;
 
 use anyhow::Result;
-use kv::{start_server_with_config, ServerConfig};
+use clap::Parser;
+use kv::{start_server_with_config, ServerConfig, StorageConfig};
+use tracing::{error, info};
+
+/// KV Server，命令行参数优先于环境变量，环境变量优先于配置文件
+#[derive(Debug, Parser)]
+#[command(name = "kvs", version)]
+struct Args {
+    /// 服务器配置文件
+    #[arg(
+        short,
+        long,
+        env = "KVS_CONFIG",
+        default_value = "fixtures/server.conf"
+    )]
+    config: String,
+    /// 覆盖配置文件中的监听地址（general.addr 或者唯一的 TLS/TCP listener）
+    #[arg(long, env = "KVS_ADDR")]
+    addr: Option<String>,
+    /// 覆盖配置文件中的存储：memtable、sled:<path> 或者 wal:<path>
+    #[arg(long, env = "KVS_STORAGE")]
+    storage: Option<StorageConfig>,
+    /// 只检查配置（TLS 证书和私钥、存储路径等）是否可用，不启动服务
+    #[arg(long)]
+    check_config: bool,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let config: ServerConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    let args = Args::parse();
+
+    let mut config = ServerConfig::load(&args.config)?;
+    if let Some(addr) = args.addr {
+        if let Err(e) = config.override_addr(addr) {
+            error!("Config {} is invalid: {}", args.config, e);
+            process::exit(1);
+        }
+    }
+    if let Some(storage) = args.storage {
+        config.storage = storage;
+    }
+
+    if let Err(e) = config.validate() {
+        error!("Config {} is invalid: {}", args.config, e);
+        process::exit(1);
+    }
+    if args.check_config {
+        info!("Config {} is OK", args.config);
+        return Ok(());
+    }
+
     start_server_with_config(&config).await?;
     Ok(())
 }
@@ -0,0 +1,324 @@
+use std::sync::{Arc, Mutex, RwLock};
+
+use tracing::info;
+
+use super::{limit::TokenBucket, Service};
+use crate::{
+    command_request::RequestData, AclConfig, Auth, Codec, CommandRequest, CommandResponse, Hello,
+    KvError, Permission, Storage, Value,
+};
+
+/// 没有认证的连接使用的 principal
+pub const ANONYMOUS: &str = "anonymous";
+
+/// 一个客户端连接的会话，同一个连接上的所有 stream 共享同一个 principal
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    principal: Arc<RwLock<Option<String>>>,
+    /// 连接的请求速率限制，第一次使用时创建
+    pub(super) bucket: Arc<Mutex<Option<TokenBucket>>>,
+    /// 客户端通过 HELLO 告诉服务器的能解压的压缩算法，None 表示还没有协商
+    codecs: Arc<RwLock<Option<Vec<Codec>>>>,
+}
+
+impl Session {
+    /// 用客户端证书里的 subject 等已知的 principal 创建会话，None 表示还没有认证
+    pub fn new(principal: Option<String>) -> Self {
+        Self {
+            principal: Arc::new(RwLock::new(principal)),
+            bucket: Default::default(),
+            codecs: Default::default(),
+        }
+    }
+
+    /// 当前的 principal，没有认证时是 anonymous
+    pub fn principal(&self) -> String {
+        let principal = self.principal.read().unwrap_or_else(|e| e.into_inner());
+        principal.clone().unwrap_or_else(|| ANONYMOUS.into())
+    }
+
+    fn set_principal(&self, principal: String) {
+        let mut guard = self.principal.write().unwrap_or_else(|e| e.into_inner());
+        *guard = Some(principal);
+    }
+
+    /// 客户端能解压的压缩算法，没有协商过时（比如旧版本的客户端）只支持 gzip
+    pub fn peer_codecs(&self) -> Vec<Codec> {
+        let codecs = self.codecs.read().unwrap_or_else(|e| e.into_inner());
+        codecs.clone().unwrap_or_else(|| Codec::LEGACY.to_vec())
+    }
+
+    fn set_peer_codecs(&self, codecs: Vec<Codec>) {
+        let mut guard = self.codecs.write().unwrap_or_else(|e| e.into_inner());
+        *guard = Some(codecs);
+    }
+}
+
+/// 命令访问的资源
+#[derive(Debug, Clone, Copy)]
+enum Resource<'a> {
+    Table(&'a str),
+    Topic(&'a str),
+}
+
+impl AclConfig {
+    /// 检查 principal 是否有权限执行 cmd，Multi 中的每个命令都要有权限
+    pub(crate) fn check(&self, principal: &str, cmd: &CommandRequest) -> Result<(), KvError> {
+        let mut required = Vec::new();
+        required_permissions(cmd, &mut required);
+
+        for (resource, permission) in required {
+            if !self.is_allowed(principal, resource, permission) {
+                let (kind, name) = match resource {
+                    Resource::Table(name) => ("table", name),
+                    Resource::Topic(name) => ("topic", name),
+                };
+                return Err(KvError::PermissionDenied(format!(
+                    "{} has no {:?} permission on {} {}",
+                    principal, permission, kind, name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 找到 token 对应的 principal
+    pub(crate) fn authenticate(&self, token: &str) -> Result<&str, KvError> {
+        self.tokens
+            .get(token)
+            .map(|v| v.as_str())
+            .ok_or_else(|| KvError::Unauthorized("Invalid token".into()))
+    }
+
+    fn is_allowed(&self, principal: &str, resource: Resource, permission: Permission) -> bool {
+        let Some(grants) = self.principals.get(principal) else {
+            return false;
+        };
+        let (grants, name) = match resource {
+            Resource::Table(name) => (&grants.tables, name),
+            Resource::Topic(name) => (&grants.topics, name),
+        };
+
+        // 具体名字的权限和 "*" 的权限合在一起
+        [name, "*"]
+            .iter()
+            .filter_map(|name| grants.get(*name))
+            .any(|v| v.contains(&permission))
+    }
+}
+
+/// 执行 cmd 需要的权限
+fn required_permissions<'a>(cmd: &'a CommandRequest, result: &mut Vec<(Resource<'a>, Permission)>) {
+    let table = |name: &'a str, permission| (Resource::Table(name), permission);
+    let topic = |name: &'a str, permission| (Resource::Topic(name), permission);
+
+    let required = match &cmd.request_data {
+        Some(RequestData::Multi(v)) => {
+            v.commands
+                .iter()
+                .for_each(|cmd| required_permissions(cmd, result));
+            return;
+        }
+        Some(RequestData::Hget(v)) => table(&v.table, Permission::Read),
+        Some(RequestData::Hgetall(v)) => table(&v.table, Permission::Read),
+        Some(RequestData::Hmget(v)) => table(&v.table, Permission::Read),
+        Some(RequestData::Hexist(v)) => table(&v.table, Permission::Read),
+        Some(RequestData::Hmexist(v)) => table(&v.table, Permission::Read),
+        Some(RequestData::Httl(v)) => table(&v.table, Permission::Read),
+        Some(RequestData::Hscan(v)) => table(&v.table, Permission::Read),
+        Some(RequestData::Hprefix(v)) => table(&v.table, Permission::Read),
+        Some(RequestData::Hset(v)) => table(&v.table, Permission::Write),
+        Some(RequestData::Hmset(v)) => table(&v.table, Permission::Write),
+        Some(RequestData::Hdel(v)) => table(&v.table, Permission::Write),
+        Some(RequestData::Hmdel(v)) => table(&v.table, Permission::Write),
+        Some(RequestData::Hexpire(v)) => table(&v.table, Permission::Write),
+        Some(RequestData::Hpersist(v)) => table(&v.table, Permission::Write),
+        Some(RequestData::Hincrby(v)) => table(&v.table, Permission::Write),
+        Some(RequestData::Hincrbyfloat(v)) => table(&v.table, Permission::Write),
+        Some(RequestData::Hcas(v)) => table(&v.table, Permission::Write),
+        Some(RequestData::Hsetnx(v)) => table(&v.table, Permission::Write),
+        Some(RequestData::Publish(v)) => topic(&v.topic, Permission::Publish),
+        Some(RequestData::Subscribe(v)) => topic(&v.topic, Permission::Subscribe),
+        Some(RequestData::Unsubscribe(v)) => topic(&v.topic, Permission::Subscribe),
+        // pattern 订阅需要对 pattern 本身（或者 "*"）有订阅权限
+        Some(RequestData::Psubscribe(v)) => topic(&v.pattern, Permission::Subscribe),
+        Some(RequestData::Punsubscribe(v)) => topic(&v.pattern, Permission::Subscribe),
+        // 复制会读取所有 table 的数据，需要对 "*" 有读权限
+        Some(RequestData::Replicate(_)) => table("*", Permission::Read),
+        Some(RequestData::ReplicationInfo(_) | RequestData::Auth(_) | RequestData::Hello(_))
+        | None => return,
+    };
+    result.push(required);
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 检查会话的 principal 是否有权限执行 cmd，没有配置 ACL 时允许所有的命令
+    pub(super) fn check_permission(
+        &self,
+        cmd: &CommandRequest,
+        session: &Session,
+    ) -> Result<(), KvError> {
+        match &self.inner.acl {
+            Some(acl) => acl.check(&session.principal(), cmd),
+            None => Ok(()),
+        }
+    }
+
+    /// 用 token 认证会话，成功后返回 principal
+    pub(super) fn authenticate(&self, param: &Auth, session: &Session) -> CommandResponse {
+        let Some(acl) = &self.inner.acl else {
+            return KvError::InvalidCommand("Authentication is not enabled".into()).into();
+        };
+
+        match acl.authenticate(&param.token) {
+            Ok(principal) => {
+                info!("Session is authenticated as {}", principal);
+                session.set_principal(principal.to_string());
+                Value::from(principal).into()
+            }
+            Err(e) => e.into(),
+        }
+    }
+
+    /// 记录客户端能解压的压缩算法，返回服务器能解压的压缩算法
+    /// 不认识的算法（比如更新版本的客户端支持的）会被忽略
+    pub(super) fn hello(&self, param: &Hello, session: &Session) -> CommandResponse {
+        let codecs = param.codecs.iter().filter_map(|v| v.parse().ok()).collect();
+        session.set_peer_codecs(codecs);
+        let values: Vec<Value> = Codec::ALL.iter().map(|v| v.as_str().into()).collect();
+        values.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok, CompressionConfig, MemTable, ServiceInner};
+    use futures::StreamExt;
+
+    fn acl() -> AclConfig {
+        toml::from_str(include_str!("../../fixtures/acl.conf")).unwrap()
+    }
+
+    async fn execute(service: &Service, cmd: CommandRequest, session: &Session) -> CommandResponse {
+        let res = service.execute_with_session(cmd, session);
+        res.collect::<Vec<_>>().await.remove(0).as_ref().clone()
+    }
+
+    #[test]
+    fn acl_should_check_table_and_topic_permissions() {
+        let acl = acl();
+
+        let cmd = CommandRequest::new_hset("device", "k1", "v1".into());
+        assert!(acl.check("awesome-device-id", &cmd).is_ok());
+        assert!(acl.check("reader", &cmd).is_err());
+        assert!(acl.check(ANONYMOUS, &cmd).is_err());
+        // "*" 匹配所有的 table
+        assert!(acl.check("admin", &cmd).is_ok());
+
+        let cmd = CommandRequest::new_hget("public", "k1");
+        assert!(acl.check(ANONYMOUS, &cmd).is_ok());
+        assert!(acl.check("awesome-device-id", &cmd).is_err());
+        // 不在 ACL 中的 principal 没有任何权限
+        assert!(acl.check("unknown", &cmd).is_err());
+
+        assert!(acl
+            .check("reader", &CommandRequest::new_subscribe("news"))
+            .is_ok());
+        assert!(acl
+            .check("reader", &CommandRequest::new_publish("news", vec![]))
+            .is_err());
+        assert!(acl
+            .check(ANONYMOUS, &CommandRequest::new_replication_info())
+            .is_ok());
+        assert!(acl.check("admin", &CommandRequest::new_replicate()).is_ok());
+        assert!(acl
+            .check("reader", &CommandRequest::new_replicate())
+            .is_ok());
+        let cmd = CommandRequest::new_replicate();
+        assert!(acl.check("awesome-device-id", &cmd).is_err());
+    }
+
+    #[test]
+    fn acl_should_check_every_command_in_multi() {
+        let acl = acl();
+        let cmd = CommandRequest::new_multi(vec![
+            CommandRequest::new_hget("device", "k1"),
+            CommandRequest::new_hset("public", "k1", "v1".into()),
+        ]);
+        let err = acl.check("awesome-device-id", &cmd).unwrap_err();
+        assert!(err.to_string().contains("table public"));
+    }
+
+    #[tokio::test]
+    async fn service_should_enforce_acl() {
+        let service: Service = ServiceInner::new(MemTable::new()).acl(Some(acl())).into();
+
+        // 客户端证书的 subject 作为 principal
+        let session = Session::new(Some("awesome-device-id".into()));
+        let cmd = CommandRequest::new_hset("device", "k1", "v1".into());
+        let res = execute(&service, cmd, &session).await;
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = execute(&service, cmd, &session).await;
+        assert_res_error(&res, 403, "Permission denied");
+
+        // 没有 principal 的调用者是 anonymous
+        let res = service.execute(CommandRequest::new_hget("device", "k1"));
+        let res = res.collect::<Vec<_>>().await.remove(0);
+        assert_res_error(&res, 403, "anonymous");
+    }
+
+    #[tokio::test]
+    async fn auth_should_set_session_principal() {
+        let service: Service = ServiceInner::new(MemTable::new()).acl(Some(acl())).into();
+        let session = Session::default();
+
+        let cmd = CommandRequest::new_auth("bad-token");
+        let res = execute(&service, cmd, &session).await;
+        assert_res_error(&res, 401, "Invalid token");
+        assert_eq!(session.principal(), ANONYMOUS);
+
+        let cmd = CommandRequest::new_auth("admin-secret-token");
+        let res = execute(&service, cmd, &session).await;
+        assert_res_ok(&res, &["admin".into()], &[]);
+
+        // 同一个会话上的其它 stream 也使用认证后的 principal
+        let cloned = session.clone();
+        assert_eq!(cloned.principal(), "admin");
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = execute(&service, cmd, &cloned).await;
+        assert_res_ok(&res, &[Value::default()], &[]);
+    }
+
+    #[tokio::test]
+    async fn hello_should_negotiate_compression() {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .compression(CompressionConfig {
+                codec: Codec::Zstd,
+                threshold: 0,
+            })
+            .into();
+        let session = Session::default();
+
+        // 没有 HELLO 的客户端只能使用 gzip
+        assert_eq!(service.compression(&session).codec, Codec::Gzip);
+
+        let cmd = CommandRequest::new_hello(&[Codec::Lz4, Codec::Zstd]);
+        let res = execute(&service, cmd, &session).await;
+        let codecs: Vec<Value> = Codec::ALL.iter().map(|v| v.as_str().into()).collect();
+        assert_res_ok(&res, &codecs, &[]);
+        assert_eq!(session.peer_codecs(), [Codec::Lz4, Codec::Zstd]);
+        assert_eq!(service.compression(&session).codec, Codec::Zstd);
+    }
+
+    #[tokio::test]
+    async fn auth_without_acl_should_fail() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let cmd = CommandRequest::new_auth("admin-secret-token");
+        let res = execute(&service, cmd, &Session::default()).await;
+        assert_res_error(&res, 400, "not enabled");
+    }
+}
@@ -1,4 +1,8 @@
-use crate::*;
+use std::{iter, time::Duration};
+
+use http::StatusCode;
+
+use crate::{command_request::RequestData, *};
 
 impl CommandService for Hget {
     fn execute(self, store: &impl Storage) -> CommandResponse {
@@ -32,6 +36,49 @@ impl CommandService for Hgetall {
     }
 }
 
+impl Hgetall {
+    /// 用 get_iter 遍历 table，每 chunk_size 个 kv pair 生成一个 CommandResponse
+    /// table 为空时也会返回一个不包含 kv pair 的 CommandResponse
+    pub fn execute_chunked(
+        self,
+        store: &impl Storage,
+    ) -> Box<dyn Iterator<Item = CommandResponse> + Send> {
+        let mut data = match store.get_iter(&self.table) {
+            Ok(v) => v,
+            Err(e) => return Box::new(iter::once(e.into())),
+        };
+
+        let chunk_size = self.chunk_size.max(1) as usize;
+        let mut first = true;
+        Box::new(iter::from_fn(move || {
+            let pairs: Vec<Kvpair> = data.by_ref().take(chunk_size).collect();
+            if pairs.is_empty() && !first {
+                return None;
+            }
+            first = false;
+            Some(pairs.into())
+        }))
+    }
+}
+
+impl CommandService for Hscan {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.scan(&self.table, "", &self.cursor, self.limit as usize) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hprefix {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.scan(&self.table, &self.prefix, "", 0) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
 impl CommandService for Hset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         match self.pair {
@@ -108,6 +155,248 @@ impl CommandService for Hmexist {
     }
 }
 
+impl CommandService for Hexpire {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let ttl = Duration::from_millis(self.ttl);
+        match store.expire(&self.table, &self.key, ttl) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Httl {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.contains(&self.table, &self.key) {
+            Ok(true) => {}
+            Ok(false) => {
+                return KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into()
+            }
+            Err(e) => return e.into(),
+        }
+
+        // 没有设置过期时间的 key 返回 -1
+        match store.ttl(&self.table, &self.key) {
+            Ok(Some(ttl)) => Value::from(i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX)).into(),
+            Ok(None) => Value::from(-1).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hpersist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.persist(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrby {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let result = store.update(&self.table, &self.key, |v| {
+            let i: i64 = match v {
+                Some(v) => v.try_into()?,
+                None => 0,
+            };
+            match i.checked_add(self.delta) {
+                Some(i) => Ok(i.into()),
+                None => Err(KvError::InvalidCommand(format!(
+                    "increment {} on {} would overflow",
+                    self.delta, i
+                ))),
+            }
+        });
+
+        match result {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrbyfloat {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let result = store.update(&self.table, &self.key, |v| {
+            // 整数也可以按浮点数来累加，结果存成浮点数
+            let f: f64 = match v {
+                Some(v) => match v.try_into() {
+                    Ok(f) => f,
+                    Err(_) => i64::try_from(v)? as f64,
+                },
+                None => 0.0,
+            };
+            match f + self.delta {
+                f if f.is_finite() => Ok(f.into()),
+                _ => Err(KvError::InvalidCommand(format!(
+                    "increment {} on {} would produce NaN or Infinity",
+                    self.delta, f
+                ))),
+            }
+        });
+
+        match result {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hcas {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = match self.value {
+            Some(v) => v,
+            None => return KvError::InvalidCommand(format!("{:?}", self)).into(),
+        };
+
+        let expected = self.expected;
+        let result = store.update(&self.table, &self.key, |v| match (v, &expected) {
+            (None, None) => Ok(value.clone()),
+            (Some(v), Some(expected)) if v == expected => Ok(value.clone()),
+            (v, _) => Err(KvError::Conflict(format!(
+                "table {}, key {}, current value {:?}",
+                self.table, self.key, v
+            ))),
+        });
+
+        // 成功时返回之前的值，和 Hset 保持一致
+        match result {
+            Ok(_) => expected.unwrap_or_default().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hsetnx {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let pair = match self.pair {
+            Some(v) => v,
+            None => return KvError::InvalidCommand(format!("{:?}", self)).into(),
+        };
+
+        let value = pair.value.unwrap_or_default();
+        let result = store.update(&self.table, &pair.key, |v| match v {
+            None => Ok(value.clone()),
+            Some(_) => Err(KvError::Conflict(format!(
+                "table {}, key {} already exists",
+                self.table, pair.key
+            ))),
+        });
+
+        match result {
+            Ok(_) => Value::default().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Multi {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut keys = Vec::new();
+        for cmd in self.commands.iter() {
+            match transaction_keys(cmd) {
+                Ok(v) => keys.extend(v),
+                Err(e) => return e.into(),
+            }
+        }
+        keys.sort();
+        keys.dedup();
+
+        // 把事务涉及的 key 加载到一个临时的 MemTable 里执行所有命令，
+        // 再把执行后的结果交给 storage 一次性写入
+        let mut aborted = Vec::new();
+        let result = store.transaction(&keys, |values| {
+            let scratch = MemTable::new();
+            for ((table, key), v) in keys.iter().zip(values.iter()) {
+                if let Some(value) = &v.value {
+                    scratch.set(table, key.clone(), value.clone())?;
+                }
+                // 带上过期时间，这样命令对过期时间的影响和在事务之外执行时一样
+                if let Some(at) = v.expire_at {
+                    scratch.expire_at(table, key, at);
+                }
+            }
+
+            let mut responses = Vec::with_capacity(self.commands.len());
+            for (i, cmd) in self.commands.iter().enumerate() {
+                let res = dispatch(cmd.clone(), &scratch);
+                // 读不到 key（404）不算出错，不影响事务提交
+                let failed = !StatusCode::from_u16(res.status as _)
+                    .map(|s| s.is_success() || s == StatusCode::NOT_FOUND)
+                    .unwrap_or_default();
+                responses.push(res);
+                if failed {
+                    aborted = responses;
+                    return Err(KvError::TransactionAborted(i));
+                }
+            }
+
+            for ((table, key), v) in keys.iter().zip(values.iter_mut()) {
+                v.value = scratch.get(table, key)?;
+                v.expire_at = scratch.get_expire(table, key);
+            }
+            Ok(responses)
+        });
+
+        match result {
+            Ok(responses) => responses.into(),
+            Err(KvError::TransactionAborted(i)) => {
+                let last = aborted.last().cloned().unwrap_or_default();
+                CommandResponse {
+                    status: last.status,
+                    message: format!("Transaction aborted at command {}: {}", i, last.message),
+                    responses: aborted,
+                    ..Default::default()
+                }
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// 获取事务中一个命令会读写的所有 key
+fn transaction_keys(cmd: &CommandRequest) -> Result<Vec<(String, String)>, KvError> {
+    let one = |table: &str, key: &str| vec![(table.to_string(), key.to_string())];
+    let many = |table: &str, keys: &[String]| {
+        keys.iter()
+            .map(|key| (table.to_string(), key.clone()))
+            .collect()
+    };
+    let pair = |table: &str, pair: &Option<Kvpair>| {
+        pair.iter()
+            .map(|p| (table.to_string(), p.key.clone()))
+            .collect()
+    };
+
+    let keys = match &cmd.request_data {
+        Some(RequestData::Hget(v)) => one(&v.table, &v.key),
+        Some(RequestData::Hmget(v)) => many(&v.table, &v.keys),
+        Some(RequestData::Hset(v)) => pair(&v.table, &v.pair),
+        Some(RequestData::Hmset(v)) => v
+            .pairs
+            .iter()
+            .map(|p| (v.table.clone(), p.key.clone()))
+            .collect(),
+        Some(RequestData::Hdel(v)) => one(&v.table, &v.key),
+        Some(RequestData::Hmdel(v)) => many(&v.table, &v.keys),
+        Some(RequestData::Hexist(v)) => one(&v.table, &v.key),
+        Some(RequestData::Hmexist(v)) => many(&v.table, &v.keys),
+        Some(RequestData::Hincrby(v)) => one(&v.table, &v.key),
+        Some(RequestData::Hincrbyfloat(v)) => one(&v.table, &v.key),
+        Some(RequestData::Hcas(v)) => one(&v.table, &v.key),
+        Some(RequestData::Hsetnx(v)) => pair(&v.table, &v.pair),
+        _ => {
+            return Err(KvError::InvalidCommand(format!(
+                "{:?} is not supported in transaction",
+                cmd
+            )))
+        }
+    };
+
+    Ok(keys)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -166,6 +455,77 @@ mod tests {
         assert_res_ok(&res, &[], pairs);
     }
 
+    #[test]
+    fn hgetall_chunked_should_work() {
+        let store = MemTable::new();
+        let pairs: Vec<_> = (0..10).map(|i| (format!("u{}", i), i)).collect();
+        for (k, v) in pairs.iter() {
+            store.set("score", k.clone(), (*v).into()).unwrap();
+        }
+
+        let cmd = Hgetall {
+            table: "score".into(),
+            chunk_size: 4,
+        };
+        let chunks: Vec<_> = cmd.execute_chunked(&store).collect();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].pairs.len(), 4);
+        assert_eq!(chunks[2].pairs.len(), 2);
+
+        let mut result: Vec<_> = chunks.into_iter().flat_map(|res| res.pairs).collect();
+        result.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let expected: Vec<_> = pairs
+            .into_iter()
+            .map(|(k, v)| Kvpair::new(k, v.into()))
+            .collect();
+        assert_eq!(result, expected);
+
+        // 空的 table 也会返回一个 response
+        let cmd = Hgetall {
+            table: "empty".into(),
+            chunk_size: 4,
+        };
+        let chunks: Vec<_> = cmd.execute_chunked(&store).collect();
+        assert_eq!(chunks.len(), 1);
+        assert_res_ok(&chunks[0], &[], &[]);
+    }
+
+    #[test]
+    fn hscan_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("score", vec![("u3", 11), ("u1", 10), ("u2", 8)], &store);
+
+        let cmd = CommandRequest::new_hscan("score", "", 2);
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 200);
+        assert_eq!(
+            res.pairs,
+            vec![Kvpair::new("u1", 10.into()), Kvpair::new("u2", 8.into())]
+        );
+
+        let cmd = CommandRequest::new_hscan("score", "u2", 2);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("u3", 11.into())]);
+    }
+
+    #[test]
+    fn hprefix_should_work() {
+        let store = MemTable::new();
+        set_key_pairs(
+            "user",
+            vec![("tyr:age", 10), ("alice:age", 20), ("tyr:score", 8)],
+            &store,
+        );
+
+        let cmd = CommandRequest::new_hprefix("user", "tyr:");
+        let res = dispatch(cmd, &store);
+        let pairs = &[
+            Kvpair::new("tyr:age", 10.into()),
+            Kvpair::new("tyr:score", 8.into()),
+        ];
+        assert_res_ok(&res, &[], pairs);
+    }
+
     #[test]
     fn hset_should_work() {
         let store = MemTable::new();
@@ -236,6 +596,238 @@ mod tests {
         assert_res_ok(&res, &[true.into(), false.into()], &[]);
     }
 
+    #[test]
+    fn hexpire_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hexpire("t1", "u1", Duration::from_millis(10));
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        std::thread::sleep(Duration::from_millis(20));
+        let cmd = CommandRequest::new_hget("t1", "u1");
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[test]
+    fn httl_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_httl("t1", "u1");
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_error(&res, 404, "Not found");
+
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &[(-1).into()], &[]);
+
+        let expire = CommandRequest::new_hexpire("t1", "u1", Duration::from_secs(10));
+        dispatch(expire, &store);
+        let res = dispatch(cmd, &store);
+        let ttl: i64 = (&res).try_into().unwrap();
+        assert!(ttl > 0 && ttl <= 10_000);
+
+        // 客户端传来的 ttl 再大也不会溢出
+        let expire = CommandRequest::new_hexpire("t1", "u1", Duration::from_millis(u64::MAX));
+        assert_res_ok(&dispatch(expire, &store), &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_httl("t1", "u1"), &store);
+        let ttl: i64 = (&res).try_into().unwrap();
+        assert!(ttl > 10_000);
+    }
+
+    #[test]
+    fn hpersist_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let cmd = CommandRequest::new_hpersist("t1", "u1");
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+
+        let expire = CommandRequest::new_hexpire("t1", "u1", Duration::from_secs(10));
+        dispatch(expire, &store);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_httl("t1", "u1"), &store);
+        assert_res_ok(&res, &[(-1).into()], &[]);
+    }
+
+    #[test]
+    fn hincrby_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hincrby("score", "u1", 10);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[10.into()], &[]);
+
+        let cmd = CommandRequest::new_hincrby("score", "u1", -3);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[7.into()], &[]);
+
+        set_key_pairs("score", vec![("u2", i64::MAX)], &store);
+        let cmd = CommandRequest::new_hincrby("score", "u2", 1);
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 400, "overflow");
+    }
+
+    #[test]
+    fn hincrby_with_non_integer_should_return_400() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let cmd = CommandRequest::new_hincrby("t1", "u1", 1);
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 400, "Cannot convert");
+
+        let res = dispatch(CommandRequest::new_hget("t1", "u1"), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn hincrbyfloat_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hincrbyfloat("score", "u1", 1.5);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[1.5.into()], &[]);
+
+        let cmd = CommandRequest::new_hincrbyfloat("score", "u1", 0.25);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[1.75.into()], &[]);
+
+        // 整数会被转换成浮点数
+        set_key_pairs("score", vec![("u2", 10)], &store);
+        let cmd = CommandRequest::new_hincrbyfloat("score", "u2", 0.5);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[10.5.into()], &[]);
+    }
+
+    #[test]
+    fn hcas_should_work() {
+        let store = MemTable::new();
+
+        // expected 为空时，key 必须不存在
+        let cmd = CommandRequest::new_hcas("t1", "u1", None, "v1".into());
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 409, "Conflict");
+
+        let cmd = CommandRequest::new_hcas("t1", "u1", Some("v1".into()), "v2".into());
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+
+        // 当前值已经是 v2，再次 cas 会冲突，值保持不变
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 409, "Conflict");
+        let res = dispatch(CommandRequest::new_hget("t1", "u1"), &store);
+        assert_res_ok(&res, &["v2".into()], &[]);
+    }
+
+    #[test]
+    fn hsetnx_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hsetnx("t1", "u1", "v1".into());
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        let cmd = CommandRequest::new_hsetnx("t1", "u1", "v2".into());
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 409, "already exists");
+
+        let res = dispatch(CommandRequest::new_hget("t1", "u1"), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn multi_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("balance", vec![("alice", 100), ("bob", 0)], &store);
+
+        let cmd = CommandRequest::new_multi(vec![
+            CommandRequest::new_hincrby("balance", "alice", -30),
+            CommandRequest::new_hincrby("balance", "bob", 30),
+            CommandRequest::new_hmget("balance", vec!["alice".into(), "bob".into()]),
+        ]);
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 200);
+        assert_eq!(res.responses.len(), 3);
+        assert_res_ok(&res.responses[0], &[70.into()], &[]);
+        assert_res_ok(&res.responses[1], &[30.into()], &[]);
+        assert_res_ok(&res.responses[2], &[70.into(), 30.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hget("balance", "bob"), &store);
+        assert_res_ok(&res, &[30.into()], &[]);
+    }
+
+    #[test]
+    fn multi_with_failed_command_should_rollback() {
+        let store = MemTable::new();
+        set_key_pairs("balance", vec![("alice", 100), ("bob", 0)], &store);
+
+        // 第二个命令 cas 失败，第一个命令的修改也不生效
+        let cmd = CommandRequest::new_multi(vec![
+            CommandRequest::new_hincrby("balance", "alice", -30),
+            CommandRequest::new_hcas("balance", "bob", Some(10.into()), 40.into()),
+            CommandRequest::new_hdel("balance", "bob"),
+        ]);
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 409);
+        assert!(res.message.contains("Transaction aborted at command 1"));
+        assert_eq!(res.responses.len(), 2);
+
+        let cmd = CommandRequest::new_hmget("balance", vec!["alice".into(), "bob".into()]);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[100.into(), 0.into()], &[]);
+    }
+
+    #[test]
+    fn multi_with_missing_key_should_commit() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_multi(vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hget("t1", "k2"),
+            CommandRequest::new_hset("t1", "k3", "v3".into()),
+        ]);
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 200);
+        assert_res_error(&res.responses[1], 404, "Not found");
+
+        let cmd = CommandRequest::new_hmget("t1", vec!["k1".into(), "k3".into()]);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &["v1".into(), "v3".into()], &[]);
+    }
+
+    #[test]
+    fn multi_should_keep_ttl_like_single_commands() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("n", 1), ("s", 2)], &store);
+        store.expire("t1", "n", Duration::from_secs(60)).unwrap();
+        store.expire("t1", "s", Duration::from_secs(60)).unwrap();
+
+        // hincrby 和 update 一样保留过期时间，hset 和 set 一样清除过期时间
+        let cmd = CommandRequest::new_multi(vec![
+            CommandRequest::new_hincrby("t1", "n", 1),
+            CommandRequest::new_hset("t1", "s", 3.into()),
+        ]);
+        assert_eq!(dispatch(cmd, &store).status, 200);
+        assert!(store.ttl("t1", "n").unwrap().is_some());
+        assert!(store.ttl("t1", "s").unwrap().is_none());
+        assert_eq!(store.get("t1", "n").unwrap(), Some(2.into()));
+    }
+
+    #[test]
+    fn multi_with_unsupported_command_should_return_400() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_multi(vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hgetall("t1"),
+        ]);
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 400, "not supported in transaction");
+        assert!(!store.contains("t1", "k1").unwrap());
+    }
+
     fn set_key_pairs<T: Into<Value>>(table: &str, pairs: Vec<(&str, T)>, store: &impl Storage) {
         pairs
             .into_iter()
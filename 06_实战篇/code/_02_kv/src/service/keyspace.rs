@@ -0,0 +1,127 @@
+use std::sync::Arc;
+
+use super::{Service, Topic};
+use crate::{command_request::RequestData, CommandRequest, CommandResponse, Storage, Value};
+
+/// key 被修改时发布的事件
+const SET: &str = "set";
+const DEL: &str = "del";
+const EXPIRE: &str = "expire";
+
+/// key 变化通知的主题
+pub fn keyspace_topic(table: &str, key: &str) -> String {
+    format!("__keyspace@{}__:{}", table, key)
+}
+
+/// 命令执行成功后修改了哪些 key，Multi 中每个命令使用自己的 response
+fn changed_keys<'a>(
+    cmd: &'a CommandRequest,
+    res: &CommandResponse,
+    result: &mut Vec<(&'a str, &'a str, &'static str)>,
+) {
+    if !(200..300).contains(&res.status) {
+        return;
+    }
+
+    let deleted = |i: usize| res.values.get(i).is_some_and(|v| *v != Value::default());
+    match &cmd.request_data {
+        Some(RequestData::Multi(v)) => {
+            for (cmd, res) in v.commands.iter().zip(res.responses.iter()) {
+                changed_keys(cmd, res, result);
+            }
+        }
+        Some(RequestData::Hset(v)) => {
+            if let Some(pair) = &v.pair {
+                result.push((&v.table, &pair.key, SET));
+            }
+        }
+        Some(RequestData::Hsetnx(v)) => {
+            if let Some(pair) = &v.pair {
+                result.push((&v.table, &pair.key, SET));
+            }
+        }
+        Some(RequestData::Hmset(v)) => {
+            result.extend(
+                v.pairs
+                    .iter()
+                    .map(|pair| (v.table.as_str(), pair.key.as_str(), SET)),
+            );
+        }
+        Some(RequestData::Hincrby(v)) => result.push((&v.table, &v.key, SET)),
+        Some(RequestData::Hincrbyfloat(v)) => result.push((&v.table, &v.key, SET)),
+        Some(RequestData::Hcas(v)) => result.push((&v.table, &v.key, SET)),
+        // 删除不存在的 key 时返回的是空的 Value
+        Some(RequestData::Hdel(v)) if deleted(0) => result.push((&v.table, &v.key, DEL)),
+        Some(RequestData::Hmdel(v)) => {
+            for (i, key) in v.keys.iter().enumerate() {
+                if deleted(i) {
+                    result.push((&v.table, key, DEL));
+                }
+            }
+        }
+        Some(RequestData::Hexpire(v)) if res.values.first() == Some(&true.into()) => {
+            result.push((&v.table, &v.key, EXPIRE))
+        }
+        _ => {}
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 把修改了的 key 发布到 keyspace 的主题，只发布配置中打开了通知的 table
+    /// 过期后被自动清理的 key 不会发布通知
+    pub(super) fn notify_keyspace(&self, cmd: &CommandRequest, res: &CommandResponse) {
+        let config = &self.inner.keyspace;
+        if config.tables.is_empty() || !cmd.is_write() {
+            return;
+        }
+
+        let mut changed = Vec::new();
+        changed_keys(cmd, res, &mut changed);
+        for (table, key, event) in changed {
+            if config.enabled(table) {
+                let value: Value = event.into();
+                let topic = keyspace_topic(table, key);
+                Arc::clone(&self.broadcaster).publish(topic, Arc::new(value.into()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{assert_res_ok, KeyspaceConfig, Kvpair, MemTable, ServiceInner};
+
+    #[tokio::test]
+    async fn keyspace_events_should_be_published() {
+        let keyspace = KeyspaceConfig {
+            tables: vec!["users".into()],
+        };
+        let service: Service = ServiceInner::new(MemTable::new()).keyspace(keyspace).into();
+
+        let cmd = CommandRequest::new_subscribe(keyspace_topic("users", "u1"));
+        let mut stream = service.execute(cmd);
+        stream.next().await.unwrap();
+
+        let cmds = [
+            CommandRequest::new_hset("users", "u1", "alice".into()),
+            // 没有打开通知的 table
+            CommandRequest::new_hset("orders", "u1", "o1".into()),
+            CommandRequest::new_hexpire("users", "u1", std::time::Duration::from_secs(60)),
+            CommandRequest::new_multi(vec![CommandRequest::new_hdel("users", "u1")]),
+            // key 已经不存在，不会发布 del
+            CommandRequest::new_hdel("users", "u1"),
+            CommandRequest::new_hmset("users", vec![Kvpair::new("u1", "bob".into())]),
+        ];
+        for cmd in cmds {
+            service.execute(cmd).next().await.unwrap();
+        }
+
+        for event in [SET, EXPIRE, DEL, SET] {
+            let res = stream.next().await.unwrap();
+            assert_res_ok(&res, &[event.into()], &[]);
+        }
+    }
+}
@@ -0,0 +1,405 @@
+use dashmap::DashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::{Service, Session};
+use crate::{
+    command_request::RequestData, CommandRequest, KvError, LimitsConfig, RateLimit, Storage,
+};
+
+/// 最多隔多久清理一次 principal 的 token bucket
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// 缓存的 table 大小多久之后需要重新读取
+const TABLE_SIZE_TTL: Duration = Duration::from_secs(1);
+
+/// token bucket 的状态，token 在取的时候才按照经过的时间补充
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// 取一个 token，没有 token 时返回 false
+    fn try_acquire(&mut self, limit: &RateLimit) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 到 now 时 token 是否已经补满，补满的 bucket 和新建的没有区别
+    fn is_full(&self, limit: &RateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * limit.rate >= limit.burst as f64
+    }
+}
+
+/// 缓存的 table 中 key 的个数
+#[derive(Debug)]
+struct TableSize {
+    len: usize,
+    /// 上一次从 storage 读取的时刻
+    checked: Option<Instant>,
+}
+
+/// 服务器的限制，每个连接的 token bucket 放在 Session 里，随连接一起释放，
+/// principal 的 token bucket 补满之后会被定期清理
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    config: LimitsConfig,
+    principals: DashMap<String, TokenBucket>,
+    /// 上一次清理 principals 的时刻
+    swept: Mutex<Instant>,
+    /// 每个 table 中 key 的个数，避免每次写入都调用 store.len（SledDb 需要遍历整个 table）
+    table_sizes: DashMap<String, TableSize>,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new(LimitsConfig::default())
+    }
+}
+
+impl Limiter {
+    pub(crate) fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            principals: DashMap::new(),
+            swept: Mutex::new(Instant::now()),
+            table_sizes: DashMap::new(),
+        }
+    }
+
+    /// 连接和 principal 的 token bucket 都要有 token
+    fn check_rate(&self, session: &Session) -> Result<(), KvError> {
+        if let Some(limit) = &self.config.connection {
+            let mut bucket = session.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let bucket = bucket.get_or_insert_with(|| TokenBucket::new(limit));
+            if !bucket.try_acquire(limit) {
+                return Err(KvError::RateLimited("connection".into()));
+            }
+        }
+
+        if let Some(limit) = &self.config.principal {
+            self.sweep(limit);
+            let principal = session.principal();
+            let mut bucket = self
+                .principals
+                .entry(principal.clone())
+                .or_insert_with(|| TokenBucket::new(limit));
+            if !bucket.try_acquire(limit) {
+                return Err(KvError::RateLimited(format!("principal {}", principal)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 每隔 SWEEP_INTERVAL 删除已经补满的 principal token bucket，删除它们不会改变限流的结果
+    fn sweep(&self, limit: &RateLimit) {
+        let now = Instant::now();
+        {
+            let mut swept = self.swept.lock().unwrap_or_else(|e| e.into_inner());
+            if now.duration_since(*swept) < SWEEP_INTERVAL {
+                return;
+            }
+            *swept = now;
+        }
+        self.principals
+            .retain(|_, bucket| !bucket.is_full(limit, now));
+    }
+
+    /// table 的 key 个数限制，没有单独设置时使用 "*"
+    fn quota(&self, table: &str) -> Option<usize> {
+        let tables = &self.config.tables;
+        tables.get(table).or_else(|| tables.get("*")).copied()
+    }
+
+    /// 给 table 中新增的 added 个 key 预留空间，会超过 quota 时返回 false。
+    /// 缓存的大小过期或者看起来会超过 quota 时才用 store.len 重新读取，
+    /// 所以删除和过期的 key 不会导致错误的拒绝，只是在重新读取之前不会从缓存中减掉
+    fn reserve<Store: Storage>(
+        &self,
+        store: &Store,
+        table: &str,
+        added: usize,
+        quota: usize,
+    ) -> Result<bool, KvError> {
+        let now = Instant::now();
+        let mut size = self
+            .table_sizes
+            .entry(table.to_string())
+            .or_insert(TableSize {
+                len: 0,
+                checked: None,
+            });
+
+        let stale = size
+            .checked
+            .is_none_or(|at| now.duration_since(at) >= TABLE_SIZE_TTL);
+        if stale || size.len + added > quota {
+            size.len = store.len(table)?;
+            size.checked = Some(now);
+        }
+
+        if size.len + added > quota {
+            return Ok(false);
+        }
+        size.len += added;
+        Ok(true)
+    }
+}
+
+/// 可能创建新 key 的命令（包括 Multi 中的）会写入的 table 和 key
+fn written_keys<'a>(cmd: &'a CommandRequest, result: &mut BTreeMap<&'a str, BTreeSet<&'a str>>) {
+    match &cmd.request_data {
+        Some(RequestData::Multi(v)) => {
+            v.commands.iter().for_each(|cmd| written_keys(cmd, result));
+        }
+        Some(RequestData::Hset(v)) => {
+            if let Some(pair) = &v.pair {
+                result.entry(&v.table).or_default().insert(&pair.key);
+            }
+        }
+        Some(RequestData::Hsetnx(v)) => {
+            if let Some(pair) = &v.pair {
+                result.entry(&v.table).or_default().insert(&pair.key);
+            }
+        }
+        Some(RequestData::Hmset(v)) => {
+            let keys = result.entry(&v.table).or_default();
+            keys.extend(v.pairs.iter().map(|pair| pair.key.as_str()));
+        }
+        Some(RequestData::Hincrby(v)) => {
+            result.entry(&v.table).or_default().insert(&v.key);
+        }
+        Some(RequestData::Hincrbyfloat(v)) => {
+            result.entry(&v.table).or_default().insert(&v.key);
+        }
+        Some(RequestData::Hcas(v)) => {
+            result.entry(&v.table).or_default().insert(&v.key);
+        }
+        _ => {}
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 请求速率超过限制时返回 429
+    pub(super) fn check_rate(&self, session: &Session) -> Result<(), KvError> {
+        self.inner.limiter.check_rate(session)
+    }
+
+    /// 写入新的 key 会让 table 超过限制时拒绝整个命令，已经存在的 key 可以继续修改。
+    /// 并发写入同一个 table 时可能会略微超过限制，后面的写入失败时预留的空间也不会退回，
+    /// 这些误差在缓存的大小过期之后会被修正
+    pub(super) fn check_quota(&self, cmd: &CommandRequest) -> Result<(), KvError> {
+        let limiter = &self.inner.limiter;
+        if limiter.config.tables.is_empty() {
+            return Ok(());
+        }
+
+        let mut written = BTreeMap::new();
+        written_keys(cmd, &mut written);
+
+        let store = &self.inner.store;
+        for (table, keys) in written {
+            let Some(quota) = limiter.quota(table) else {
+                continue;
+            };
+
+            let mut added = 0;
+            for key in keys {
+                if !store.contains(table, key)? {
+                    added += 1;
+                }
+            }
+            if added > 0 && !limiter.reserve(store, table, added, quota)? {
+                return Err(KvError::QuotaExceeded(format!(
+                    "table {} can have at most {} keys",
+                    table, quota
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{assert_res_error, CommandResponse, Kvpair, MemTable, ServiceInner};
+
+    fn service(limits: LimitsConfig) -> Service {
+        ServiceInner::new(MemTable::new()).limits(limits).into()
+    }
+
+    async fn execute(service: &Service, cmd: CommandRequest, session: &Session) -> CommandResponse {
+        let res = service.execute_with_session(cmd, session);
+        res.collect::<Vec<_>>().await.remove(0).as_ref().clone()
+    }
+
+    #[test]
+    fn token_bucket_should_refill_over_time() {
+        let limit = RateLimit {
+            rate: 100.0,
+            burst: 2,
+        };
+        let mut bucket = TokenBucket::new(&limit);
+        assert!(bucket.try_acquire(&limit));
+        assert!(bucket.try_acquire(&limit));
+        assert!(!bucket.try_acquire(&limit));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(bucket.try_acquire(&limit));
+    }
+
+    #[tokio::test]
+    async fn connection_rate_limit_should_return_429() {
+        let limit = RateLimit {
+            rate: 0.001,
+            burst: 2,
+        };
+        let service = service(LimitsConfig {
+            connection: Some(limit),
+            ..Default::default()
+        });
+
+        let session = Session::default();
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        for _ in 0..2 {
+            let res = execute(&service, cmd.clone(), &session).await;
+            assert_eq!(res.status, 404);
+        }
+        let res = execute(&service, cmd.clone(), &session).await;
+        assert_res_error(&res, 429, "connection");
+
+        // 其它连接不受影响
+        let res = execute(&service, cmd, &Session::default()).await;
+        assert_eq!(res.status, 404);
+    }
+
+    #[tokio::test]
+    async fn principal_rate_limit_should_be_shared_by_connections() {
+        let limit = RateLimit {
+            rate: 0.001,
+            burst: 1,
+        };
+        let service = service(LimitsConfig {
+            principal: Some(limit),
+            ..Default::default()
+        });
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = execute(&service, cmd.clone(), &Session::new(Some("app".into()))).await;
+        assert_eq!(res.status, 404);
+        let res = execute(&service, cmd.clone(), &Session::new(Some("app".into()))).await;
+        assert_res_error(&res, 429, "principal app");
+
+        let res = execute(&service, cmd, &Session::default()).await;
+        assert_eq!(res.status, 404);
+    }
+
+    #[tokio::test]
+    async fn table_quota_should_be_enforced_on_new_keys() {
+        let service = service(LimitsConfig {
+            tables: HashMap::from([("*".to_string(), 2), ("big".to_string(), 10)]),
+            ..Default::default()
+        });
+        let session = Session::default();
+
+        let cmd = CommandRequest::new_hmset(
+            "t1",
+            vec![
+                Kvpair::new("k1", "v1".into()),
+                Kvpair::new("k2", "v2".into()),
+            ],
+        );
+        assert_eq!(execute(&service, cmd, &session).await.status, 200);
+
+        // 修改已经存在的 key 不受影响
+        let cmd = CommandRequest::new_hset("t1", "k1", "v3".into());
+        assert_eq!(execute(&service, cmd, &session).await.status, 200);
+
+        let cmd = CommandRequest::new_hset("t1", "k3", "v3".into());
+        let res = execute(&service, cmd, &session).await;
+        assert_res_error(&res, 507, "table t1");
+
+        // Multi 中的写入也要检查
+        let cmd =
+            CommandRequest::new_multi(vec![CommandRequest::new_hset("t1", "k4", "v4".into())]);
+        let res = execute(&service, cmd, &session).await;
+        assert_res_error(&res, 507, "at most 2 keys");
+
+        let cmd = CommandRequest::new_hset("big", "k3", "v3".into());
+        assert_eq!(execute(&service, cmd, &session).await.status, 200);
+
+        // 其它会创建 key 的命令也要检查
+        let cmds = [
+            CommandRequest::new_hsetnx("t1", "k4", "v4".into()),
+            CommandRequest::new_hincrby("t1", "k4", 1),
+            CommandRequest::new_hincrbyfloat("t1", "k4", 1.0),
+            CommandRequest::new_hcas("t1", "k4", None, "v4".into()),
+        ];
+        for cmd in cmds {
+            let res = execute(&service, cmd, &session).await;
+            assert_res_error(&res, 507, "table t1");
+        }
+        let cmd = CommandRequest::new_hincrby("t1", "k1", 1);
+        assert_ne!(execute(&service, cmd, &session).await.status, 507);
+
+        // 删除 key 之后马上可以写入新的 key
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        assert_eq!(execute(&service, cmd, &session).await.status, 200);
+        let cmd = CommandRequest::new_hset("t1", "k3", "v3".into());
+        assert_eq!(execute(&service, cmd, &session).await.status, 200);
+    }
+
+    #[test]
+    fn full_principal_buckets_should_be_evicted() {
+        let limit = RateLimit {
+            rate: 1000.0,
+            burst: 1,
+        };
+        let limiter = Limiter::new(LimitsConfig {
+            principal: Some(limit),
+            ..Default::default()
+        });
+
+        limiter
+            .check_rate(&Session::new(Some("app".into())))
+            .unwrap();
+        assert!(limiter.principals.contains_key("app"));
+
+        // 等 bucket 补满，并且到了下一次清理的时间
+        std::thread::sleep(Duration::from_millis(10));
+        *limiter.swept.lock().unwrap() -= SWEEP_INTERVAL;
+        limiter
+            .check_rate(&Session::new(Some("other".into())))
+            .unwrap();
+        assert!(!limiter.principals.contains_key("app"));
+        assert!(limiter.principals.contains_key("other"));
+    }
+}
@@ -0,0 +1,168 @@
+use futures::{future::BoxFuture, stream};
+use std::sync::Arc;
+
+use super::{Session, StreamingResponse};
+use crate::{CommandRequest, CommandResponse};
+
+/// 包在 Service::execute 外面的中间件，可以拒绝或者改写请求，也可以处理返回的结果
+///
+/// 调用 next.run(cmd) 把请求交给下一个中间件，最后一个中间件之后是 Service 本身；
+/// 不调用 next 就直接返回结果，比如权限不够时返回错误
+pub trait Middleware: Send + Sync + 'static {
+    fn handle<'a>(
+        &'a self,
+        cmd: CommandRequest,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, StreamingResponse>;
+}
+
+/// 用 closure 实现的中间件，见 ServiceInner::layer_fn
+pub(super) struct FnMiddleware<F>(pub(super) F);
+
+impl<F> Middleware for FnMiddleware<F>
+where
+    F: for<'a> Fn(CommandRequest, Next<'a>) -> BoxFuture<'a, StreamingResponse>,
+    F: Send + Sync + 'static,
+{
+    fn handle<'a>(
+        &'a self,
+        cmd: CommandRequest,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, StreamingResponse> {
+        (self.0)(cmd, next)
+    }
+}
+
+/// 中间件链中剩下的部分
+pub struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    endpoint: &'a (dyn Fn(CommandRequest) -> StreamingResponse + Send + Sync),
+    session: &'a Session,
+}
+
+impl<'a> Next<'a> {
+    pub(super) fn new(
+        middlewares: &'a [Box<dyn Middleware>],
+        endpoint: &'a (dyn Fn(CommandRequest) -> StreamingResponse + Send + Sync),
+        session: &'a Session,
+    ) -> Self {
+        Self {
+            middlewares,
+            endpoint,
+            session,
+        }
+    }
+
+    /// 当前请求所在的会话
+    pub fn session(&self) -> &'a Session {
+        self.session
+    }
+
+    /// 执行剩下的中间件和 Service
+    pub async fn run(self, cmd: CommandRequest) -> StreamingResponse {
+        match self.middlewares.split_first() {
+            Some((first, rest)) => {
+                let next = Next {
+                    middlewares: rest,
+                    ..self
+                };
+                first.handle(cmd, next).await
+            }
+            None => (self.endpoint)(cmd),
+        }
+    }
+}
+
+/// 只有一个 response 的结果，中间件拒绝请求时使用
+pub fn reply(res: impl Into<CommandResponse>) -> StreamingResponse {
+    let res = Arc::new(res.into());
+    Box::pin(stream::once(async { res }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures::{FutureExt, StreamExt};
+
+    use super::*;
+    use crate::{
+        assert_res_error, assert_res_ok, command_request::RequestData, KvError, MemTable, Service,
+        ServiceInner, Value,
+    };
+
+    /// 记录所有写命令的审计日志，需要等待异步的写入完成
+    struct Audit(Arc<Mutex<Vec<String>>>);
+
+    impl Middleware for Audit {
+        fn handle<'a>(
+            &'a self,
+            cmd: CommandRequest,
+            next: Next<'a>,
+        ) -> BoxFuture<'a, StreamingResponse> {
+            async move {
+                if cmd.is_write() {
+                    let entry = format!("{} {}", next.session().principal(), cmd.name());
+                    tokio::task::yield_now().await;
+                    self.0.lock().unwrap().push(entry);
+                }
+                next.run(cmd).await
+            }
+            .boxed()
+        }
+    }
+
+    async fn execute(service: &Service, cmd: CommandRequest) -> Arc<CommandResponse> {
+        service.execute(cmd).next().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn middleware_should_reject_and_rewrite_requests() {
+        let service: Service = ServiceInner::new(MemTable::new())
+            // 拒绝对 secret 的访问
+            .layer_fn(|cmd, next| {
+                async move {
+                    match &cmd.request_data {
+                        Some(RequestData::Hget(v)) if v.table == "secret" => {
+                            reply(KvError::PermissionDenied("secret".into()))
+                        }
+                        _ => next.run(cmd).await,
+                    }
+                }
+                .boxed()
+            })
+            // 所有的 key 都转成小写
+            .layer_fn(|mut cmd, next| {
+                if let Some(RequestData::Hset(v)) = &mut cmd.request_data {
+                    if let Some(pair) = &mut v.pair {
+                        pair.key = pair.key.to_lowercase();
+                    }
+                }
+                next.run(cmd).boxed()
+            })
+            .into();
+
+        let res = execute(&service, CommandRequest::new_hget("secret", "k1")).await;
+        assert_res_error(&res, 403, "secret");
+
+        let cmd = CommandRequest::new_hset("t1", "KEY", "v1".into());
+        execute(&service, cmd).await;
+        let res = execute(&service, CommandRequest::new_hget("t1", "key")).await;
+        assert_res_ok(&res, &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn middleware_should_keep_state() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(Audit(log.clone()))
+            .into();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = execute(&service, cmd).await;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        execute(&service, CommandRequest::new_hget("t1", "k1")).await;
+
+        assert_eq!(*log.lock().unwrap(), vec!["anonymous hset".to_string()]);
+    }
+}
@@ -1,15 +1,27 @@
 use crate::{
-    command_request::RequestData, CommandRequest, CommandResponse, KvError, MemTable, Storage,
+    command_request::RequestData, AclConfig, CommandRequest, CommandResponse, CompressionConfig,
+    KeyspaceConfig, KvError, LimitsConfig, MemTable, Storage, TopicsConfig,
 };
-use futures::stream;
-use std::sync::Arc;
-use tracing::{debug, instrument};
+use futures::{future::BoxFuture, stream, StreamExt};
+use std::{sync::Arc, time::Duration};
+use tokio::{task::JoinHandle, time};
+use tracing::{debug, instrument, warn};
 
+mod auth;
 mod command_service;
+mod keyspace;
+mod limit;
+mod middleware;
+mod replication;
+mod subscriber;
 mod topic;
 mod topic_service;
 
-pub use topic::{Broadcaster, Topic};
+pub use auth::{Session, ANONYMOUS};
+pub use keyspace::keyspace_topic;
+pub use middleware::{reply, Middleware, Next};
+pub use replication::{ReplicaState, ReplicationRole, HEARTBEAT_INTERVAL};
+pub use topic::{Broadcaster, Replay, Topic};
 pub use topic_service::{StreamingResponse, TopicService};
 
 /// 对 Command 的处理的抽象
@@ -68,16 +80,30 @@ pub struct ServiceInner<Store> {
     on_executed: Vec<fn(&CommandResponse)>,
     on_before_send: Vec<fn(&mut CommandResponse)>,
     on_after_send: Vec<fn()>,
+    middlewares: Vec<Box<dyn Middleware>>,
+    role: ReplicationRole,
+    acl: Option<AclConfig>,
+    limiter: limit::Limiter,
+    topics: TopicsConfig,
+    keyspace: KeyspaceConfig,
+    compression: CompressionConfig,
 }
 
 impl<Store: Storage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
             store,
+            role: ReplicationRole::default(),
+            acl: None,
+            limiter: Default::default(),
+            topics: Default::default(),
+            keyspace: Default::default(),
+            compression: Default::default(),
             on_received: Vec::new(),
             on_executed: Vec::new(),
             on_before_send: Vec::new(),
             on_after_send: Vec::new(),
+            middlewares: Vec::new(),
         }
     }
 
@@ -100,40 +126,199 @@ impl<Store: Storage> ServiceInner<Store> {
         self.on_after_send.push(f);
         self
     }
+
+    /// 添加一个中间件，先添加的在外层，最先看到请求
+    pub fn layer(mut self, middleware: impl Middleware) -> Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// 用 closure 添加一个中间件，closure 返回的 future 需要 boxed()
+    pub fn layer_fn<F>(self, f: F) -> Self
+    where
+        F: for<'a> Fn(CommandRequest, Next<'a>) -> BoxFuture<'a, StreamingResponse>,
+        F: Send + Sync + 'static,
+    {
+        self.layer(middleware::FnMiddleware(f))
+    }
+
+    /// 设置主从复制中的角色
+    pub fn replication(mut self, role: ReplicationRole) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// 设置请求速率和 table 大小的限制
+    pub fn limits(mut self, limits: LimitsConfig) -> Self {
+        self.limiter = limit::Limiter::new(limits);
+        self
+    }
+
+    /// 设置哪些主题是持久化的
+    pub fn topics(mut self, topics: TopicsConfig) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    /// 设置哪些 table 发布 key 变化的通知
+    pub fn keyspace(mut self, keyspace: KeyspaceConfig) -> Self {
+        self.keyspace = keyspace;
+        self
+    }
+
+    /// 设置发送 frame 时的压缩配置，客户端通过 HELLO 协商之后才使用 gzip 以外的算法
+    pub fn compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// 设置访问控制，None 表示不做权限检查
+    pub fn acl(mut self, acl: Option<AclConfig>) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// 命令执行完后，通知 on_executed/on_before_send
+    fn notify_executed(&self, mut res: CommandResponse) -> Arc<CommandResponse> {
+        debug!("Executed response: {:?}", res);
+        self.on_executed.notify(&res);
+        self.on_before_send.notify(&mut res);
+        if !self.on_before_send.is_empty() {
+            debug!("Modified response: {:?}", res);
+        }
+        Arc::new(res)
+    }
 }
 
 impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
     fn from(inner: ServiceInner<Store>) -> Self {
+        let broadcaster = Broadcaster::new(inner.topics.clone());
         Self {
             inner: Arc::new(inner),
-            broadcaster: Default::default(),
+            broadcaster: Arc::new(broadcaster),
         }
     }
 }
 
 impl<Store: Storage> Service<Store> {
-    #[instrument(name = "service_execute", skip_all)]
+    /// 以 anonymous 的身份执行命令
     pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
+        self.execute_with_session(cmd, &Session::default())
+    }
+
+    /// 以会话的 principal 的身份执行命令，先经过所有的中间件
+    pub fn execute_with_session(
+        &self,
+        cmd: CommandRequest,
+        session: &Session,
+    ) -> StreamingResponse {
+        if self.inner.middlewares.is_empty() {
+            return self.handle(cmd, session);
+        }
+
+        // 中间件可能需要 await，等中间件链执行完之后再返回结果中的数据
+        let service = self.clone();
+        let session = session.clone();
+        let res = async move {
+            let endpoint = |cmd| service.handle(cmd, &session);
+            Next::new(&service.inner.middlewares, &endpoint, &session)
+                .run(cmd)
+                .await
+        };
+        Box::pin(stream::once(res).flatten())
+    }
+
+    /// 执行命令，dispatch 之前先检查请求速率、ACL 和 table 的大小限制
+    #[instrument(name = "service_execute", skip_all)]
+    fn handle(&self, cmd: CommandRequest, session: &Session) -> StreamingResponse {
         debug!("Got request: {:?}", cmd);
         self.inner.on_received.notify(&cmd);
-        let mut res = dispatch(cmd.clone(), &self.inner.store);
+
+        let read_only = matches!(self.inner.role, ReplicationRole::Replica(_));
+        let checked = self
+            .check_rate(session)
+            .and_then(|_| self.check_permission(&cmd, session))
+            .and_then(|_| self.check_quota(&cmd));
+        let res = match checked {
+            Err(e) => e.into(),
+            Ok(()) => match &cmd.request_data {
+                // 从节点只能执行只读的命令
+                _ if read_only && cmd.is_write() => KvError::ReadOnly.into(),
+                // 分批返回的 HGETALL，每一批都和普通的 response 一样处理
+                Some(RequestData::Hgetall(param)) if param.chunk_size > 0 => {
+                    let inner = Arc::clone(&self.inner);
+                    let chunks = param.clone().execute_chunked(&self.inner.store);
+                    return Box::pin(
+                        stream::iter(chunks).map(move |res| inner.notify_executed(res)),
+                    );
+                }
+                Some(RequestData::Replicate(_)) => return self.replicate(),
+                Some(RequestData::ReplicationInfo(_)) => self.replication_info(),
+                Some(RequestData::Auth(param)) => self.authenticate(param, session),
+                Some(RequestData::Hello(param)) => self.hello(param, session),
+                _ => dispatch(cmd.clone(), &self.inner.store),
+            },
+        };
 
         if res == CommandResponse::default() {
             dispatch_stream(cmd, Arc::clone(&self.broadcaster))
         } else {
-            debug!("Executed response: {:?}", res);
-            self.inner.on_executed.notify(&res);
-            self.inner.on_before_send.notify(&mut res);
-            if !self.inner.on_before_send.is_empty() {
-                debug!("Modified response: {:?}", res);
-            }
-
-            Box::pin(stream::once(async { Arc::new(res) }))
+            self.notify_keyspace(&cmd, &res);
+            let res = self.inner.notify_executed(res);
+            Box::pin(stream::once(async { res }))
         }
     }
+
+    /// 和会话的客户端协商之后，发送 frame 时使用的压缩配置
+    pub fn compression(&self, session: &Session) -> CompressionConfig {
+        self.inner.compression.negotiate(&session.peer_codecs())
+    }
+
+    /// 订阅相关的状态
+    pub fn broadcaster(&self) -> &Broadcaster {
+        &self.broadcaster
+    }
+
+    /// 每个 table 中 key 的数量，有的 storage 需要遍历所有的数据，只适合偶尔调用（比如 metrics）
+    pub fn table_sizes(&self) -> Result<Vec<(String, usize)>, KvError> {
+        let store = &self.inner.store;
+        store
+            .get_tables()?
+            .into_iter()
+            .map(|table| {
+                let size = store.len(&table)?;
+                Ok((table, size))
+            })
+            .collect()
+    }
+
+    /// 把 storage 中还在缓存的修改写到磁盘
+    pub fn flush(&self) -> Result<(), KvError> {
+        self.inner.store.flush()
+    }
+
+    /// 启动后台任务，每隔 interval 清理一次 storage 中过期的 key
+    /// 所有的 Service 都被释放后，这个任务会自动退出
+    pub fn start_expiration(&self, interval: Duration) -> JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+                match inner.store.purge_expired() {
+                    Ok(0) => {}
+                    Ok(n) => debug!("{} expired keys are purged", n),
+                    Err(e) => warn!("Failed to purge expired keys: {:?}", e),
+                }
+            }
+        })
+    }
 }
 
-/// 从 Request 中得到 Response，目前处理所有 HGET/HSET/HDEL/HEXIST
+/// 从 Request 中得到 Response，目前处理所有的 KV 命令（HGET/HSET/HDEL/HEXIST 等）
 pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
     match cmd.request_data {
         Some(RequestData::Hget(param)) => param.execute(store),
@@ -145,23 +330,58 @@ pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
         Some(RequestData::Hmdel(param)) => param.execute(store),
         Some(RequestData::Hexist(param)) => param.execute(store),
         Some(RequestData::Hmexist(param)) => param.execute(store),
+        Some(RequestData::Hexpire(param)) => param.execute(store),
+        Some(RequestData::Httl(param)) => param.execute(store),
+        Some(RequestData::Hpersist(param)) => param.execute(store),
+        Some(RequestData::Hincrby(param)) => param.execute(store),
+        Some(RequestData::Hincrbyfloat(param)) => param.execute(store),
+        Some(RequestData::Hcas(param)) => param.execute(store),
+        Some(RequestData::Hsetnx(param)) => param.execute(store),
+        Some(RequestData::Multi(param)) => param.execute(store),
+        Some(RequestData::Hscan(param)) => param.execute(store),
+        Some(RequestData::Hprefix(param)) => param.execute(store),
         None => KvError::InvalidCommand("Request has no data".into()).into(),
         // 处理不了的返回一个啥都不包括的 Response，这样后续可以用 dispatch_stream 处理
         _ => CommandResponse::default(),
     }
 }
 
-/// 从 Request 中得到 Response，目前处理所有 PUBLISH/SUBSCRIBE/UNSUBSCRIBE
+/// 从 Request 中得到 Response，目前处理所有 PUBLISH/SUBSCRIBE/UNSUBSCRIBE/PSUBSCRIBE/PUNSUBSCRIBE
 pub fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingResponse {
     match cmd.request_data {
         Some(RequestData::Publish(param)) => param.execute(topic),
         Some(RequestData::Subscribe(param)) => param.execute(topic),
         Some(RequestData::Unsubscribe(param)) => param.execute(topic),
+        Some(RequestData::Psubscribe(param)) => param.execute(topic),
+        Some(RequestData::Punsubscribe(param)) => param.execute(topic),
         // 如果走到这里，就是代码逻辑的问题，直接 crash 出来
         _ => unreachable!(),
     }
 }
 
+#[cfg(test)]
+use crate::{Kvpair, Value};
+
+// 测试成功返回的结果
+#[cfg(test)]
+pub fn assert_res_ok(res: &CommandResponse, values: &[Value], pairs: &[Kvpair]) {
+    let mut sorted_pairs = res.pairs.clone();
+    sorted_pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(res.status, 200);
+    assert_eq!(res.message, "");
+    assert_eq!(res.values, values);
+    assert_eq!(sorted_pairs, pairs);
+}
+
+// 测试失败返回的结果
+#[cfg(test)]
+pub fn assert_res_error(res: &CommandResponse, code: u32, msg: &str) {
+    assert_eq!(res.status, code);
+    assert!(res.message.contains(msg));
+    assert_eq!(res.values, &[]);
+    assert_eq!(res.pairs, &[]);
+}
+
 #[cfg(test)]
 mod tests {
     use http::StatusCode;
@@ -223,27 +443,45 @@ mod tests {
         assert_eq!(data.message, "");
         assert_eq!(data.values, vec![Value::default()]);
     }
-}
 
-#[cfg(test)]
-use crate::{Kvpair, Value};
+    #[tokio::test]
+    async fn chunked_hgetall_should_return_multiple_responses() {
+        fn d(res: &mut CommandResponse) {
+            res.status = StatusCode::CREATED.as_u16() as _;
+        }
 
-// 测试成功返回的结果
-#[cfg(test)]
-pub fn assert_res_ok(res: &CommandResponse, values: &[Value], pairs: &[Kvpair]) {
-    let mut sorted_pairs = res.pairs.clone();
-    sorted_pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    assert_eq!(res.status, 200);
-    assert_eq!(res.message, "");
-    assert_eq!(res.values, values);
-    assert_eq!(sorted_pairs, pairs);
-}
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_before_send(d)
+            .into();
+        for i in 0..5 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+            service.execute(cmd).next().await.unwrap();
+        }
 
-// 测试失败返回的结果
-#[cfg(test)]
-pub fn assert_res_error(res: &CommandResponse, code: u32, msg: &str) {
-    assert_eq!(res.status, code);
-    assert!(res.message.contains(msg));
-    assert_eq!(res.values, &[]);
-    assert_eq!(res.pairs, &[]);
+        let res = service.execute(CommandRequest::new_hgetall_chunked("t1", 2));
+        let chunks: Vec<_> = res.collect().await;
+        assert_eq!(chunks.len(), 3);
+        let total: usize = chunks.iter().map(|res| res.pairs.len()).sum();
+        assert_eq!(total, 5);
+        assert!(chunks.iter().all(|res| res.status == 201));
+    }
+
+    #[tokio::test]
+    async fn expiration_task_should_purge_expired_keys() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let handle = service.start_expiration(Duration::from_millis(10));
+
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        res.next().await.unwrap();
+        let cmd = CommandRequest::new_hexpire("t1", "k1", Duration::from_millis(10));
+        let mut res = service.execute(cmd);
+        res.next().await.unwrap();
+
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(service.inner.store.purge_expired().unwrap(), 0);
+
+        // service 释放后，后台任务退出
+        drop(service);
+        handle.await.unwrap();
+    }
 }
@@ -0,0 +1,388 @@
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use prost::Message;
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::{sync::broadcast::error::RecvError, time};
+use tracing::{info, warn};
+
+use crate::{
+    apply_record, deadline_ms, now_ms,
+    pb::wal::{wal_record::Op, ReplicationFrame, WalRecord},
+    CommandResponse, KvError, Kvpair, ReplicationLog, Service, Storage, StreamingResponse, Value,
+};
+
+/// 没有修改时，主节点发送心跳的间隔
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// 全量同步时，每个 frame 最多包含多少条记录
+const SNAPSHOT_CHUNK_SIZE: usize = 1000;
+
+/// 节点在主从复制中的角色
+#[derive(Debug, Clone, Default)]
+pub enum ReplicationRole {
+    /// 不参与复制
+    #[default]
+    Standalone,
+    /// 主节点，从节点通过 REPLICATE 命令来同步数据
+    Primary(ReplicationLog),
+    /// 从节点，数据来自主节点，只能执行只读的命令
+    Replica(Arc<ReplicaState>),
+}
+
+/// 从节点的复制状态
+#[derive(Debug, Default)]
+pub struct ReplicaState {
+    inner: Mutex<ReplicaStatus>,
+}
+
+#[derive(Debug, Default)]
+struct ReplicaStatus {
+    connected: bool,
+    /// 最近一次收到的主节点的修改序号
+    seq: u64,
+    /// 最近一次收到的 frame 在主节点上的发送时刻
+    timestamp: u64,
+    /// 全量同步过程中收到的 key，同步完成后删除本地多余的 key
+    resync: Option<HashSet<(String, String)>>,
+}
+
+impl ReplicaState {
+    /// 是否正在从主节点同步数据
+    pub fn is_connected(&self) -> bool {
+        self.lock().connected
+    }
+
+    /// 最近一次收到的主节点的修改序号
+    pub fn seq(&self) -> u64 {
+        self.lock().seq
+    }
+
+    /// 复制延迟：当前时刻减去最近收到的 frame 在主节点上的发送时刻
+    /// 主节点空闲时也会发送心跳，所以连接断开后延迟会一直增大。还没有收到过数据时返回 None
+    /// 这个值依赖主从节点的时钟同步
+    pub fn lag(&self) -> Option<Duration> {
+        match self.lock().timestamp {
+            0 => None,
+            ts => Some(Duration::from_millis(now_ms().saturating_sub(ts))),
+        }
+    }
+
+    pub(crate) fn disconnected(&self) {
+        let mut status = self.lock();
+        status.connected = false;
+        status.resync = None;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ReplicaStatus> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 处理从节点的 REPLICATE 命令：先发送所有的数据，然后持续发送之后的修改
+    pub(super) fn replicate(&self) -> StreamingResponse {
+        let log = match &self.inner.role {
+            ReplicationRole::Primary(log) => log.clone(),
+            _ => {
+                let res = KvError::InvalidCommand("Server is not a primary".into()).into();
+                return Box::pin(stream::once(async { Arc::new(res) }));
+            }
+        };
+
+        // 先订阅再读取所有的数据，这样读取之后的修改一定能收到
+        // 两者重叠部分的修改会在从节点上再执行一遍，因为记录里都是修改后的结果，所以不影响最终的数据
+        let rx = log.subscribe();
+        let snapshot = match snapshot_frames(&self.inner.store, log.seq()) {
+            Ok(v) => v,
+            Err(e) => {
+                let res = e.into();
+                return Box::pin(stream::once(async { Arc::new(res) }));
+            }
+        };
+        info!("Start replicating, snapshot has {} frames", snapshot.len());
+
+        let changes = stream::unfold((rx, log), |(mut rx, log)| async move {
+            let frame = match time::timeout(HEARTBEAT_INTERVAL, rx.recv()).await {
+                Ok(Ok(frame)) => frame,
+                Ok(Err(RecvError::Lagged(n))) => {
+                    // 结束 stream，从节点会重新连接并全量同步
+                    warn!("Replica lagged behind {} frames, stop replicating", n);
+                    return None;
+                }
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_) => Arc::new(log.heartbeat()),
+            };
+            Some((frame, (rx, log)))
+        });
+
+        let frames = stream::iter(snapshot.into_iter().map(Arc::new)).chain(changes);
+        Box::pin(frames.map(|frame| Arc::new(frame_to_response(&frame))))
+    }
+
+    /// 从节点应用主节点发来的数据
+    pub fn apply_replication(&self, res: &CommandResponse) -> Result<(), KvError> {
+        let state = match &self.inner.role {
+            ReplicationRole::Replica(state) => state,
+            _ => return Err(KvError::InvalidCommand("Server is not a replica".into())),
+        };
+
+        let frame = frame_from_response(res)?;
+        let store = &self.inner.store;
+        let mut status = state.lock();
+
+        if frame.snapshot {
+            let keys = status.resync.get_or_insert_with(HashSet::new);
+            for record in frame.records.iter() {
+                if let Some(Op::Set(v)) = &record.op {
+                    keys.insert((v.table.clone(), v.key.clone()));
+                }
+            }
+        } else if let Some(keys) = status.resync.take() {
+            // 全量同步完成，删除主节点上已经不存在的 key
+            let count = remove_stale_keys(store, &keys)?;
+            info!("Snapshot is applied, {} stale keys are removed", count);
+        }
+
+        for record in frame.records {
+            apply_record(store, record)?;
+        }
+
+        status.connected = true;
+        status.seq = frame.seq;
+        status.timestamp = frame.timestamp;
+        Ok(())
+    }
+
+    /// 处理 REPLICATION_INFO 命令
+    pub(super) fn replication_info(&self) -> CommandResponse {
+        let pairs = match &self.inner.role {
+            ReplicationRole::Standalone => vec![Kvpair::new("role", "standalone".into())],
+            ReplicationRole::Primary(log) => vec![
+                Kvpair::new("role", "primary".into()),
+                Kvpair::new("seq", (log.seq() as i64).into()),
+                Kvpair::new("replicas", (log.replicas() as i64).into()),
+            ],
+            ReplicationRole::Replica(state) => {
+                // 没有收到过数据时延迟为 -1
+                let lag = state.lag().map(|v| v.as_millis() as i64).unwrap_or(-1);
+                vec![
+                    Kvpair::new("role", "replica".into()),
+                    Kvpair::new("seq", (state.seq() as i64).into()),
+                    Kvpair::new("connected", state.is_connected().into()),
+                    Kvpair::new("lag_ms", lag.into()),
+                ]
+            }
+        };
+        pairs.into()
+    }
+}
+
+/// 把 storage 里所有的数据生成全量同步的 frame，至少会有一个 frame
+fn snapshot_frames(store: &impl Storage, seq: u64) -> Result<Vec<ReplicationFrame>, KvError> {
+    let mut records = Vec::new();
+    for table in store.get_tables()? {
+        for pair in store.get_iter(&table)? {
+            let ttl = store.ttl(&table, &pair.key)?;
+            let Kvpair { key, value } = pair;
+            records.push(WalRecord::new_set(
+                table.as_str(),
+                key.as_str(),
+                value.unwrap_or_default(),
+            ));
+            if let Some(ttl) = ttl {
+                let at = deadline_ms(ttl);
+                records.push(WalRecord::new_expire_at(table.as_str(), key, at));
+            }
+        }
+    }
+
+    let timestamp = now_ms();
+    let mut frames = Vec::new();
+    let mut records = records.into_iter().peekable();
+    loop {
+        let chunk: Vec<_> = records.by_ref().take(SNAPSHOT_CHUNK_SIZE).collect();
+        frames.push(ReplicationFrame {
+            seq,
+            timestamp,
+            snapshot: true,
+            records: chunk,
+        });
+        if records.peek().is_none() {
+            break;
+        }
+    }
+    Ok(frames)
+}
+
+/// 删除 store 中不在 keys 里的 key，返回删除的个数
+fn remove_stale_keys(
+    store: &impl Storage,
+    keys: &HashSet<(String, String)>,
+) -> Result<usize, KvError> {
+    let mut stale = Vec::new();
+    for table in store.get_tables()? {
+        for pair in store.get_iter(&table)? {
+            let key = (table.clone(), pair.key);
+            if !keys.contains(&key) {
+                stale.push(key);
+            }
+        }
+    }
+
+    for (table, key) in stale.iter() {
+        store.del(table, key)?;
+    }
+    Ok(stale.len())
+}
+
+fn frame_to_response(frame: &ReplicationFrame) -> CommandResponse {
+    let data = Bytes::from(frame.encode_to_vec());
+    Value::from(data).into()
+}
+
+fn frame_from_response(res: &CommandResponse) -> Result<ReplicationFrame, KvError> {
+    if res.status != 200 {
+        return Err(KvError::Internal(format!(
+            "Replication failed: {}",
+            res.message
+        )));
+    }
+
+    match res.values.first() {
+        Some(v) => {
+            let data = Bytes::try_from(v.clone())?;
+            Ok(ReplicationFrame::decode(data)?)
+        }
+        None => Err(KvError::Internal("Invalid replication frame".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, CommandRequest, MemTable, ReplicatedStorage, ServiceInner};
+
+    fn primary() -> Service<ReplicatedStorage<MemTable>> {
+        let log = ReplicationLog::default();
+        let store = ReplicatedStorage::new(MemTable::new(), log.clone());
+        ServiceInner::new(store)
+            .replication(ReplicationRole::Primary(log))
+            .into()
+    }
+
+    fn replica() -> (Service, Arc<ReplicaState>) {
+        let state = Arc::new(ReplicaState::default());
+        let role = ReplicationRole::Replica(state.clone());
+        (
+            ServiceInner::new(MemTable::new()).replication(role).into(),
+            state,
+        )
+    }
+
+    async fn execute<Store: Storage>(service: &Service<Store>, cmd: CommandRequest) {
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.status, 200);
+    }
+
+    #[tokio::test]
+    async fn replica_should_receive_snapshot_and_changes() {
+        let primary = primary();
+        execute(&primary, CommandRequest::new_hset("t1", "k1", "v1".into())).await;
+        // 很大的 ttl 在 snapshot 中换算成过期时刻时也不会溢出
+        let cmd = CommandRequest::new_hexpire("t1", "k1", Duration::from_millis(u64::MAX));
+        execute(&primary, cmd).await;
+
+        let (replica, state) = replica();
+        // 从节点上有一个主节点上不存在的 key，全量同步后应该被删除
+        replica
+            .inner
+            .store
+            .set("t2", "stale".into(), 1.into())
+            .unwrap();
+
+        let mut stream = primary.execute(CommandRequest::new_replicate());
+        // snapshot
+        replica
+            .apply_replication(&stream.next().await.unwrap())
+            .unwrap();
+        assert_eq!(
+            replica.inner.store.get("t1", "k1").unwrap(),
+            Some("v1".into())
+        );
+        assert!(replica.inner.store.ttl("t1", "k1").unwrap().is_some());
+
+        // 增量的修改
+        execute(&primary, CommandRequest::new_hincrby("t1", "n", 2)).await;
+        execute(&primary, CommandRequest::new_hdel("t1", "k1")).await;
+        for _ in 0..2 {
+            replica
+                .apply_replication(&stream.next().await.unwrap())
+                .unwrap();
+        }
+        assert_eq!(replica.inner.store.get("t1", "n").unwrap(), Some(2.into()));
+        assert!(!replica.inner.store.contains("t1", "k1").unwrap());
+        assert!(!replica.inner.store.contains("t2", "stale").unwrap());
+
+        assert!(state.is_connected());
+        assert_eq!(state.seq(), 4);
+        assert!(state.lag().unwrap() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn primary_should_send_heartbeat_when_idle() {
+        let primary = primary();
+        let (replica, state) = replica();
+
+        let mut stream = primary.execute(CommandRequest::new_replicate());
+        replica
+            .apply_replication(&stream.next().await.unwrap())
+            .unwrap();
+        let frame = frame_from_response(&stream.next().await.unwrap()).unwrap();
+        assert!(!frame.snapshot);
+        assert!(frame.records.is_empty());
+        assert_eq!(state.seq(), 0);
+    }
+
+    #[tokio::test]
+    async fn replica_should_reject_write_commands() {
+        let (replica, _) = replica();
+        let mut res = replica.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let res = res.next().await.unwrap();
+        assert_res_error(&res, 403, "read only replica");
+
+        let cmd = CommandRequest::new_multi(vec![CommandRequest::new_hdel("t1", "k1")]);
+        let res = replica.execute(cmd).next().await.unwrap();
+        assert_eq!(res.status, 403);
+
+        execute(&replica, CommandRequest::new_hgetall("t1")).await;
+    }
+
+    #[tokio::test]
+    async fn replication_info_should_work() {
+        let (replica, _) = replica();
+        let res = replica.execute(CommandRequest::new_replication_info());
+        let res = res.collect::<Vec<_>>().await.remove(0);
+        assert_eq!(res.pairs[0], Kvpair::new("role", "replica".into()));
+        assert_eq!(res.pairs[3], Kvpair::new("lag_ms", (-1).into()));
+
+        let primary = primary();
+        let mut stream = primary.execute(CommandRequest::new_replicate());
+        stream.next().await.unwrap();
+        let res = primary.execute(CommandRequest::new_replication_info());
+        let res = res.collect::<Vec<_>>().await.remove(0);
+        assert_eq!(res.pairs[0], Kvpair::new("role", "primary".into()));
+        assert_eq!(res.pairs[2], Kvpair::new("replicas", 1.into()));
+    }
+
+    #[tokio::test]
+    async fn standalone_should_reject_replicate() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let res = service.execute(CommandRequest::new_replicate());
+        let res = res.collect::<Vec<_>>().await;
+        assert_res_error(&res[0], 400, "not a primary");
+    }
+}
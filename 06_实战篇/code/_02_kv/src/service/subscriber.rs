@@ -0,0 +1,243 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc::{self, error::TrySendError},
+};
+use tracing::warn;
+
+use crate::{Backpressure, CommandResponse, KvError};
+
+/// 每个订阅者的 channel 中最多存放的数据
+pub(super) const BROADCAST_CAPACITY: usize = 128;
+/// Block 策略下订阅者的 channel 中最多存放的数据，满了之后断开订阅，避免慢的订阅者耗尽内存
+pub(super) const BLOCK_CAPACITY: usize = 16 * 1024;
+
+/// 推送失败的原因，两种情况都需要删除订阅
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum SendError {
+    /// 订阅者已经断开
+    Closed,
+    /// Disconnect 或 Block 策略下订阅者的 channel 满了
+    TooSlow,
+}
+
+/// 一个订阅者，按照 backpressure 的策略推送数据
+pub(super) struct Subscriber {
+    tx: Sender,
+    policy: Backpressure,
+    /// 丢弃了还没有告诉订阅者的消息数量
+    lost: AtomicU64,
+}
+
+enum Sender {
+    Channel(mpsc::Sender<Arc<CommandResponse>>),
+    /// DropOldest 时使用 broadcast channel，满了之后覆盖最老的数据，由后台任务转发给订阅者
+    Ring(broadcast::Sender<Arc<CommandResponse>>),
+}
+
+impl Subscriber {
+    /// 生成订阅者和对应的 channel，first 在任何 publish 的数据之前放入 channel
+    pub(super) fn new(
+        policy: Backpressure,
+        first: Option<Arc<CommandResponse>>,
+    ) -> (Self, mpsc::Receiver<Arc<CommandResponse>>) {
+        let (tx, rx) = match policy {
+            Backpressure::DropOldest => {
+                let (ring, ring_rx) = broadcast::channel(BROADCAST_CAPACITY);
+                let (tx, rx) = mpsc::channel(1);
+                if let Some(res) = first {
+                    // 这时 ring_rx 还在，不会失败
+                    let _ = ring.send(res);
+                }
+                tokio::spawn(forward(ring_rx, tx));
+                (Sender::Ring(ring), rx)
+            }
+            _ => {
+                let capacity = match policy {
+                    Backpressure::Block => BLOCK_CAPACITY,
+                    _ => BROADCAST_CAPACITY,
+                };
+                let (tx, rx) = mpsc::channel(capacity);
+                if let Some(res) = first {
+                    // 新的 channel 是空的，不会失败
+                    let _ = tx.try_send(res);
+                }
+                (Sender::Channel(tx), rx)
+            }
+        };
+
+        let subscriber = Self {
+            tx,
+            policy,
+            lost: AtomicU64::new(0),
+        };
+        (subscriber, rx)
+    }
+
+    /// 推送一条数据，不会等待订阅者，所以按调用的顺序推送的数据也按这个顺序到达订阅者
+    pub(super) fn send(&self, value: Arc<CommandResponse>) -> Result<(), SendError> {
+        let tx = match &self.tx {
+            Sender::Ring(tx) => return tx.send(value).map(|_| ()).map_err(|_| SendError::Closed),
+            Sender::Channel(tx) => tx,
+        };
+
+        let lost = self.lost.swap(0, Ordering::Relaxed);
+        match tx.try_send(with_lost(value, lost)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Closed(_)) => Err(SendError::Closed),
+            Err(TrySendError::Full(_)) => {
+                self.lost.fetch_add(lost + 1, Ordering::Relaxed);
+                match self.policy {
+                    Backpressure::Disconnect | Backpressure::Block => Err(SendError::TooSlow),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// 订阅被删除后，等订阅者处理完 channel 中的数据，再告诉它订阅被断开
+    pub(super) fn disconnect(&self, id: u32) {
+        let Sender::Channel(tx) = &self.tx else {
+            return;
+        };
+        let tx = tx.clone();
+        let lost = self.lost.load(Ordering::Relaxed);
+        tokio::spawn(async move {
+            let msg = format!(
+                "subscription {} is disconnected, {} messages lost",
+                id, lost
+            );
+            let res = Arc::new(KvError::SlowSubscriber(msg).into());
+            if tx.send(res).await.is_err() {
+                warn!("Subscriber {} is gone before it is disconnected", id);
+            }
+        });
+    }
+}
+
+/// 把 broadcast channel 中的数据转发给订阅者，被覆盖的数据数量带在下一条数据中
+async fn forward(
+    mut ring: broadcast::Receiver<Arc<CommandResponse>>,
+    tx: mpsc::Sender<Arc<CommandResponse>>,
+) {
+    let mut lost = 0;
+    loop {
+        let res = match ring.recv().await {
+            Ok(res) => with_lost(res, std::mem::take(&mut lost)),
+            Err(RecvError::Lagged(n)) => {
+                lost += n;
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        // 订阅者断开后退出，ring 被 drop，下次 publish 时删除订阅
+        if tx.send(res).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn with_lost(value: Arc<CommandResponse>, lost: u64) -> Arc<CommandResponse> {
+    if lost == 0 {
+        return value;
+    }
+    let mut res = value.as_ref().clone();
+    res.lost = lost;
+    Arc::new(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    fn message(i: i64) -> Arc<CommandResponse> {
+        Arc::new(Value::from(i).into())
+    }
+
+    fn send_all(subscriber: &Subscriber, n: i64) -> Result<(), SendError> {
+        for i in 0..n {
+            subscriber.send(message(i))?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn block_should_keep_messages_in_order_until_full() {
+        let (subscriber, mut rx) = Subscriber::new(Backpressure::Block, Some(message(-1)));
+        let n = BLOCK_CAPACITY as i64 - 1;
+        send_all(&subscriber, n).unwrap();
+        // channel 满了之后断开订阅，不会无限地缓存
+        assert_eq!(subscriber.send(message(n)), Err(SendError::TooSlow));
+
+        let res = rx.recv().await.unwrap();
+        assert_eq!(res.values, [Value::from(-1)]);
+        for i in 0..n {
+            let res = rx.recv().await.unwrap();
+            assert_eq!(res.values, [Value::from(i)]);
+            assert_eq!(res.lost, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_newest_should_report_lost_messages() {
+        let (subscriber, mut rx) = Subscriber::new(Backpressure::DropNewest, None);
+        let n = BROADCAST_CAPACITY as i64;
+        send_all(&subscriber, n + 2).unwrap();
+
+        // 最早的数据都还在，新的两条被丢弃
+        let res = rx.recv().await.unwrap();
+        assert_eq!(res.values, [Value::from(0)]);
+        for _ in 1..n {
+            rx.recv().await.unwrap();
+        }
+
+        subscriber.send(message(100)).unwrap();
+        let res = rx.recv().await.unwrap();
+        assert_eq!(res.values, [Value::from(100)]);
+        assert_eq!(res.lost, 2);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_should_report_lost_messages() {
+        let (subscriber, mut rx) = Subscriber::new(Backpressure::DropOldest, Some(message(-1)));
+        // 先让转发的任务阻塞在订阅者的 channel 上
+        tokio::task::yield_now().await;
+        let n = BROADCAST_CAPACITY as i64 + 10;
+        send_all(&subscriber, n).unwrap();
+
+        let res = rx.recv().await.unwrap();
+        assert_eq!(res.values, [Value::from(-1)]);
+
+        // 收到的最后一条是最新的数据，之前丢弃的数量带在第一条收到的数据中
+        let mut received = vec![];
+        let mut lost = 0;
+        while received.len() < BROADCAST_CAPACITY {
+            let res = rx.recv().await.unwrap();
+            lost += res.lost;
+            received.push(res);
+        }
+        assert_eq!(received.last().unwrap().values, [Value::from(n - 1)]);
+        assert_eq!(lost as usize + received.len(), n as usize);
+    }
+
+    #[tokio::test]
+    async fn disconnect_should_notify_slow_subscriber() {
+        let (subscriber, mut rx) = Subscriber::new(Backpressure::Disconnect, None);
+        let n = BROADCAST_CAPACITY as i64;
+        send_all(&subscriber, n).unwrap();
+        assert_eq!(subscriber.send(message(n)), Err(SendError::TooSlow));
+
+        subscriber.disconnect(1);
+        drop(subscriber);
+        for _ in 0..n {
+            assert_eq!(rx.recv().await.unwrap().status, 200);
+        }
+        let res = rx.recv().await.unwrap();
+        assert_eq!(res.status, 410);
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -1,15 +1,17 @@
 use dashmap::{DashMap, DashSet};
-use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    iter,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
 };
 use tokio::sync::mpsc;
 use tracing::{debug, info, instrument, warn};
 
-use crate::{CommandResponse, KvError, Value};
-
-/// topic 里最大存放的数据
-const BROADCAST_CAPACITY: usize = 128;
+use super::subscriber::{SendError, Subscriber, BROADCAST_CAPACITY};
+use crate::{CommandResponse, KvError, TopicsConfig, Value};
 
 /// 下一个 subscription id
 static NEXT_ID: AtomicU32 = AtomicU32::new(1);
@@ -19,11 +21,27 @@ fn get_next_subscription_id() -> u32 {
     NEXT_ID.fetch_add(1, Ordering::Relaxed)
 }
 
+/// 订阅持久化的主题时，先重放哪些保留的消息
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Replay {
+    /// 只接收新的消息
+    #[default]
+    None,
+    /// 从这个序号开始的消息
+    From(u64),
+    /// 最后 n 条消息
+    Last(u32),
+}
+
 pub trait Topic: Send + Sync + 'static {
     /// 订阅某个主题
-    fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>>;
+    fn subscribe(self, name: String, replay: Replay) -> mpsc::Receiver<Arc<CommandResponse>>;
     /// 取消对主题的订阅
     fn unsubscribe(self, name: String, id: u32) -> Result<u32, KvError>;
+    /// 订阅所有和 pattern 匹配的主题
+    fn psubscribe(self, pattern: String) -> Result<mpsc::Receiver<Arc<CommandResponse>>, KvError>;
+    /// 取消对 pattern 的订阅
+    fn punsubscribe(self, pattern: String, id: u32) -> Result<u32, KvError>;
     /// 往主题里发布一个数据
     fn publish(self, name: String, value: Arc<CommandResponse>);
 }
@@ -33,40 +51,195 @@ pub trait Topic: Send + Sync + 'static {
 pub struct Broadcaster {
     /// 所有的主题列表
     topics: DashMap<String, DashSet<u32>>,
+    /// 所有的 pattern 订阅
+    patterns: RwLock<PatternIndex>,
     /// 所有的订阅列表
-    subscriptions: DashMap<u32, mpsc::Sender<Arc<CommandResponse>>>,
+    subscriptions: DashMap<u32, Arc<Subscriber>>,
+    /// 哪些主题是持久化的
+    config: TopicsConfig,
+    /// 持久化主题保留的消息
+    logs: DashMap<String, TopicLog>,
 }
 
-impl Topic for Arc<Broadcaster> {
-    #[instrument(name = "topic_subscribe", skip_all)]
-    fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>> {
-        let id = {
-            let entry = self.topics.entry(name).or_default();
-            let id = get_next_subscription_id();
-            entry.value().insert(id);
-            id
+/// 持久化主题保留的最近的消息
+#[derive(Debug, Default)]
+struct TopicLog {
+    /// 最后一条消息的序号，序号从 1 开始
+    last_seq: u64,
+    messages: VecDeque<Arc<CommandResponse>>,
+}
+
+impl TopicLog {
+    /// 给消息分配序号并保存，超过 retain 条时丢弃最老的消息
+    fn append(&mut self, value: Arc<CommandResponse>, retain: usize) -> Arc<CommandResponse> {
+        self.last_seq += 1;
+        let mut res = value.as_ref().clone();
+        res.seq = self.last_seq;
+        let value = Arc::new(res);
+
+        self.messages.push_back(value.clone());
+        while self.messages.len() > retain {
+            self.messages.pop_front();
+        }
+        value
+    }
+
+    /// 需要重放的消息，已经被丢弃的消息无法重放
+    fn replay(&self, replay: Replay) -> Vec<Arc<CommandResponse>> {
+        let len = self.messages.len();
+        let skip = match replay {
+            Replay::None => return vec![],
+            Replay::From(seq) => {
+                let first_seq = self.last_seq + 1 - len as u64;
+                seq.saturating_sub(first_seq).min(len as u64) as usize
+            }
+            Replay::Last(n) => len.saturating_sub(n as usize),
         };
+        self.messages.iter().skip(skip).cloned().collect()
+    }
+}
 
-        // 生成一个 mpsc channel
-        let (tx, rx) = mpsc::channel(BROADCAST_CAPACITY);
+/// pattern 订阅的索引：pattern 按 '.' 分成 segment 组成一棵前缀树
+/// publish 时只需要沿着主题的 segment 往下找，查找的代价和 pattern 的个数无关
+#[derive(Debug, Default)]
+struct PatternIndex {
+    root: PatternNode,
+    /// subscription id -> pattern
+    patterns: HashMap<u32, String>,
+}
 
-        let v: Value = (id as i64).into();
+#[derive(Debug, Default)]
+struct PatternNode {
+    /// 下一个 segment，`*` 也作为普通的 key 保存
+    children: HashMap<String, PatternNode>,
+    /// 在这个节点结束的 pattern 的订阅
+    subscriptions: HashSet<u32>,
+    /// 在这个节点之后是 `**` 的 pattern 的订阅
+    rest: HashSet<u32>,
+}
 
-        // 立刻发送 subscription id 到 rx
-        let tx1 = tx.clone();
-        tokio::spawn(async move {
-            if let Err(e) = tx1.send(Arc::new(v.into())).await {
-                // TODO: 这个很小概率发生，但目前我们没有善后
-                warn!("Failed to send subscription id: {}. Error: {:?}", id, e);
+impl PatternIndex {
+    fn insert(&mut self, pattern: String, id: u32) {
+        self.root.insert(&segments(&pattern), id);
+        self.patterns.insert(id, pattern);
+    }
+
+    /// 删除订阅，返回它的 pattern
+    fn remove(&mut self, id: u32) -> Option<String> {
+        let pattern = self.patterns.remove(&id)?;
+        self.root.remove(&segments(&pattern), id);
+        Some(pattern)
+    }
+
+    fn pattern(&self, id: u32) -> Option<&str> {
+        self.patterns.get(&id).map(|v| v.as_str())
+    }
+
+    /// 所有和主题匹配的订阅
+    fn matches(&self, name: &str) -> HashSet<u32> {
+        let mut result = HashSet::new();
+        self.root.collect(&segments(name), &mut result);
+        result
+    }
+}
+
+impl PatternNode {
+    fn insert(&mut self, segments: &[&str], id: u32) {
+        match segments {
+            [] => {
+                self.subscriptions.insert(id);
             }
-        });
+            ["**"] => {
+                self.rest.insert(id);
+            }
+            [first, rest @ ..] => {
+                let child = self.children.entry(first.to_string()).or_default();
+                child.insert(rest, id);
+            }
+        }
+    }
 
-        // 把 tx 存入 subscription table
-        self.subscriptions.insert(id, tx);
-        debug!("Subscription {} is added", id);
+    /// 删除订阅，同时删除变空的子节点
+    fn remove(&mut self, segments: &[&str], id: u32) {
+        match segments {
+            [] => {
+                self.subscriptions.remove(&id);
+            }
+            ["**"] => {
+                self.rest.remove(&id);
+            }
+            [first, rest @ ..] => {
+                if let Some(child) = self.children.get_mut(*first) {
+                    child.remove(rest, id);
+                    if child.is_empty() {
+                        self.children.remove(*first);
+                    }
+                }
+            }
+        }
+    }
 
-        // 返回 rx 给网络处理的上下文
-        rx
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.subscriptions.is_empty() && self.rest.is_empty()
+    }
+
+    fn collect(&self, segments: &[&str], result: &mut HashSet<u32>) {
+        result.extend(&self.rest);
+        match segments {
+            [] => result.extend(&self.subscriptions),
+            [first, rest @ ..] => {
+                if let Some(child) = self.children.get(*first) {
+                    child.collect(rest, result);
+                }
+                if *first != "*" {
+                    if let Some(child) = self.children.get("*") {
+                        child.collect(rest, result);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn segments(name: &str) -> Vec<&str> {
+    name.split('.').collect()
+}
+
+/// segment 不能为空，`*` 和 `**` 必须是完整的 segment，`**` 只能是最后一个 segment
+fn validate_pattern(pattern: &str) -> Result<(), KvError> {
+    let segments = segments(pattern);
+    let last = segments.len() - 1;
+    for (i, segment) in segments.into_iter().enumerate() {
+        let valid = match segment {
+            "" => false,
+            "*" => true,
+            "**" => i == last,
+            _ => !segment.contains('*'),
+        };
+        if !valid {
+            return Err(KvError::InvalidCommand(format!(
+                "Invalid pattern: {}",
+                pattern
+            )));
+        }
+    }
+    Ok(())
+}
+
+impl Topic for Arc<Broadcaster> {
+    #[instrument(name = "topic_subscribe", skip_all)]
+    fn subscribe(self, name: String, replay: Replay) -> mpsc::Receiver<Arc<CommandResponse>> {
+        let id = get_next_subscription_id();
+        if replay == Replay::None || self.config.retain(&name).is_none() {
+            self.topics.entry(name).or_default().insert(id);
+            return self.add_subscription(id);
+        }
+
+        // 在 log 的锁里加入订阅并复制需要重放的消息，之后 publish 的消息一定会推送给订阅者
+        let log = self.logs.entry(name.clone()).or_default();
+        self.topics.entry(name).or_default().insert(id);
+        let messages = log.replay(replay);
+        self.add_replay_subscription(id, messages, log.last_seq)
     }
 
     #[instrument(name = "topic_unsubscribe", skip_all)]
@@ -77,43 +250,171 @@ impl Topic for Arc<Broadcaster> {
         }
     }
 
+    #[instrument(name = "topic_psubscribe", skip_all)]
+    fn psubscribe(self, pattern: String) -> Result<mpsc::Receiver<Arc<CommandResponse>>, KvError> {
+        validate_pattern(&pattern)?;
+        let id = get_next_subscription_id();
+        self.write_patterns().insert(pattern, id);
+        Ok(self.add_subscription(id))
+    }
+
+    #[instrument(name = "topic_punsubscribe", skip_all)]
+    fn punsubscribe(self, pattern: String, id: u32) -> Result<u32, KvError> {
+        let not_found = || KvError::NotFound(format!("subscription {}", id));
+        if self.read_patterns().pattern(id) != Some(pattern.as_str()) {
+            return Err(not_found());
+        }
+        self.remove_pattern_subscription(id).ok_or_else(not_found)
+    }
+
     #[instrument(name = "topic_publish", skip_all)]
     fn publish(self, name: String, value: Arc<CommandResponse>) {
-        tokio::spawn(async move {
-            let mut ids = vec![];
-            if let Some(topic) = self.topics.get(&name) {
-                // 复制整个 topic 下所有的 subscription id
-                // 这里我们每个 id 是 u32，如果一个 topic 下有 10k 订阅，复制的成本
-                // 也就是 40k 堆内存（外加一些控制结构），所以效率不算差
-                // 这也是为什么我们用 NEXT_ID 来控制 subscription id 的生成
-
-                let subscriptions = topic.value().clone();
-                // 尽快释放锁
-                drop(topic);
-
-                // 循环发送
-                for id in subscriptions.into_iter() {
-                    if let Some(tx) = self.subscriptions.get(&id) {
-                        if let Err(e) = tx.send(value.clone()).await {
-                            warn!("Publish to {} failed! error: {:?}", id, e);
-                            // client 中断连接
-                            ids.push(id);
-                        }
-                    }
-                }
+        // 持久化的主题在发布时就分配序号，并且在 log 的锁里推送，
+        // 同时 publish 的消息也按照序号的顺序到达订阅者
+        let (value, log) = match self.config.retain(&name) {
+            Some(retain) => {
+                let mut log = self.logs.entry(name.clone()).or_default();
+                (log.append(value, retain), Some(log))
             }
-            for id in ids {
+            None => (value, None),
+        };
+
+        if let Some(topic) = self.topics.get(&name) {
+            // 复制整个 topic 下所有的 subscription id
+            // 这里我们每个 id 是 u32，如果一个 topic 下有 10k 订阅，复制的成本
+            // 也就是 40k 堆内存（外加一些控制结构），所以效率不算差
+            // 这也是为什么我们用 NEXT_ID 来控制 subscription id 的生成
+
+            let subscriptions = topic.value().clone();
+            // 尽快释放锁
+            drop(topic);
+
+            for id in self.deliver(subscriptions, &value) {
                 self.remove_subscription(name.clone(), id);
             }
-        });
+        }
+
+        // pattern 订阅收到的数据需要带上主题的名字
+        let matched = self.read_patterns().matches(&name);
+        if !matched.is_empty() {
+            let mut res = value.as_ref().clone();
+            res.topic = name;
+            let value = Arc::new(res);
+
+            for id in self.deliver(matched, &value) {
+                self.remove_pattern_subscription(id);
+            }
+        }
+        drop(log);
     }
 }
 
 impl Broadcaster {
+    pub fn new(config: TopicsConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// 当前有订阅者的 topic 数量
+    pub fn topic_count(&self) -> usize {
+        self.topics.len()
+    }
+
+    /// 当前的订阅数量（包括 pattern 订阅）
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    /// 当前的 pattern 订阅数量
+    pub fn pattern_count(&self) -> usize {
+        self.read_patterns().patterns.len()
+    }
+
+    /// 把数据推送给订阅者，不会等待订阅者，返回需要删除的订阅
+    fn deliver(
+        &self,
+        ids: impl IntoIterator<Item = u32>,
+        value: &Arc<CommandResponse>,
+    ) -> Vec<u32> {
+        let mut failed = vec![];
+        for id in ids {
+            // 复制出 subscriber，避免删除订阅时持有 DashMap 的锁
+            let Some(subscriber) = self.subscriptions.get(&id).map(|v| v.clone()) else {
+                continue;
+            };
+            match subscriber.send(value.clone()) {
+                Ok(()) => {}
+                Err(SendError::Closed) => {
+                    // client 中断连接
+                    warn!("Publish to {} failed! subscriber is closed", id);
+                    failed.push(id);
+                }
+                Err(SendError::TooSlow) => {
+                    warn!("Subscriber {} is too slow, disconnecting", id);
+                    subscriber.disconnect(id);
+                    failed.push(id);
+                }
+            }
+        }
+        failed
+    }
+
+    /// 生成订阅的 channel，subscription id 是订阅者收到的第一个数据
+    fn add_subscription(&self, id: u32) -> mpsc::Receiver<Arc<CommandResponse>> {
+        let v: Value = (id as i64).into();
+        let (subscriber, rx) = Subscriber::new(self.config.backpressure, Some(Arc::new(v.into())));
+
+        // 把 subscriber 存入 subscription table
+        self.subscriptions.insert(id, Arc::new(subscriber));
+        debug!("Subscription {} is added", id);
+
+        // 返回 rx 给网络处理的上下文
+        rx
+    }
+
+    /// 先推送 subscription id 和重放的消息，再推送新的消息
+    /// 重放时可能有 publish 的消息同时推送过来，序号不超过 last_seq 的已经重放过，直接丢弃，
+    /// 没有序号的 response（比如订阅被断开的通知）总是推送
+    fn add_replay_subscription(
+        &self,
+        id: u32,
+        messages: Vec<Arc<CommandResponse>>,
+        last_seq: u64,
+    ) -> mpsc::Receiver<Arc<CommandResponse>> {
+        let (live, mut live_rx) = Subscriber::new(self.config.backpressure, None);
+        let (tx, rx) = mpsc::channel(BROADCAST_CAPACITY);
+
+        let v: Value = (id as i64).into();
+        tokio::spawn(async move {
+            for res in iter::once(Arc::new(v.into())).chain(messages) {
+                if tx.send(res).await.is_err() {
+                    // 订阅者断开后 live_rx 被 drop，下次 publish 时删除订阅
+                    return;
+                }
+            }
+            while let Some(res) = live_rx.recv().await {
+                let replayed = res.seq != 0 && res.seq <= last_seq;
+                if !replayed && tx.send(res).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        self.subscriptions.insert(id, Arc::new(live));
+        debug!(
+            "Subscription {} is added, replaying to seq {}",
+            id, last_seq
+        );
+        rx
+    }
+
     pub fn remove_subscription(&self, name: String, id: u32) -> Option<u32> {
+        let mut found = false;
         if let Some(v) = self.topics.get_mut(&name) {
             // 在 topics 表里找到 topic 的 subscription id，删除
-            v.remove(&id);
+            found = v.remove(&id).is_some();
 
             // 如果这个 topic 为空，则也删除 topic
             if v.is_empty() {
@@ -123,10 +424,32 @@ impl Broadcaster {
             }
         }
 
+        // 通过 id 取消的也可能是 pattern 订阅，需要从 pattern 的索引中删除
+        if !found {
+            if let Some(pattern) = self.write_patterns().remove(id) {
+                debug!("Pattern {:?} of subscription {} is removed", pattern, id);
+            }
+        }
+
         debug!("Subscription {} is removed!", id);
         // 在 subscription 表中同样删除
         self.subscriptions.remove(&id).map(|(id, _)| id)
     }
+
+    pub fn remove_pattern_subscription(&self, id: u32) -> Option<u32> {
+        if let Some(pattern) = self.write_patterns().remove(id) {
+            debug!("Pattern {:?} of subscription {} is removed", pattern, id);
+        }
+        self.subscriptions.remove(&id).map(|(id, _)| id)
+    }
+
+    fn read_patterns(&self) -> RwLockReadGuard<'_, PatternIndex> {
+        self.patterns.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_patterns(&self) -> RwLockWriteGuard<'_, PatternIndex> {
+        self.patterns.write().unwrap_or_else(|e| e.into_inner())
+    }
 }
 
 #[cfg(test)]
@@ -145,8 +468,8 @@ mod tests {
         let lobby = "lobby".to_string();
 
         // subscribe
-        let mut stream1 = b.clone().subscribe(lobby.clone());
-        let mut stream2 = b.clone().subscribe(lobby.clone());
+        let mut stream1 = b.clone().subscribe(lobby.clone(), Replay::None);
+        let mut stream2 = b.clone().subscribe(lobby.clone(), Replay::None);
 
         // publish
         let v: Value = "hello".into();
@@ -162,11 +485,11 @@ mod tests {
         let res2 = stream2.recv().await.unwrap();
 
         assert_eq!(res1, res2);
-        assert_res_ok(&res1, &[v.clone()], &[]);
+        assert_res_ok(&res1, &[v], &[]);
 
         // 如果 subscriber 取消订阅，则收不到新数据
         let result = b.clone().unsubscribe(lobby.clone(), id1 as _).unwrap();
-        assert_eq!(result, id1 as _);
+        assert_eq!(result, id1 as u32);
 
         // publish
         let v: Value = "world".into();
@@ -174,7 +497,209 @@ mod tests {
 
         assert!(stream1.recv().await.is_none());
         let res2 = stream2.recv().await.unwrap();
-        assert_res_ok(&res2, &[v.clone()], &[]);
+        assert_res_ok(&res2, &[v], &[]);
+    }
+
+    #[test]
+    fn pattern_index_should_match_topics() {
+        let mut index = PatternIndex::default();
+        index.insert("orders.*.created".into(), 1);
+        index.insert("orders.**".into(), 2);
+        index.insert("orders.eu.created".into(), 3);
+        index.insert("*".into(), 4);
+
+        assert_eq!(matches(&index, "orders.eu.created"), vec![1, 2, 3]);
+        assert_eq!(matches(&index, "orders.us.created"), vec![1, 2]);
+        assert_eq!(matches(&index, "orders.us.deleted"), vec![2]);
+        // `**` 也匹配零个 segment
+        assert_eq!(matches(&index, "orders"), vec![2, 4]);
+        assert_eq!(matches(&index, "users.created"), Vec::<u32>::new());
+
+        // 删除之后不再匹配，空的节点也被删除
+        assert_eq!(index.remove(1), Some("orders.*.created".into()));
+        assert_eq!(matches(&index, "orders.us.created"), vec![2]);
+        index.remove(2);
+        index.remove(3);
+        index.remove(4);
+        assert!(index.root.is_empty());
+        assert!(index.remove(1).is_none());
+    }
+
+    #[tokio::test]
+    async fn psubscribe_should_receive_matched_topics() {
+        let b = Arc::new(Broadcaster::default());
+        let mut stream = b.clone().psubscribe("news.*".into()).unwrap();
+        let id = get_id(&mut stream).await;
+        assert_eq!(b.pattern_count(), 1);
+
+        let v: Value = "hello".into();
+        b.clone()
+            .publish("sports".into(), Arc::new(v.clone().into()));
+        b.clone()
+            .publish("news.tech".into(), Arc::new(v.clone().into()));
+
+        // 只收到匹配的主题，topic 中带上主题的名字
+        let res = stream.recv().await.unwrap();
+        assert_eq!(res.topic, "news.tech");
+        assert_eq!(res.message, "");
+        assert_eq!(res.values, std::slice::from_ref(&v));
+
+        // 订阅者断开后，下次 publish 时删除订阅
+        drop(stream);
+        b.clone().publish("news.tech".into(), Arc::new(v.into()));
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(b.pattern_count(), 0);
+        assert!(b.punsubscribe("news.*".into(), id).is_err());
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_pattern_by_id_should_remove_it_from_index() {
+        let b = Arc::new(Broadcaster::default());
+        let mut stream = b.clone().psubscribe("news.*".into()).unwrap();
+        let id = get_id(&mut stream).await;
+
+        assert_eq!(b.clone().unsubscribe("news.*".into(), id).unwrap(), id);
+        assert_eq!(b.pattern_count(), 0);
+        assert!(b.read_patterns().root.is_empty());
+        assert_eq!(b.subscription_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn durable_topic_should_replay_messages() {
+        let config = TopicsConfig {
+            retain: HashMap::from([("orders".to_string(), 3)]),
+            ..Default::default()
+        };
+        let b = Arc::new(Broadcaster::new(config));
+        for i in 1..=5 {
+            let v: Value = i.into();
+            b.clone()
+                .publish("orders".into(), Arc::new(v.clone().into()));
+            b.clone().publish("lobby".into(), Arc::new(v.into()));
+        }
+
+        // 只保留了最后 3 条消息
+        let mut stream = b.clone().subscribe("orders".into(), Replay::From(1));
+        get_id(&mut stream).await;
+        for seq in 3..=5 {
+            let res = stream.recv().await.unwrap();
+            assert_eq!(res.seq, seq);
+            assert_res_ok(&res, &[(seq as i64).into()], &[]);
+        }
+
+        let mut last = b.clone().subscribe("orders".into(), Replay::Last(1));
+        get_id(&mut last).await;
+        assert_eq!(last.recv().await.unwrap().seq, 5);
+
+        // 重放之后继续收到新的消息
+        let v: Value = 6.into();
+        b.clone()
+            .publish("orders".into(), Arc::new(v.clone().into()));
+        assert_eq!(stream.recv().await.unwrap().seq, 6);
+        assert_eq!(last.recv().await.unwrap().seq, 6);
+
+        // 不是持久化的主题没有可以重放的消息
+        let mut lobby = b.clone().subscribe("lobby".into(), Replay::From(1));
+        get_id(&mut lobby).await;
+        b.clone().publish("lobby".into(), Arc::new(v.into()));
+        let res = lobby.recv().await.unwrap();
+        assert_eq!(res.seq, 0);
+        assert_res_ok(&res, &[6.into()], &[]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_publish_should_deliver_in_seq_order() {
+        let config = TopicsConfig {
+            retain: HashMap::from([("orders".to_string(), 10)]),
+            ..Default::default()
+        };
+        let b = Arc::new(Broadcaster::new(config));
+        let mut stream = b.clone().subscribe("orders".into(), Replay::None);
+        let mut pattern = b.clone().psubscribe("orders.**".into()).unwrap();
+        get_id(&mut stream).await;
+        get_id(&mut pattern).await;
+
+        let publishers: Vec<_> = (0..4)
+            .map(|_| {
+                let b = b.clone();
+                tokio::spawn(async move {
+                    for i in 0..200 {
+                        let v: Value = i.into();
+                        b.clone().publish("orders".into(), Arc::new(v.into()));
+                    }
+                })
+            })
+            .collect();
+        for publisher in publishers {
+            publisher.await.unwrap();
+        }
+
+        for rx in [&mut stream, &mut pattern] {
+            for seq in 1..=800 {
+                assert_eq!(rx.recv().await.unwrap().seq, seq);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_subscriber_should_be_told_when_disconnected() {
+        let config = TopicsConfig {
+            retain: HashMap::from([("orders".to_string(), 10)]),
+            backpressure: crate::Backpressure::Disconnect,
+        };
+        let b = Arc::new(Broadcaster::new(config));
+        let v: Value = 0.into();
+        b.clone()
+            .publish("orders".into(), Arc::new(v.clone().into()));
+
+        let mut stream = b.clone().subscribe("orders".into(), Replay::From(1));
+        get_id(&mut stream).await;
+        // 不读取数据，订阅者的 channel 满了之后被断开
+        for _ in 0..BROADCAST_CAPACITY * 3 {
+            b.clone()
+                .publish("orders".into(), Arc::new(v.clone().into()));
+        }
+        assert_eq!(b.subscription_count(), 0);
+
+        let mut last = None;
+        while let Some(res) = stream.recv().await {
+            last = Some(res);
+        }
+        assert_eq!(last.unwrap().status, 410);
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_should_not_block_others() {
+        let config = TopicsConfig {
+            backpressure: crate::Backpressure::Disconnect,
+            ..Default::default()
+        };
+        let b = Arc::new(Broadcaster::new(config));
+        let lobby = "lobby".to_string();
+        let mut slow = b.clone().subscribe(lobby.clone(), Replay::None);
+        let mut fast = b.clone().subscribe(lobby.clone(), Replay::None);
+        get_id(&mut slow).await;
+        get_id(&mut fast).await;
+
+        let v: Value = "hello".into();
+        for _ in 0..BROADCAST_CAPACITY + 1 {
+            b.clone().publish(lobby.clone(), Arc::new(v.clone().into()));
+            assert_res_ok(&fast.recv().await.unwrap(), std::slice::from_ref(&v), &[]);
+        }
+
+        // 慢的订阅者被删除，处理完之前的数据后收到错误
+        assert_eq!(b.subscription_count(), 1);
+        for _ in 0..BROADCAST_CAPACITY {
+            assert_eq!(slow.recv().await.unwrap().status, 200);
+        }
+        assert_eq!(slow.recv().await.unwrap().status, 410);
+        assert!(slow.recv().await.is_none());
+    }
+
+    fn matches(index: &PatternIndex, name: &str) -> Vec<u32> {
+        let mut ids: Vec<_> = index.matches(name).into_iter().collect();
+        ids.sort();
+        ids
     }
 
     pub async fn get_id(res: &mut Receiver<Arc<CommandResponse>>) -> u32 {
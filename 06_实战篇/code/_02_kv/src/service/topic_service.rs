@@ -2,7 +2,9 @@ use futures::{stream, Stream};
 use std::{pin::Pin, sync::Arc};
 use tokio_stream::wrappers::ReceiverStream;
 
-use crate::{CommandResponse, Publish, Subscribe, Topic, Unsubscribe};
+use crate::{
+    CommandResponse, Psubscribe, Publish, Punsubscribe, Replay, Subscribe, Topic, Unsubscribe,
+};
 
 pub type StreamingResponse = Pin<Box<dyn Stream<Item = Arc<CommandResponse>> + Send>>;
 
@@ -13,7 +15,13 @@ pub trait TopicService {
 
 impl TopicService for Subscribe {
     fn execute(self, topic: impl Topic) -> StreamingResponse {
-        let rx = topic.subscribe(self.topic);
+        // from_seq 优先于 last_n
+        let replay = match (self.from_seq, self.last_n) {
+            (0, 0) => Replay::None,
+            (0, n) => Replay::Last(n),
+            (seq, _) => Replay::From(seq),
+        };
+        let rx = topic.subscribe(self.topic, replay);
         Box::pin(ReceiverStream::new(rx))
     }
 }
@@ -28,6 +36,25 @@ impl TopicService for Unsubscribe {
     }
 }
 
+impl TopicService for Psubscribe {
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        match topic.psubscribe(self.pattern) {
+            Ok(rx) => Box::pin(ReceiverStream::new(rx)),
+            Err(e) => Box::pin(stream::once(async { Arc::new(e.into()) })),
+        }
+    }
+}
+
+impl TopicService for Punsubscribe {
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        let res = match topic.punsubscribe(self.pattern, self.id) {
+            Ok(_) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        };
+        Box::pin(stream::once(async { Arc::new(res) }))
+    }
+}
+
 impl TopicService for Publish {
     fn execute(self, topic: impl Topic) -> StreamingResponse {
         topic.publish(self.topic, Arc::new(self.data.into()));
@@ -74,7 +101,7 @@ mod tests {
 
         // publish 时，这个 subscription 已经失效，所以会被删除
         let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
-        dispatch_stream(cmd, topic.clone());
+        let _ = dispatch_stream(cmd, topic.clone());
         time::sleep(Duration::from_millis(10)).await;
 
         // 如果再尝试删除，应该返回 KvError
@@ -107,6 +134,39 @@ mod tests {
         assert_res_error(&data, 404, "Not found: subscription 9527");
     }
 
+    #[tokio::test]
+    async fn dispatch_psubscribe_should_work() {
+        let topic = Arc::new(Broadcaster::default());
+        let cmd = CommandRequest::new_psubscribe("orders.*.created");
+        let mut res = dispatch_stream(cmd, topic.clone());
+        let id = get_id(&mut res).await;
+
+        let cmd = CommandRequest::new_publish("orders.eu.created", vec!["hello".into()]);
+        let _ = dispatch_stream(cmd, topic.clone());
+        let data = res.next().await.unwrap();
+        assert_eq!(data.topic, "orders.eu.created");
+        assert_eq!(data.values, &["hello".into()]);
+
+        // pattern 不一致时无法取消订阅
+        let cmd = CommandRequest::new_punsubscribe("orders.**", id);
+        let mut res = dispatch_stream(cmd, topic.clone());
+        assert_res_error(&res.next().await.unwrap(), 404, "subscription");
+
+        let cmd = CommandRequest::new_punsubscribe("orders.*.created", id);
+        let mut res = dispatch_stream(cmd, topic);
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+    }
+
+    #[tokio::test]
+    async fn dispatch_psubscribe_invalid_pattern_should_error() {
+        let topic = Arc::new(Broadcaster::default());
+        for pattern in ["orders.**.created", "orders..created", "orders.eu*"] {
+            let cmd = CommandRequest::new_psubscribe(pattern);
+            let mut res = dispatch_stream(cmd, topic.clone());
+            assert_res_error(&res.next().await.unwrap(), 400, "Invalid pattern");
+        }
+    }
+
     pub async fn get_id(res: &mut StreamingResponse) -> u32 {
         let id: i64 = res.next().await.unwrap().as_ref().try_into().unwrap();
         id as u32
@@ -1,10 +1,28 @@
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
-use dashmap::{mapref::one::Ref, DashMap};
+use crate::{KvError, Kvpair, Storage, StorageIter, TxEntry, Value};
+use dashmap::{
+    mapref::{entry::Entry, one::Ref},
+    DashMap,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::Bound,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
+};
+
+use super::{deadline_ms, now_ms};
 
 /// 使用 DashMap 构建的 MemTable，实现了 Storage trait
-#[derive(Clone, Debug, Default)]
+/// clone 会复制所有的数据，得到一个独立的 MemTable
+#[derive(Debug, Default)]
 pub struct MemTable {
     tables: DashMap<String, DashMap<String, Value>>,
+    /// 设置了过期时间的 key，value 是过期时刻（UNIX 时间戳，毫秒）
+    expires: DashMap<String, DashMap<String, u64>>,
+    /// 每个 table 里有序的 key，用于按顺序遍历和分页
+    index: DashMap<String, BTreeSet<String>>,
+    /// 事务锁：事务执行时持有写锁，其它操作持有读锁
+    lock: RwLock<()>,
 }
 
 impl MemTable {
@@ -14,7 +32,7 @@ impl MemTable {
     }
 
     /// 如果名为 name 的 hash table 不存在，则创建，否则返回
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<'_, String, DashMap<String, Value>> {
         match self.tables.get(name) {
             Some(table) => table,
             None => {
@@ -23,42 +41,422 @@ impl MemTable {
             }
         }
     }
+
+    /// 如果 key 已经过期，则把它删除，返回 key 是否过期
+    /// 持有 key 在 table 中的 entry 锁再检查和删除，同时写入这个 key 的操作要等待，不会删掉新的 value
+    fn remove_if_expired(&self, table: &str, key: &str) -> bool {
+        let now = now_ms();
+        let is_expired = || self.get_expire(table, key).is_some_and(|at| at <= now);
+        if !is_expired() {
+            return false;
+        }
+
+        let Some(t) = self.tables.get(table) else {
+            self.remove_expire(table, key);
+            return false;
+        };
+        let entry = t.entry(key.into());
+        match entry {
+            Entry::Occupied(entry) if is_expired() => {
+                self.remove_expire(table, key);
+                self.index_remove(table, key);
+                entry.remove();
+                true
+            }
+            Entry::Occupied(_) => false,
+            // 没有 value 的过期时刻是无效的，直接删除
+            Entry::Vacant(_) => {
+                self.remove_expire(table, key);
+                false
+            }
+        }
+    }
+
+    /// 获取 key 的 value，调用者需要持有锁
+    fn get_value(&self, table: &str, key: &str) -> Option<Value> {
+        self.remove_if_expired(table, key);
+        let table = self.get_or_create_table(table);
+        table.get(key).map(|v| v.value().clone())
+    }
+
+    /// 设置 key 的 value，调用者需要持有锁
+    fn set_value(&self, table: &str, key: String, value: Value) -> Option<Value> {
+        self.remove_if_expired(table, &key);
+        let t = self.get_or_create_table(table);
+        // 和 remove_if_expired 一样，在 entry 锁里修改过期时间和索引
+        let entry = t.entry(key);
+        match entry {
+            Entry::Occupied(mut entry) => {
+                self.remove_expire(table, entry.key());
+                Some(entry.insert(value))
+            }
+            Entry::Vacant(entry) => {
+                self.remove_expire(table, entry.key());
+                self.index_insert(table, entry.key());
+                entry.insert(value);
+                None
+            }
+        }
+    }
+
+    /// 删除 key，调用者需要持有锁
+    fn del_value(&self, table: &str, key: &str) -> Option<Value> {
+        if self.remove_if_expired(table, key) {
+            return None;
+        }
+        let t = self.get_or_create_table(table);
+        let entry = t.entry(key.into());
+        match entry {
+            Entry::Occupied(entry) => {
+                self.remove_expire(table, key);
+                self.index_remove(table, key);
+                Some(entry.remove())
+            }
+            Entry::Vacant(_) => None,
+        }
+    }
+
+    /// 设置 key 的过期时刻（UNIX 时间戳，毫秒），key 不存在时返回 false
+    pub(crate) fn expire_at(&self, table: &str, key: &str, at: u64) -> bool {
+        let _guard = self.read_lock();
+        if self.remove_if_expired(table, key) || !self.get_or_create_table(table).contains_key(key)
+        {
+            return false;
+        }
+
+        self.set_expire(table, key, at);
+        true
+    }
+
+    /// 获取 key 的过期时刻，调用者需要持有锁
+    pub(crate) fn get_expire(&self, table: &str, key: &str) -> Option<u64> {
+        self.expires
+            .get(table)
+            .and_then(|expires| expires.get(key).map(|v| *v))
+    }
+
+    /// 设置 key 的过期时刻，调用者需要持有锁
+    fn set_expire(&self, table: &str, key: &str, at: u64) {
+        self.expires
+            .entry(table.into())
+            .or_default()
+            .insert(key.into(), at);
+    }
+
+    /// 导出所有的数据：(table, kv pair, 过期时刻)，用于生成 snapshot
+    pub(crate) fn dump(&self) -> Vec<(String, Kvpair, Option<u64>)> {
+        let _guard = self.read_lock();
+        let now = now_ms();
+        let mut result = Vec::new();
+        for table in self.tables.iter() {
+            let expires = self.copy_expires(table.key());
+            for entry in table.value().iter() {
+                let at = expires.get(entry.key()).copied();
+                if at.is_some_and(|at| at <= now) {
+                    continue;
+                }
+                let pair = Kvpair::new(entry.key(), entry.value().clone());
+                result.push((table.key().clone(), pair, at));
+            }
+        }
+        result
+    }
+
+    // 锁里只有 ()，被 poison 也不影响数据，所以直接忽略
+    fn read_lock(&self) -> RwLockReadGuard<'_, ()> {
+        self.lock.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_lock(&self) -> RwLockWriteGuard<'_, ()> {
+        self.lock.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // 索引只在这几个函数里短暂地加锁，不会在持有索引锁的时候去锁其它的 map，避免死锁
+    fn index_insert(&self, table: &str, key: &str) {
+        if let Some(mut index) = self.index.get_mut(table) {
+            if !index.contains(key) {
+                index.insert(key.into());
+            }
+            return;
+        }
+        self.index
+            .entry(table.into())
+            .or_default()
+            .insert(key.into());
+    }
+
+    fn index_remove(&self, table: &str, key: &str) {
+        if let Some(mut index) = self.index.get_mut(table) {
+            index.remove(key);
+        }
+    }
+
+    /// 从索引里按顺序取出从 start 开始、以 prefix 开头的最多 limit 个 key
+    fn index_keys(
+        &self,
+        table: &str,
+        prefix: &str,
+        start: Bound<&str>,
+        limit: usize,
+    ) -> Vec<String> {
+        match self.index.get(table) {
+            Some(index) => index
+                .range::<str, _>((start, Bound::Unbounded))
+                .take_while(|k| k.starts_with(prefix))
+                .take(limit)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// 复制 table 中所有 key 的过期时刻。
+    /// 遍历 table 时不能持有 expires 的锁：删除过期的 key 时是先锁 table 再锁 expires，反过来会死锁
+    fn copy_expires(&self, table: &str) -> HashMap<String, u64> {
+        self.expires
+            .get(table)
+            .map(|e| e.iter().map(|v| (v.key().clone(), *v.value())).collect())
+            .unwrap_or_default()
+    }
+
+    /// 清除 key 的过期时间，返回之前的过期时刻
+    fn remove_expire(&self, table: &str, key: &str) -> Option<u64> {
+        self.expires
+            .get(table)
+            .and_then(|expires| expires.remove(key).map(|(_k, at)| at))
+    }
+}
+
+impl Clone for MemTable {
+    fn clone(&self) -> Self {
+        // 持有读锁，不会复制到执行了一半的事务
+        let _guard = self.read_lock();
+        Self {
+            tables: self.tables.clone(),
+            expires: self.expires.clone(),
+            index: self.index.clone(),
+            lock: RwLock::default(),
+        }
+    }
 }
 
 impl Storage for MemTable {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.value().clone()))
+        let _guard = self.read_lock();
+        Ok(self.get_value(table, key))
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.insert(key, value))
+        let _guard = self.read_lock();
+        Ok(self.set_value(table, key, value))
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let _guard = self.read_lock();
+        self.remove_if_expired(table, key);
         let table = self.get_or_create_table(table);
         Ok(table.contains_key(key))
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.remove(key).map(|(_k, v)| v))
+        let _guard = self.read_lock();
+        Ok(self.del_value(table, key))
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let _guard = self.read_lock();
+        let now = now_ms();
+        let expires = self.copy_expires(table);
+        let is_expired = |k: &String| expires.get(k).is_some_and(|at| *at <= now);
+
         let table = self.get_or_create_table(table);
         Ok(table
             .iter()
+            .filter(|v| !is_expired(v.key()))
             .map(|v| Kvpair::new(v.key(), v.value().clone()))
             .collect())
     }
 
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let _guard = self.read_lock();
         // 使用 clone() 来获取 table 的 snapshot
-        let table = self.get_or_create_table(table).clone();
-        let iter = StorageIter::new(table.into_iter());
-        Ok(Box::new(iter))
+        let data = self.get_or_create_table(table).clone();
+        let expires = self
+            .expires
+            .get(table)
+            .map(|v| v.clone())
+            .unwrap_or_default();
+
+        // 过滤掉已经过期但还没来得及删除的 key
+        let now = now_ms();
+        let iter = data
+            .into_iter()
+            .filter(move |(k, _)| expires.get(k).is_none_or(|at| *at > now));
+        Ok(Box::new(StorageIter::new(iter)))
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        let _guard = self.read_lock();
+        // 不复制整个 table，已经过期但还没有清理的 key 也会被计算在内
+        Ok(self.tables.get(table).map(|t| t.len()).unwrap_or_default())
+    }
+
+    fn get_tables(&self) -> Result<Vec<String>, KvError> {
+        let _guard = self.read_lock();
+        Ok(self
+            .tables
+            .iter()
+            .filter(|t| !t.value().is_empty())
+            .map(|t| t.key().clone())
+            .collect())
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        prefix: &str,
+        cursor: &str,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let _guard = self.read_lock();
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let mut result = Vec::new();
+        let mut last: Option<String> = None;
+
+        // 已经过期的 key 会被跳过，所以一批 key 不够的话要继续往后取
+        while result.len() < limit {
+            let start = match &last {
+                Some(k) => Bound::Excluded(k.as_str()),
+                None if cursor.is_empty() || cursor < prefix => Bound::Included(prefix),
+                None => Bound::Excluded(cursor),
+            };
+            let wanted = limit - result.len();
+            let keys = self.index_keys(table, prefix, start, wanted);
+            let done = keys.len() < wanted;
+
+            for key in keys {
+                if let Some(v) = self.get_value(table, &key) {
+                    result.push(Kvpair::new(&key, v));
+                }
+                last = Some(key);
+            }
+
+            if done {
+                break;
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn update<F>(&self, table: &str, key: &str, mut f: F) -> Result<Value, KvError>
+    where
+        F: FnMut(Option<&Value>) -> Result<Value, KvError>,
+    {
+        let _guard = self.read_lock();
+        self.remove_if_expired(table, key);
+        let table = self.get_or_create_table(table);
+
+        // entry 会锁住 key 所在的 shard，所以读取和写入之间不会有其他人修改
+        let value = match table.entry(key.into()) {
+            Entry::Occupied(mut entry) => {
+                let value = f(Some(entry.get()))?;
+                entry.insert(value.clone());
+                value
+            }
+            Entry::Vacant(entry) => {
+                let value = f(None)?;
+                entry.insert(value.clone());
+                self.index_insert(table.key(), key);
+                value
+            }
+        };
+        Ok(value)
+    }
+
+    fn transaction<T, F>(&self, keys: &[(String, String)], mut f: F) -> Result<T, KvError>
+    where
+        F: FnMut(&mut [TxEntry]) -> Result<T, KvError>,
+    {
+        // 持有写锁，事务执行期间其它的读写都要等待
+        let _guard = self.write_lock();
+
+        let old: Vec<_> = keys
+            .iter()
+            .map(|(t, k)| TxEntry {
+                value: self.get_value(t, k),
+                expire_at: self.get_expire(t, k),
+            })
+            .collect();
+        let mut values = old.clone();
+        let result = f(&mut values)?;
+
+        for (((table, key), old), new) in keys.iter().zip(old).zip(values) {
+            if old == new {
+                continue;
+            }
+            match new.value {
+                Some(v) => {
+                    self.set_value(table, key.clone(), v);
+                    if let Some(at) = new.expire_at {
+                        self.set_expire(table, key, at);
+                    }
+                }
+                None => {
+                    self.del_value(table, key);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        Ok(self.expire_at(table, key, deadline_ms(ttl)))
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        let _guard = self.read_lock();
+        if self.remove_if_expired(table, key) {
+            return Ok(None);
+        }
+
+        let at = self.get_expire(table, key);
+        Ok(at.map(|at| Duration::from_millis(at.saturating_sub(now_ms()))))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let _guard = self.read_lock();
+        if self.remove_if_expired(table, key) {
+            return Ok(false);
+        }
+        Ok(self.remove_expire(table, key).is_some())
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let _guard = self.read_lock();
+        let now = now_ms();
+
+        // 先找出过期的 key，释放 expires 的锁之后再删除，和其它操作一样先锁 table 再锁 expires
+        let expired: Vec<_> = self
+            .expires
+            .iter()
+            .flat_map(|expires| {
+                let table = expires.key().clone();
+                expires
+                    .value()
+                    .iter()
+                    .filter(|v| *v.value() <= now)
+                    .map(|v| (table.clone(), v.key().clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let count = expired
+            .iter()
+            .filter(|(table, key)| self.remove_if_expired(table, key))
+            .count();
+        Ok(count)
     }
 }
 
@@ -79,4 +477,89 @@ mod tests {
         store.get_or_create_table("t1");
         assert!(store.tables.contains_key("t1"));
     }
+
+    #[test]
+    fn clone_should_copy_all_data() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.expire("t1", "k1", Duration::from_secs(60)).unwrap();
+
+        let cloned = store.clone();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert_eq!(cloned.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(cloned.ttl("t1", "k1").unwrap().is_some());
+        assert!(!cloned.contains("t1", "k2").unwrap());
+        assert_eq!(cloned.scan("t1", "", "", 0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn index_should_follow_table_changes() {
+        let store = MemTable::new();
+        store.set("t1", "b".into(), 1.into()).unwrap();
+        store.set("t1", "a".into(), 1.into()).unwrap();
+        store.update("t1", "c", |_| Ok(1.into())).unwrap();
+        store.del("t1", "b").unwrap();
+        store.set("t1", "d".into(), 1.into()).unwrap();
+        store.expire("t1", "d", Duration::ZERO).unwrap();
+        store.purge_expired().unwrap();
+
+        let keys: Vec<_> = store.index.get("t1").unwrap().iter().cloned().collect();
+        assert_eq!(keys, vec!["a", "c"]);
+    }
+
+    #[test]
+    fn purge_expired_should_not_deadlock_with_get_all() {
+        let store = std::sync::Arc::new(MemTable::new());
+        for i in 0..1000 {
+            store.set("t1", format!("p{}", i), i.into()).unwrap();
+        }
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let mut threads = Vec::new();
+        let s = store.clone();
+        threads.push(std::thread::spawn(move || {
+            for i in 0..500 {
+                for j in 0..64 {
+                    let key = format!("k{}", j);
+                    s.set("t1", key.clone(), i.into()).unwrap();
+                    s.expire("t1", &key, Duration::ZERO).unwrap();
+                }
+                s.purge_expired().unwrap();
+            }
+        }));
+        for _ in 0..4 {
+            let s = store.clone();
+            threads.push(std::thread::spawn(move || {
+                for _ in 0..200 {
+                    s.get_all("t1").unwrap();
+                    s.dump();
+                }
+            }));
+        }
+        std::thread::spawn(move || {
+            threads.into_iter().for_each(|t| t.join().unwrap());
+            tx.send(()).unwrap();
+        });
+
+        rx.recv_timeout(Duration::from_secs(10))
+            .expect("purge_expired and get_all should not deadlock");
+    }
+
+    #[test]
+    fn removing_expired_key_should_not_remove_new_value() {
+        let store = MemTable::new();
+        for i in 0..500 {
+            store.set("t1", "k1".into(), "old".into()).unwrap();
+            store.expire("t1", "k1", Duration::ZERO).unwrap();
+
+            // 一边清理过期的 key，一边写入新的 value，新的 value 不能被删掉
+            std::thread::scope(|s| {
+                s.spawn(|| store.get("t1", "k1").unwrap());
+                s.spawn(|| store.purge_expired().unwrap());
+                s.spawn(|| store.set("t1", "k1".into(), i.into()).unwrap());
+            });
+            assert_eq!(store.get("t1", "k1").unwrap(), Some(i.into()));
+            assert_eq!(store.scan("t1", "", "", 0).unwrap().len(), 1);
+        }
+    }
 }
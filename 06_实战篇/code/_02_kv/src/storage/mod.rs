@@ -1,8 +1,16 @@
 mod memory;
+mod replicated;
 mod sleddb;
+mod wal;
 
 pub use memory::MemTable;
+pub use replicated::{ReplicatedStorage, ReplicationLog};
 pub use sleddb::SledDb;
+pub use wal::WalMemTable;
+
+pub(crate) use wal::{apply_record, diff_records};
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::{KvError, Kvpair, Value};
 
@@ -10,7 +18,7 @@ use crate::{KvError, Kvpair, Value};
 pub trait Storage: Send + Sync + 'static {
     /// 从一个 HashTable 里获取一个 key 的 value
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
-    /// 从一个 HashTable 里设置一个 key 的 value，返回旧的 value
+    /// 从一个 HashTable 里设置一个 key 的 value，返回旧的 value（同时清除 key 的过期时间）
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>;
     /// 查看 HashTable 中是否有 key
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
@@ -18,8 +26,79 @@ pub trait Storage: Send + Sync + 'static {
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
     /// 遍历 HashTable，返回所有 kv pair（这个接口不好）
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
-    /// 遍历 HashTable，返回 kv pair 的 Iterator
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    /// 遍历 HashTable，返回 kv pair 的 Iterator（可以跨线程使用，以便用来生成 Stream）
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError>;
+    /// 返回所有包含数据的 HashTable 的名字
+    fn get_tables(&self) -> Result<Vec<String>, KvError>;
+    /// 按 key 的顺序遍历 HashTable 中以 prefix 开头的 key，从 cursor 之后（不包括 cursor）开始，
+    /// 最多返回 limit 个 kv pair。cursor 为空表示从头开始，limit 为 0 表示不限制个数
+    fn scan(
+        &self,
+        table: &str,
+        prefix: &str,
+        cursor: &str,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError>;
+    /// 原子地更新 HashTable 里一个 key 的 value，返回新的 value（key 的过期时间保持不变）
+    /// f 根据旧的 value 计算出新的 value，返回错误时放弃这次更新
+    /// 发生冲突时 f 可能会被调用多次，所以 f 里不要有副作用
+    fn update<F>(&self, table: &str, key: &str, f: F) -> Result<Value, KvError>
+    where
+        F: FnMut(Option<&Value>) -> Result<Value, KvError>;
+    /// 在一个事务里读取 keys（table, key）当前的 value 和过期时刻交给 f，f 直接在上面修改
+    /// （value 为 None 表示删除），f 成功返回后所有的修改一次性写入，f 返回错误时什么都不修改
+    /// 发生冲突时 f 可能会被调用多次，所以 f 里不要有副作用
+    fn transaction<T, F>(&self, keys: &[(String, String)], f: F) -> Result<T, KvError>
+    where
+        F: FnMut(&mut [TxEntry]) -> Result<T, KvError>;
+    /// 给 HashTable 中的 key 设置存活时间，key 不存在时返回 false
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError>;
+    /// 获取 key 剩余的存活时间，key 不存在或者没有设置过期时间返回 None
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError>;
+    /// 清除 key 的过期时间，返回之前是否设置过
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError>;
+    /// 删除所有已经过期的 key，返回删除的个数
+    fn purge_expired(&self) -> Result<usize, KvError>;
+    /// HashTable 中 key 的个数
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.get_iter(table)?.count())
+    }
+    /// 把还在缓存中的修改写到磁盘，服务器退出前调用，内存存储不需要处理
+    fn flush(&self) -> Result<(), KvError> {
+        Ok(())
+    }
+}
+
+/// 事务中一个 key 的状态
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TxEntry {
+    /// key 的 value，None 表示 key 不存在，改成 None 就是删除
+    pub value: Option<Value>,
+    /// 过期时刻（UNIX 时间戳，毫秒），None 表示不会过期
+    pub expire_at: Option<u64>,
+}
+
+impl From<Option<Value>> for TxEntry {
+    fn from(value: Option<Value>) -> Self {
+        Self {
+            value,
+            expire_at: None,
+        }
+    }
+}
+
+/// 当前的 UNIX 时间戳（毫秒），用于记录 key 的过期时刻
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// ttl 之后的过期时刻（毫秒），ttl 来自客户端，太大时取 u64::MAX 而不是溢出
+pub(crate) fn deadline_ms(ttl: Duration) -> u64 {
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    now_ms().saturating_add(ttl)
 }
 
 /// 提供 Storage iterator，这样 trait 的实现者只需要
@@ -49,9 +128,12 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::{path::Path, thread};
+
     use tempfile::tempdir;
 
     use super::*;
+    use crate::{FsyncPolicy, WalConfig};
 
     #[test]
     fn memtable_basic_interface_should_work() {
@@ -92,6 +174,206 @@ mod tests {
         test_get_iter(store);
     }
 
+    #[test]
+    fn memtable_get_tables_should_work() {
+        let store = MemTable::new();
+        test_get_tables(store);
+    }
+
+    #[test]
+    fn sleddb_get_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_get_tables(store);
+    }
+
+    #[test]
+    fn replicated_basic_interface_should_work() {
+        let store = ReplicatedStorage::new(MemTable::new(), ReplicationLog::default());
+        test_basi_interface(store);
+    }
+
+    #[test]
+    fn replicated_expire_should_work() {
+        let store = ReplicatedStorage::new(MemTable::new(), ReplicationLog::default());
+        test_expire(store);
+    }
+
+    #[test]
+    fn replicated_transaction_should_work() {
+        let store = ReplicatedStorage::new(MemTable::new(), ReplicationLog::default());
+        test_transaction(store);
+    }
+
+    #[test]
+    fn memtable_scan_should_work() {
+        let store = MemTable::new();
+        test_scan(store);
+    }
+
+    #[test]
+    fn sleddb_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_scan(store);
+    }
+
+    #[test]
+    fn wal_memtable_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::new(&wal_config(dir.path())).unwrap();
+        test_scan(store);
+    }
+
+    #[test]
+    fn memtable_expire_should_work() {
+        let store = MemTable::new();
+        test_expire(store);
+    }
+
+    #[test]
+    fn memtable_purge_expired_should_work() {
+        let store = MemTable::new();
+        test_purge_expired(store);
+    }
+
+    #[test]
+    fn sleddb_expire_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_expire(store);
+    }
+
+    #[test]
+    fn sleddb_purge_expired_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_purge_expired(store);
+    }
+
+    #[test]
+    fn sleddb_expired_key_should_not_remove_new_value() {
+        let dir = tempdir().unwrap();
+        let store = std::sync::Arc::new(SledDb::new(dir));
+        for i in 0..100i64 {
+            store.set("t1", "k1".into(), i.into()).unwrap();
+            store.expire("t1", "k1", Duration::ZERO).unwrap();
+
+            // 读的时候会删除过期的 key，它不能删除同时写入的新值
+            let reader = {
+                let store = store.clone();
+                thread::spawn(move || store.get("t1", "k1").unwrap())
+            };
+            store.set("t1", "k1".into(), (-i).into()).unwrap();
+            reader.join().unwrap();
+            assert_eq!(store.get("t1", "k1").unwrap(), Some((-i).into()));
+        }
+    }
+
+    #[test]
+    fn memtable_update_should_work() {
+        let store = MemTable::new();
+        test_update(store);
+    }
+
+    #[test]
+    fn sleddb_update_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_update(store);
+    }
+
+    #[test]
+    fn memtable_transaction_should_work() {
+        let store = MemTable::new();
+        test_transaction(store);
+    }
+
+    #[test]
+    fn sleddb_transaction_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_transaction(store);
+    }
+
+    #[test]
+    fn wal_memtable_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::new(&wal_config(dir.path())).unwrap();
+        test_basi_interface(store);
+    }
+
+    #[test]
+    fn wal_memtable_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::new(&wal_config(dir.path())).unwrap();
+        test_get_all(store);
+    }
+
+    #[test]
+    fn wal_memtable_expire_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::new(&wal_config(dir.path())).unwrap();
+        test_expire(store);
+    }
+
+    #[test]
+    fn wal_memtable_update_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::new(&wal_config(dir.path())).unwrap();
+        test_update(store);
+    }
+
+    #[test]
+    fn wal_memtable_transaction_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::new(&wal_config(dir.path())).unwrap();
+        test_transaction(store);
+    }
+
+    #[test]
+    fn memtable_structured_value_should_work() {
+        let store = MemTable::new();
+        test_structured_value(&store);
+    }
+
+    #[test]
+    fn sleddb_structured_value_should_be_persisted() {
+        let dir = tempdir().unwrap();
+        test_structured_value(&SledDb::new(dir.path()));
+        // 重新打开之后数据还在
+        let store = SledDb::new(dir.path());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(structured_value()));
+    }
+
+    #[test]
+    fn wal_memtable_structured_value_should_be_persisted() {
+        let dir = tempdir().unwrap();
+        test_structured_value(&WalMemTable::new(&wal_config(dir.path())).unwrap());
+        let store = WalMemTable::new(&wal_config(dir.path())).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(structured_value()));
+    }
+
+    fn wal_config(path: &Path) -> WalConfig {
+        WalConfig {
+            path: path.to_string_lossy().into(),
+            fsync: FsyncPolicy::Never,
+            snapshot_threshold: 10,
+        }
+    }
+
+    fn structured_value() -> Value {
+        let json = serde_json::json!({"name": "alice", "tags": ["admin", 1, {"level": 2.5}]});
+        json.into()
+    }
+
+    fn test_structured_value(store: &impl Storage) {
+        store.set("t1", "k1".into(), structured_value()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(structured_value()));
+        let v = store.get("t1", "k1").unwrap().unwrap();
+        assert_eq!(serde_json::Value::from(v)["tags"][2]["level"], 2.5);
+    }
+
     fn test_basi_interface(store: impl Storage) {
         // 第一次 set 会创建 table，插入 key 并返回 None（之前没值）
         let v = store.set("t1", "hello".into(), "world".into());
@@ -147,6 +429,202 @@ mod tests {
                 Kvpair::new("k1", "v1".into()),
                 Kvpair::new("k2", "v2".into())
             ]
-        )
+        );
+        assert_eq!(store.len("t2").unwrap(), 2);
+        assert_eq!(store.len("t3").unwrap(), 0);
+    }
+
+    fn test_get_tables(store: impl Storage) {
+        assert!(store.get_tables().unwrap().is_empty());
+        for table in ["t2", "t1", "t10"] {
+            store.set(table, "k1".into(), "v1".into()).unwrap();
+            store.set(table, "k2".into(), "v2".into()).unwrap();
+        }
+        store.del("t10", "k1").unwrap();
+        store.del("t10", "k2").unwrap();
+
+        let mut tables = store.get_tables().unwrap();
+        tables.sort();
+        assert_eq!(tables, vec!["t1", "t2"]);
+    }
+
+    fn test_scan(store: impl Storage) {
+        for key in ["b2", "a1", "c1", "b1", "a2", "b3"] {
+            store.set("t1", key.into(), key.into()).unwrap();
+        }
+        // 其它 table 的数据不应该被扫描到
+        store.set("t0", "a0".into(), "a0".into()).unwrap();
+        store.set("t2", "a0".into(), "a0".into()).unwrap();
+        let keys = |pairs: Vec<Kvpair>| pairs.into_iter().map(|p| p.key).collect::<Vec<_>>();
+
+        // 分页遍历
+        let page = store.scan("t1", "", "", 4).unwrap();
+        assert_eq!(keys(page), vec!["a1", "a2", "b1", "b2"]);
+        let page = store.scan("t1", "", "b2", 4).unwrap();
+        assert_eq!(keys(page), vec!["b3", "c1"]);
+        let page = store.scan("t1", "", "c1", 4).unwrap();
+        assert!(page.is_empty());
+        assert_eq!(store.scan("t1", "", "", 0).unwrap().len(), 6);
+
+        // 前缀查询
+        let pairs = store.scan("t1", "b", "", 0).unwrap();
+        assert_eq!(keys(pairs), vec!["b1", "b2", "b3"]);
+        let pairs = store.scan("t1", "b", "b1", 1).unwrap();
+        assert_eq!(keys(pairs), vec!["b2"]);
+        let pairs = store.scan("t1", "b", "a", 0).unwrap();
+        assert_eq!(keys(pairs), vec!["b1", "b2", "b3"]);
+        assert!(store.scan("t1", "d", "", 0).unwrap().is_empty());
+        assert!(store.scan("t3", "", "", 0).unwrap().is_empty());
+
+        // 删除和过期的 key 会被跳过
+        store.del("t1", "a2").unwrap();
+        store.expire("t1", "b1", Duration::ZERO).unwrap();
+        let page = store.scan("t1", "", "", 2).unwrap();
+        assert_eq!(keys(page), vec!["a1", "b2"]);
+        let pairs = store.scan("t1", "b", "", 0).unwrap();
+        assert_eq!(pairs[0], Kvpair::new("b2", "b2".into()));
+    }
+
+    fn test_expire(store: impl Storage) {
+        // 不存在的 key 无法设置过期时间
+        assert!(!store.expire("t1", "k1", Duration::from_secs(1)).unwrap());
+
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert!(store.ttl("t1", "k1").unwrap().is_none());
+
+        assert!(store.expire("t1", "k1", Duration::from_millis(50)).unwrap());
+        let ttl = store.ttl("t1", "k1").unwrap().unwrap();
+        assert!(ttl <= Duration::from_millis(50));
+
+        // persist 之后 key 不再过期
+        assert!(store.expire("t1", "k2", Duration::from_millis(50)).unwrap());
+        assert!(store.persist("t1", "k2").unwrap());
+        assert!(!store.persist("t1", "k2").unwrap());
+
+        thread::sleep(Duration::from_millis(60));
+
+        // 过期的 key 读不到了
+        assert!(store.get("t1", "k1").unwrap().is_none());
+        assert!(!store.contains("t1", "k1").unwrap());
+        assert!(store.ttl("t1", "k1").unwrap().is_none());
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+
+        // 重新 set 会清除过期时间
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        store.expire("t1", "k3", Duration::from_millis(10)).unwrap();
+        store.set("t1", "k3".into(), "v4".into()).unwrap();
+        assert!(store.ttl("t1", "k3").unwrap().is_none());
+
+        // 很大的 ttl 不会溢出，key 不会立即过期
+        let ttl = Duration::from_millis(u64::MAX);
+        assert!(store.expire("t1", "k3", ttl).unwrap());
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v4".into()));
+        assert!(store.ttl("t1", "k3").unwrap().unwrap() > Duration::from_secs(86400));
+    }
+
+    fn test_purge_expired(store: impl Storage) {
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), "v2".into()).unwrap();
+        store.expire("t2", "k1", Duration::from_millis(10)).unwrap();
+        store.expire("t2", "k2", Duration::from_secs(60)).unwrap();
+
+        // get_all / get_iter 都不应该返回过期的 key
+        thread::sleep(Duration::from_millis(20));
+        let data = store.get_all("t2").unwrap();
+        assert_eq!(data, vec![Kvpair::new("k2", "v2".into())]);
+        let data: Vec<_> = store.get_iter("t2").unwrap().collect();
+        assert_eq!(data, vec![Kvpair::new("k2", "v2".into())]);
+
+        assert_eq!(store.purge_expired().unwrap(), 1);
+        assert_eq!(store.purge_expired().unwrap(), 0);
+        assert!(store.ttl("t2", "k2").unwrap().is_some());
+    }
+
+    fn test_update(store: impl Storage) {
+        let incr = |v: Option<&Value>| {
+            let i: i64 = v.map(|v| v.try_into()).transpose()?.unwrap_or_default();
+            Ok((i + 1).into())
+        };
+
+        // key 不存在时 f 拿到的是 None
+        assert_eq!(store.update("t1", "k1", incr).unwrap(), 1.into());
+        assert_eq!(store.update("t1", "k1", incr).unwrap(), 2.into());
+
+        // f 出错时不更新
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert!(store.update("t1", "k2", incr).is_err());
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+
+        // 多个线程同时更新，结果不会丢失
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        store.update("t1", "k1", incr).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(202.into()));
+    }
+
+    fn test_transaction(store: impl Storage) {
+        store.set("t1", "a".into(), 100.into()).unwrap();
+        store.set("t1", "b".into(), 0.into()).unwrap();
+        let keys = vec![
+            ("t1".to_string(), "a".to_string()),
+            ("t1".to_string(), "b".to_string()),
+            ("t1".to_string(), "c".to_string()),
+        ];
+
+        // 从 a 转 10 到 b，同时删除 a 以外的 c（不存在）
+        let result = store.transaction(&keys, |values| {
+            let a: i64 = values[0].value.as_ref().unwrap().try_into()?;
+            let b: i64 = values[1].value.as_ref().unwrap().try_into()?;
+            assert!(values[2].value.is_none());
+            values[0].value = Some((a - 10).into());
+            values[1].value = Some((b + 10).into());
+            Ok(a + b)
+        });
+        assert_eq!(result.unwrap(), 100);
+        assert_eq!(store.get("t1", "a").unwrap(), Some(90.into()));
+        assert_eq!(store.get("t1", "b").unwrap(), Some(10.into()));
+
+        // f 出错时，所有修改都不生效
+        let result: Result<(), _> = store.transaction(&keys, |values| {
+            values[0].value = None;
+            values[2].value = Some("c".into());
+            Err(KvError::Internal("abort".into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(store.get("t1", "a").unwrap(), Some(90.into()));
+        assert!(!store.contains("t1", "c").unwrap());
+
+        // 过期时间和 value 一起读写
+        store.expire("t1", "b", Duration::from_secs(60)).unwrap();
+        store
+            .transaction(&keys, |values| {
+                assert!(values[0].expire_at.is_none());
+                assert!(values[1].expire_at.unwrap() > now_ms());
+                values[0].expire_at = values[1].expire_at;
+                values[1].expire_at = None;
+                values[1].value = Some(20.into());
+                Ok(())
+            })
+            .unwrap();
+        assert!(store.ttl("t1", "a").unwrap().is_some());
+        assert!(store.ttl("t1", "b").unwrap().is_none());
+        assert_eq!(store.get("t1", "b").unwrap(), Some(20.into()));
+
+        // 删除
+        store
+            .transaction(&keys[..1], |values| {
+                values[0].value = None;
+                Ok(())
+            })
+            .unwrap();
+        assert!(!store.contains("t1", "a").unwrap());
+        assert!(store.ttl("t1", "a").unwrap().is_none());
     }
 }
@@ -0,0 +1,271 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+use tokio::sync::broadcast;
+
+use super::{deadline_ms, diff_records, now_ms};
+use crate::{
+    pb::wal::{ReplicationFrame, WalRecord},
+    KvError, Kvpair, Storage, TxEntry, Value,
+};
+
+/// 最多缓存多少个还没有发给从节点的 frame，从节点落后太多时需要重新全量同步
+const REPLICATION_BACKLOG: usize = 4096;
+
+/// 主节点上的修改日志，每次修改都会生成一个 ReplicationFrame 广播给所有的从节点
+#[derive(Debug, Clone)]
+pub struct ReplicationLog {
+    inner: Arc<LogInner>,
+}
+
+#[derive(Debug)]
+struct LogInner {
+    tx: broadcast::Sender<Arc<ReplicationFrame>>,
+    /// 已经产生的修改的序号
+    seq: AtomicU64,
+}
+
+impl Default for ReplicationLog {
+    fn default() -> Self {
+        let (tx, _rx) = broadcast::channel(REPLICATION_BACKLOG);
+        Self {
+            inner: Arc::new(LogInner {
+                tx,
+                seq: AtomicU64::new(0),
+            }),
+        }
+    }
+}
+
+impl ReplicationLog {
+    /// 当前修改的序号
+    pub fn seq(&self) -> u64 {
+        self.inner.seq.load(Ordering::Acquire)
+    }
+
+    /// 当前连接的从节点的个数
+    pub fn replicas(&self) -> usize {
+        self.inner.tx.receiver_count()
+    }
+
+    /// 订阅之后的所有修改
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Arc<ReplicationFrame>> {
+        self.inner.tx.subscribe()
+    }
+
+    /// 生成一个不包含修改的 frame，用作心跳
+    pub(crate) fn heartbeat(&self) -> ReplicationFrame {
+        ReplicationFrame {
+            seq: self.seq(),
+            timestamp: now_ms(),
+            snapshot: false,
+            records: vec![],
+        }
+    }
+
+    fn append(&self, record: WalRecord) {
+        let seq = self.inner.seq.fetch_add(1, Ordering::AcqRel) + 1;
+        let frame = ReplicationFrame {
+            seq,
+            timestamp: now_ms(),
+            snapshot: false,
+            records: vec![record],
+        };
+        // 没有从节点时发送会失败，直接忽略
+        let _ = self.inner.tx.send(Arc::new(frame));
+    }
+}
+
+/// 把所有的修改记录到 ReplicationLog 的 Storage，主节点用它来给从节点同步数据
+#[derive(Debug)]
+pub struct ReplicatedStorage<S> {
+    store: S,
+    log: ReplicationLog,
+    // 修改和写日志在一个锁里完成，保证日志里的顺序和实际修改的顺序一致。
+    // 这是有意的取舍：主节点上所有的写入（包括 sled 的 I/O）因此是串行的，写入吞吐受限于单个写入的延迟。
+    // 只在分配 seq 和发送时持锁是不行的：同一个 key 的两次写入可能以和实际相反的顺序进入日志，
+    // 从节点回放后得到的就是旧值。等锁的写入会阻塞所在的线程，读操作不需要这个锁
+    lock: Mutex<()>,
+}
+
+impl<S: Storage> ReplicatedStorage<S> {
+    pub fn new(store: S, log: ReplicationLog) -> Self {
+        Self {
+            store,
+            log,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// 持有锁修改 store，然后把 f 返回的记录写入 ReplicationLog
+    fn write<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: FnOnce(&S) -> Result<(T, Option<WalRecord>), KvError>,
+    {
+        let _guard = self.lock();
+        let (result, record) = f(&self.store)?;
+        if let Some(record) = record {
+            self.log.append(record);
+        }
+        Ok(result)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<S: Storage> Storage for ReplicatedStorage<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.store.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.write(|s| {
+            let record = WalRecord::new_set(table, key.as_str(), value.clone());
+            Ok((s.set(table, key, value)?, Some(record)))
+        })
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.store.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.write(|s| {
+            let old = s.del(table, key)?;
+            let record = old.as_ref().map(|_| WalRecord::new_del(table, key));
+            Ok((old, record))
+        })
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.store.get_iter(table)
+    }
+
+    fn get_tables(&self) -> Result<Vec<String>, KvError> {
+        self.store.get_tables()
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        self.store.len(table)
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        prefix: &str,
+        cursor: &str,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.store.scan(table, prefix, cursor, limit)
+    }
+
+    fn update<F>(&self, table: &str, key: &str, f: F) -> Result<Value, KvError>
+    where
+        F: FnMut(Option<&Value>) -> Result<Value, KvError>,
+    {
+        self.write(|s| {
+            let value = s.update(table, key, f)?;
+            let record = WalRecord::new_update(table, key, value.clone());
+            Ok((value, Some(record)))
+        })
+    }
+
+    fn transaction<T, F>(&self, keys: &[(String, String)], mut f: F) -> Result<T, KvError>
+    where
+        F: FnMut(&mut [TxEntry]) -> Result<T, KvError>,
+    {
+        self.write(|s| {
+            let mut records = Vec::new();
+            let result = s.transaction(keys, |values| {
+                let old = values.to_vec();
+                let result = f(values)?;
+                records = diff_records(keys, &old, values);
+                Ok(result)
+            })?;
+
+            let record = (!records.is_empty()).then(|| WalRecord::new_batch(records));
+            Ok((result, record))
+        })
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        // 记录过期时刻，从节点收到时再换算成剩余的存活时间
+        let at = deadline_ms(ttl);
+        self.write(|s| {
+            let ok = s.expire(table, key, ttl)?;
+            Ok((ok, ok.then(|| WalRecord::new_expire_at(table, key, at))))
+        })
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.store.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.write(|s| {
+            let ok = s.persist(table, key)?;
+            Ok((ok, ok.then(|| WalRecord::new_persist(table, key))))
+        })
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        // 从节点上的 key 有同样的过期时刻，会自己过期，所以不用同步
+        self.store.purge_expired()
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.store.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pb::wal::wal_record::Op, MemTable};
+
+    #[test]
+    fn mutations_should_be_broadcasted_in_order() {
+        let log = ReplicationLog::default();
+        let store = ReplicatedStorage::new(MemTable::new(), log.clone());
+        let mut rx = log.subscribe();
+
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        // 只读的操作和没有修改数据的操作不会产生记录
+        store.get("t1", "k1").unwrap();
+        store.del("t1", "k2").unwrap();
+        store.update("t1", "n", |_| Ok(1.into())).unwrap();
+        let keys = vec![("t1".to_string(), "k1".to_string())];
+        store
+            .transaction(&keys, |values| {
+                values[0].value = None;
+                Ok(())
+            })
+            .unwrap();
+        store.expire("t1", "n", Duration::from_secs(10)).unwrap();
+
+        let ops: Vec<_> = (1..=4)
+            .map(|seq| {
+                let frame = rx.try_recv().unwrap();
+                assert_eq!(frame.seq, seq);
+                frame.records[0].op.clone().unwrap()
+            })
+            .collect();
+        assert!(rx.try_recv().is_err());
+        assert_eq!(log.seq(), 4);
+
+        assert!(matches!(&ops[0], Op::Set(v) if v.key == "k1"));
+        assert!(matches!(&ops[1], Op::Update(v) if v.value == Some(1.into())));
+        assert!(matches!(&ops[2], Op::Batch(v) if v.records.len() == 1));
+        assert!(matches!(&ops[3], Op::ExpireAt(v) if v.at > now_ms()));
+    }
+}
@@ -1,14 +1,34 @@
-use sled::{Db, IVec};
-use std::{convert::TryInto, path::Path, str};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Db, IVec, Transactional, Tree,
+};
+use std::{
+    cell::RefCell,
+    convert::{TryFrom, TryInto},
+    ops::Bound,
+    path::Path,
+    str,
+    time::Duration,
+};
 
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use super::{deadline_ms, now_ms};
+use crate::{KvError, Kvpair, Storage, StorageIter, TxEntry, Value};
+
+/// 存放 key 过期时刻的 tree
+const EXPIRES_TREE: &str = "__expires__";
 
 #[derive(Debug)]
-pub struct SledDb(Db);
+pub struct SledDb {
+    db: Db,
+    /// full key -> 过期时刻（UNIX 时间戳，毫秒，big endian）
+    expires: Tree,
+}
 
 impl SledDb {
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        let db = sled::open(path).unwrap();
+        let expires = db.open_tree(EXPIRES_TREE).unwrap();
+        Self { db, expires }
     }
 
     // 在 sleddb 里，因为它可以 scan_prefix，我们用 prefix
@@ -21,6 +41,31 @@ impl SledDb {
     fn get_table_prefix(table: &str) -> String {
         format!("{}:", table)
     }
+
+    /// 如果 key 已经过期，则把它删除，返回 key 是否过期
+    fn remove_if_expired(&self, name: impl AsRef<[u8]>) -> Result<bool, KvError> {
+        let name = name.as_ref();
+        if !matches!(self.expires.get(name)?, Some(at) if decode_expire(&at) <= now_ms()) {
+            return Ok(false);
+        }
+
+        // 在事务里重新检查过期时刻再删除，避免把检查之后刚 set 进来的新值删掉
+        let result =
+            (&*self.db, &self.expires).transaction(|(db, expires)| match expires.get(name)? {
+                Some(at) if decode_expire(&at) <= now_ms() => {
+                    expires.remove(name)?;
+                    db.remove(name)?;
+                    Ok(true)
+                }
+                _ => Ok(false),
+            });
+
+        match result {
+            Ok(v) => Ok(v),
+            Err(TransactionError::Abort(())) => Ok(false),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
 }
 
 /// 把 Option<Result<T, E>> flip 成 Result<Option<T>, E>
@@ -32,42 +77,268 @@ fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
 impl Storage for SledDb {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, key);
-        let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
+        self.remove_if_expired(&name)?;
+        let result = self.db.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
         flip(result)
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, &key);
+        self.remove_if_expired(&name)?;
+        self.expires.remove(&name)?;
         let data: Vec<u8> = value.try_into()?;
 
-        let result = self.0.insert(name, data)?.map(|v| v.as_ref().try_into());
+        let result = self.db.insert(name, data)?.map(|v| v.as_ref().try_into());
         flip(result)
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
         let name = SledDb::get_full_key(table, key);
+        self.remove_if_expired(&name)?;
 
-        Ok(self.0.contains_key(name)?)
+        Ok(self.db.contains_key(name)?)
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, key);
+        if self.remove_if_expired(&name)? {
+            return Ok(None);
+        }
+        self.expires.remove(&name)?;
 
-        let result = self.0.remove(name)?.map(|v| v.as_ref().try_into());
+        let result = self.db.remove(name)?.map(|v| v.as_ref().try_into());
         flip(result)
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        Ok(self.get_iter(table)?.collect())
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
         let prefix = SledDb::get_table_prefix(table);
-        let result = self.0.scan_prefix(prefix).map(|v| v.into()).collect();
+        let expires = self.expires.clone();
+        let now = now_ms();
+
+        // 过滤掉已经过期但还没来得及删除的 key
+        let iter = self.db.scan_prefix(prefix).filter(move |v| match v {
+            Ok((k, _)) => !matches!(expires.get(k), Ok(Some(at)) if decode_expire(&at) <= now),
+            Err(_) => true,
+        });
+        Ok(Box::new(StorageIter::new(iter)))
+    }
+
+    fn get_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = Vec::new();
+        let mut start = Vec::new();
+
+        // 找到一个 table 后，直接跳到下一个 table 的第一个 key（':' 的下一个字符是 ';'）
+        while let Some(item) = self.db.range(start.as_slice()..).next() {
+            let (k, _) = item?;
+            match k.iter().position(|c| *c == b':') {
+                Some(pos) => {
+                    tables.push(String::from_utf8_lossy(&k[..pos]).into_owned());
+                    start = k[..pos].to_vec();
+                    start.push(b';');
+                }
+                None => {
+                    start = k.to_vec();
+                    start.push(0);
+                }
+            }
+        }
+
+        Ok(tables)
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        prefix: &str,
+        cursor: &str,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let table_prefix = SledDb::get_table_prefix(table);
+        let full_prefix = SledDb::get_full_key(table, prefix);
+        // 没有 cursor 时直接 scan_prefix，否则从 cursor 之后开始 range
+        let iter = if cursor.is_empty() || cursor < prefix {
+            self.db.scan_prefix(&full_prefix)
+        } else {
+            let start = SledDb::get_full_key(table, cursor);
+            self.db
+                .range::<String, _>((Bound::Excluded(start), Bound::Unbounded))
+        };
+
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let now = now_ms();
+        let mut result = Vec::new();
+        for item in iter {
+            let (k, v) = item?;
+            if !k.starts_with(full_prefix.as_bytes()) {
+                break;
+            }
+            if matches!(self.expires.get(&k)?, Some(at) if decode_expire(&at) <= now) {
+                continue;
+            }
+
+            let key = String::from_utf8_lossy(&k[table_prefix.len()..]);
+            result.push(Kvpair::new(key, v.as_ref().try_into()?));
+            if result.len() >= limit {
+                break;
+            }
+        }
 
         Ok(result)
     }
 
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let iter = StorageIter::new(self.0.scan_prefix(prefix));
-        Ok(Box::new(iter))
+    fn update<F>(&self, table: &str, key: &str, mut f: F) -> Result<Value, KvError>
+    where
+        F: FnMut(Option<&Value>) -> Result<Value, KvError>,
+    {
+        let name = SledDb::get_full_key(table, key);
+        self.remove_if_expired(&name)?;
+
+        // update_and_fetch 内部使用 compare-and-swap，冲突时会重新调用闭包
+        // 闭包里无法返回错误，所以出错时保持原值不变，并把错误记下来
+        let mut error = None;
+        let result = self.db.update_and_fetch(&name, |old| {
+            let value = old
+                .map(Value::try_from)
+                .transpose()
+                .and_then(|v| f(v.as_ref()))
+                .and_then(Vec::<u8>::try_from);
+
+            match value {
+                Ok(data) => {
+                    error = None;
+                    Some(data)
+                }
+                Err(e) => {
+                    error = Some(e);
+                    old.map(|v| v.to_vec())
+                }
+            }
+        })?;
+
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        match result {
+            Some(v) => v.as_ref().try_into(),
+            None => Err(KvError::Internal(format!("Failed to update {}", name))),
+        }
+    }
+
+    fn transaction<T, F>(&self, keys: &[(String, String)], f: F) -> Result<T, KvError>
+    where
+        F: FnMut(&mut [TxEntry]) -> Result<T, KvError>,
+    {
+        let names: Vec<_> = keys
+            .iter()
+            .map(|(table, key)| SledDb::get_full_key(table, key))
+            .collect();
+
+        // sled 的事务在冲突时会自动重试，用 Abort 把 KvError 带出来
+        // 它只接受 Fn 闭包，所以用 RefCell 来调用 FnMut
+        let f = RefCell::new(f);
+        let result = (&*self.db, &self.expires).transaction(|(db, expires)| {
+            let now = now_ms();
+            let mut old = Vec::with_capacity(names.len());
+            for name in names.iter() {
+                let at = expires.get(name)?.map(|at| decode_expire(&at));
+                let entry = match db.get(name)? {
+                    Some(v) if at.is_none_or(|at| at > now) => TxEntry {
+                        value: Some(
+                            Value::try_from(v.as_ref())
+                                .map_err(ConflictableTransactionError::Abort)?,
+                        ),
+                        expire_at: at,
+                    },
+                    _ => TxEntry::default(),
+                };
+                old.push(entry);
+            }
+
+            let mut values = old.clone();
+            let result =
+                (f.borrow_mut())(&mut values).map_err(ConflictableTransactionError::Abort)?;
+
+            for ((name, old), new) in names.iter().zip(old).zip(values) {
+                if old == new {
+                    continue;
+                }
+                expires.remove(name.as_bytes())?;
+                match new.value {
+                    Some(v) => {
+                        let data: Vec<u8> =
+                            v.try_into().map_err(ConflictableTransactionError::Abort)?;
+                        db.insert(name.as_bytes(), data)?;
+                        if let Some(at) = new.expire_at {
+                            expires.insert(name.as_bytes(), &at.to_be_bytes())?;
+                        }
+                    }
+                    None => {
+                        db.remove(name.as_bytes())?;
+                    }
+                }
+            }
+
+            Ok(result)
+        });
+
+        match result {
+            Ok(v) => Ok(v),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        if !self.contains(table, key)? {
+            return Ok(false);
+        }
+
+        let name = SledDb::get_full_key(table, key);
+        let at = deadline_ms(ttl);
+        self.expires.insert(name, &at.to_be_bytes())?;
+        Ok(true)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        if self.remove_if_expired(&name)? {
+            return Ok(None);
+        }
+
+        let at = self.expires.get(name)?.map(|v| decode_expire(&v));
+        Ok(at.map(|at| Duration::from_millis(at.saturating_sub(now_ms()))))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        if self.remove_if_expired(&name)? {
+            return Ok(false);
+        }
+        Ok(self.expires.remove(name)?.is_some())
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let now = now_ms();
+        let mut count = 0;
+
+        for item in self.expires.iter() {
+            let (name, at) = item?;
+            if decode_expire(&at) <= now && self.remove_if_expired(&name)? {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.db.flush()?;
+        Ok(())
     }
 }
 
@@ -89,3 +360,7 @@ fn ivec_to_key(ivec: &[u8]) -> &str {
     iter.next();
     iter.next().unwrap()
 }
+
+fn decode_expire(ivec: &[u8]) -> u64 {
+    ivec.try_into().map(u64::from_be_bytes).unwrap_or_default()
+}
@@ -0,0 +1,601 @@
+use prost::Message;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use super::{deadline_ms, now_ms};
+use crate::{
+    pb::wal::{wal_record::Op, WalRecord},
+    FsyncPolicy, KvError, Kvpair, MemTable, Storage, TxEntry, Value, WalConfig,
+};
+
+/// WAL 文件名
+const WAL_FILE: &str = "kv.wal";
+/// FsyncPolicy::EverySec 时 sync 的间隔
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// snapshot 文件名
+const SNAPSHOT_FILE: &str = "kv.snapshot";
+/// 生成 snapshot 时使用的临时文件名，写完后再 rename 成 SNAPSHOT_FILE
+const SNAPSHOT_TMP_FILE: &str = "kv.snapshot.tmp";
+
+/// 基于 MemTable 的持久化存储：所有修改都会追加写入 WAL，
+/// WAL 累计到一定数量后生成 snapshot 并清空 WAL，启动时依次回放 snapshot 和 WAL
+#[derive(Debug)]
+pub struct WalMemTable {
+    table: MemTable,
+    // 所有的修改都在持有这个锁的情况下进行，保证 WAL 里的顺序和实际修改的顺序一致
+    wal: Arc<Mutex<Wal>>,
+}
+
+#[derive(Debug)]
+struct Wal {
+    dir: PathBuf,
+    file: File,
+    fsync: FsyncPolicy,
+    /// 上次 snapshot 之后写入的记录数
+    records: usize,
+    /// 为 0 时不自动做 snapshot
+    snapshot_threshold: usize,
+    last_sync: Instant,
+    /// 已经写入但是还没有 sync 的记录
+    dirty: bool,
+    /// WAL 文件中完整记录的长度，写入失败时截断到这里
+    len: u64,
+}
+
+impl WalMemTable {
+    /// 打开（或创建）config.path 下的 WAL 和 snapshot，回放之后得到一个 WalMemTable
+    pub fn new(config: &WalConfig) -> Result<Self, KvError> {
+        let dir = PathBuf::from(&config.path);
+        fs::create_dir_all(&dir)?;
+
+        let table = MemTable::new();
+
+        // 先回放 snapshot，再回放 snapshot 之后的 WAL
+        let (records, _) = load_records(&dir.join(SNAPSHOT_FILE))?;
+        for record in records {
+            apply_record(&table, record)?;
+        }
+
+        let path = dir.join(WAL_FILE);
+        let (records, len) = load_records(&path)?;
+        let count = records.len();
+        for record in records {
+            apply_record(&table, record)?;
+        }
+        info!("Replayed {} WAL records from {:?}", count, path);
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // 上次写到一半的记录（比如进程崩溃）直接丢弃
+        if file.metadata()?.len() > len {
+            warn!("WAL {:?} is truncated to {} bytes", path, len);
+            file.set_len(len)?;
+        }
+
+        let wal = Wal {
+            dir,
+            file,
+            fsync: config.fsync,
+            records: count,
+            snapshot_threshold: config.snapshot_threshold,
+            last_sync: Instant::now(),
+            dirty: false,
+            len,
+        };
+
+        let wal = Arc::new(Mutex::new(wal));
+        if config.fsync == FsyncPolicy::EverySec {
+            start_sync(Arc::downgrade(&wal));
+        }
+        Ok(Self { table, wal })
+    }
+
+    /// 立刻生成 snapshot，并清空 WAL
+    pub fn snapshot(&self) -> Result<(), KvError> {
+        let mut wal = self.lock_wal();
+        self.snapshot_with(&mut wal)
+    }
+
+    fn snapshot_with(&self, wal: &mut Wal) -> Result<(), KvError> {
+        let mut buf = Vec::new();
+        for (table, pair, at) in self.table.dump() {
+            let Kvpair { key, value } = pair;
+            let record =
+                WalRecord::new_set(table.as_str(), key.as_str(), value.unwrap_or_default());
+            record.encode_length_delimited(&mut buf)?;
+            if let Some(at) = at {
+                WalRecord::new_expire_at(table, key, at).encode_length_delimited(&mut buf)?;
+            }
+        }
+
+        // 先写临时文件再 rename，这样任何时候 snapshot 文件都是完整的
+        let tmp = wal.dir.join(SNAPSHOT_TMP_FILE);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, wal.dir.join(SNAPSHOT_FILE))?;
+
+        // snapshot 已经包含了 WAL 里所有的修改，可以清空 WAL
+        // 即使在这之前崩溃，WAL 里的记录再回放一次也不会改变结果
+        wal.file.set_len(0)?;
+        wal.file.sync_all()?;
+        wal.records = 0;
+        wal.dirty = false;
+        wal.len = 0;
+        info!("Snapshot is written with {} bytes", buf.len());
+
+        Ok(())
+    }
+
+    /// 持有 WAL 锁执行 f，f 需要先把记录写入 WAL，写入成功后再修改 MemTable，
+    /// 这样写入 WAL 失败时 MemTable 不会被修改。其它的修改都要等待 WAL 锁，
+    /// 所以 f 生成记录时读到的数据，在修改 MemTable 之前不会变化
+    fn write<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: FnOnce(&MemTable, &mut Wal) -> Result<T, KvError>,
+    {
+        let mut wal = self.lock_wal();
+        let result = f(&self.table, &mut wal)?;
+
+        // 修改已经写入 WAL，snapshot 失败不能让调用者以为修改没有成功，下次写入时会再试
+        if wal.snapshot_threshold > 0 && wal.records >= wal.snapshot_threshold {
+            if let Err(e) = self.snapshot_with(&mut wal) {
+                warn!("Failed to write snapshot: {:?}", e);
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn lock_wal(&self) -> MutexGuard<'_, Wal> {
+        lock(&self.wal)
+    }
+}
+
+// 被 poison 时 Wal 里的状态仍然是一致的（写入失败会截断），所以直接忽略
+fn lock(wal: &Mutex<Wal>) -> MutexGuard<'_, Wal> {
+    wal.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// FsyncPolicy::EverySec 时，在后台线程里每秒 sync 一次还没有 sync 的记录，
+/// 这样一批写入的最后几条也会在一秒内落盘。WalMemTable 被释放后线程退出
+fn start_sync(wal: Weak<Mutex<Wal>>) {
+    thread::spawn(move || loop {
+        thread::sleep(SYNC_INTERVAL);
+        let Some(wal) = wal.upgrade() else {
+            break;
+        };
+        let mut wal = lock(&wal);
+        if wal.dirty {
+            if let Err(e) = wal.sync() {
+                warn!("Failed to sync WAL: {:?}", e);
+            }
+        }
+    });
+}
+
+impl Wal {
+    /// 追加一条记录，失败时把文件截断到写入之前，不留下写了一半的记录
+    fn append(&mut self, record: &WalRecord) -> Result<(), KvError> {
+        let mut buf = Vec::with_capacity(record.encoded_len() + 8);
+        record.encode_length_delimited(&mut buf)?;
+
+        if let Err(e) = self.write_and_sync(&buf) {
+            if let Err(e) = self.file.set_len(self.len) {
+                warn!("Failed to truncate WAL to {} bytes: {:?}", self.len, e);
+            }
+            return Err(e);
+        }
+        self.len += buf.len() as u64;
+        self.records += 1;
+
+        Ok(())
+    }
+
+    fn write_and_sync(&mut self, buf: &[u8]) -> Result<(), KvError> {
+        self.file.write_all(buf)?;
+        self.dirty = true;
+
+        let need_sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EverySec => self.last_sync.elapsed() >= SYNC_INTERVAL,
+            FsyncPolicy::Never => false,
+        };
+        if need_sync {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), KvError> {
+        self.file.sync_data()?;
+        self.last_sync = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Storage for WalMemTable {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.table.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.write(|t, wal| {
+            wal.append(&WalRecord::new_set(table, key.as_str(), value.clone()))?;
+            t.set(table, key, value)
+        })
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.table.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.write(|t, wal| {
+            if !t.contains(table, key)? {
+                return Ok(None);
+            }
+            wal.append(&WalRecord::new_del(table, key))?;
+            t.del(table, key)
+        })
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.table.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.table.get_iter(table)
+    }
+
+    fn get_tables(&self) -> Result<Vec<String>, KvError> {
+        self.table.get_tables()
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        self.table.len(table)
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        prefix: &str,
+        cursor: &str,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.table.scan(table, prefix, cursor, limit)
+    }
+
+    fn update<F>(&self, table: &str, key: &str, mut f: F) -> Result<Value, KvError>
+    where
+        F: FnMut(Option<&Value>) -> Result<Value, KvError>,
+    {
+        self.write(|t, wal| {
+            let value = f(t.get(table, key)?.as_ref())?;
+            wal.append(&WalRecord::new_update(table, key, value.clone()))?;
+            t.update(table, key, |_| Ok(value.clone()))
+        })
+    }
+
+    fn transaction<T, F>(&self, keys: &[(String, String)], mut f: F) -> Result<T, KvError>
+    where
+        F: FnMut(&mut [TxEntry]) -> Result<T, KvError>,
+    {
+        self.write(|t, wal| {
+            // 事务里真正修改了的 key 作为一条 Batch 写入 WAL，
+            // 写入失败时返回错误，MemTable 的事务也就不会提交
+            t.transaction(keys, |values| {
+                let old = values.to_vec();
+                let result = f(values)?;
+                let records = diff_records(keys, &old, values);
+                if !records.is_empty() {
+                    wal.append(&WalRecord::new_batch(records))?;
+                }
+                Ok(result)
+            })
+        })
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        // WAL 里记录的是过期时刻，这样回放时不会延长 key 的存活时间
+        let at = deadline_ms(ttl);
+        self.write(|t, wal| {
+            if !t.contains(table, key)? {
+                return Ok(false);
+            }
+            wal.append(&WalRecord::new_expire_at(table, key, at))?;
+            Ok(t.expire_at(table, key, at))
+        })
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.table.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.write(|t, wal| {
+            if t.ttl(table, key)?.is_none() {
+                return Ok(false);
+            }
+            wal.append(&WalRecord::new_persist(table, key))?;
+            t.persist(table, key)
+        })
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        // 过期时刻已经在 WAL 里了，回放时这些 key 同样会过期，所以不用记录
+        self.table.purge_expired()
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.lock_wal().sync()
+    }
+}
+
+/// 从文件中读取所有完整的记录，返回记录和它们占用的字节数
+fn load_records(path: &Path) -> Result<(Vec<WalRecord>, u64), KvError> {
+    if !path.exists() {
+        return Ok((Vec::new(), 0));
+    }
+
+    let data = fs::read(path)?;
+    let mut buf = &data[..];
+    let mut records = Vec::new();
+    while !buf.is_empty() {
+        // 解码失败时 buf 不能前进，这样才能算出完整记录的长度
+        let mut cursor = buf;
+        match WalRecord::decode_length_delimited(&mut cursor) {
+            Ok(record) => {
+                records.push(record);
+                buf = cursor;
+            }
+            Err(e) => {
+                warn!("Failed to decode record from {:?}: {:?}", path, e);
+                break;
+            }
+        }
+    }
+
+    let len = data.len() - buf.len();
+    Ok((records, len as u64))
+}
+
+/// 比较事务前后 keys 的 value，把修改了的 key 生成 Set/Del 记录
+pub(crate) fn diff_records(
+    keys: &[(String, String)],
+    old: &[TxEntry],
+    new: &[TxEntry],
+) -> Vec<WalRecord> {
+    let mut records = Vec::new();
+    for ((table, key), (old, new)) in keys.iter().zip(old.iter().zip(new.iter())) {
+        if old == new {
+            continue;
+        }
+        match (&new.value, new.expire_at) {
+            (Some(v), at) => {
+                // set 会清除过期时间，所以有过期时间的 key 后面再跟一条 ExpireAt
+                records.push(WalRecord::new_set(table.as_str(), key.as_str(), v.clone()));
+                if let Some(at) = at {
+                    records.push(WalRecord::new_expire_at(table.as_str(), key.as_str(), at));
+                }
+            }
+            (None, _) => records.push(WalRecord::new_del(table.as_str(), key.as_str())),
+        }
+    }
+    records
+}
+
+/// 把一条记录应用到 storage 上，WAL 回放和从节点同步数据都使用它
+pub(crate) fn apply_record(store: &impl Storage, record: WalRecord) -> Result<(), KvError> {
+    match record.op {
+        Some(Op::Set(v)) => {
+            store.set(&v.table, v.key, v.value.unwrap_or_default())?;
+        }
+        Some(Op::Del(v)) => {
+            store.del(&v.table, &v.key)?;
+        }
+        Some(Op::Update(v)) => {
+            let value = v.value.unwrap_or_default();
+            store.update(&v.table, &v.key, |_| Ok(value.clone()))?;
+        }
+        Some(Op::ExpireAt(v)) => match v.at.checked_sub(now_ms()) {
+            Some(ttl) if ttl > 0 => {
+                store.expire(&v.table, &v.key, Duration::from_millis(ttl))?;
+            }
+            // 已经过期了
+            _ => {
+                store.del(&v.table, &v.key)?;
+            }
+        },
+        Some(Op::Persist(v)) => {
+            store.persist(&v.table, &v.key)?;
+        }
+        Some(Op::Batch(v)) => {
+            // Batch 里只有 Set/Del，在一个事务里整体生效
+            let mut keys = Vec::with_capacity(v.records.len());
+            let mut values = Vec::with_capacity(v.records.len());
+            for record in v.records {
+                match record.op {
+                    Some(Op::Set(v)) => {
+                        keys.push((v.table, v.key));
+                        values.push(TxEntry::from(Some(v.value.unwrap_or_default())));
+                    }
+                    Some(Op::Del(v)) => {
+                        keys.push((v.table, v.key));
+                        values.push(TxEntry::default());
+                    }
+                    // 紧跟在 Set 之后，设置它的过期时刻
+                    Some(Op::ExpireAt(v)) => {
+                        if let Some(entry) = values.last_mut() {
+                            entry.expire_at = Some(v.at);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            store.transaction(&keys, |current| {
+                current.clone_from_slice(&values);
+                Ok(())
+            })?;
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn config(path: &Path, snapshot_threshold: usize) -> WalConfig {
+        WalConfig {
+            path: path.to_string_lossy().into(),
+            fsync: FsyncPolicy::Always,
+            snapshot_threshold,
+        }
+    }
+
+    #[test]
+    fn wal_should_be_replayed_after_restart() {
+        let dir = tempdir().unwrap();
+        {
+            let store = WalMemTable::new(&config(dir.path(), 0)).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.set("t1", "k2".into(), "v2".into()).unwrap();
+            store.del("t1", "k2").unwrap();
+            store.update("t1", "n", |_| Ok(10.into())).unwrap();
+            store.expire("t1", "n", Duration::from_secs(60)).unwrap();
+            store.expire("t1", "k1", Duration::from_secs(60)).unwrap();
+            store.persist("t1", "k1").unwrap();
+
+            // 事务里保留的过期时间也会写入 WAL
+            let keys = vec![("t1".to_string(), "n".to_string())];
+            store
+                .transaction(&keys, |values| {
+                    values[0].value = Some(11.into());
+                    Ok(())
+                })
+                .unwrap();
+        }
+
+        let store = WalMemTable::new(&config(dir.path(), 0)).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(!store.contains("t1", "k2").unwrap());
+        assert_eq!(store.get("t1", "n").unwrap(), Some(11.into()));
+        assert!(store.ttl("t1", "n").unwrap().is_some());
+        assert!(store.ttl("t1", "k1").unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_append_should_not_change_memtable() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::new(&config(dir.path(), 0)).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+
+        // 只读打开 WAL 文件，之后的写入都会失败
+        store.lock_wal().file = File::open(dir.path().join(WAL_FILE)).unwrap();
+        assert!(store.set("t1", "k1".into(), "v2".into()).is_err());
+        assert!(store.update("t1", "n", |_| Ok(1.into())).is_err());
+        let keys = vec![("t1".to_string(), "k2".to_string())];
+        let result = store.transaction(&keys, |values| {
+            values[0].value = Some("v2".into());
+            Ok(())
+        });
+        assert!(result.is_err());
+
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(!store.contains("t1", "n").unwrap());
+        assert!(!store.contains("t1", "k2").unwrap());
+    }
+
+    #[test]
+    fn every_sec_policy_should_sync_in_background() {
+        let dir = tempdir().unwrap();
+        let config = WalConfig {
+            fsync: FsyncPolicy::EverySec,
+            ..config(dir.path(), 0)
+        };
+        let store = WalMemTable::new(&config).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert!(store.lock_wal().dirty);
+
+        // 之后没有新的写入，后台线程也会 sync
+        thread::sleep(SYNC_INTERVAL + Duration::from_millis(500));
+        assert!(!store.lock_wal().dirty);
+    }
+
+    #[test]
+    fn snapshot_should_truncate_wal() {
+        let dir = tempdir().unwrap();
+        {
+            let store = WalMemTable::new(&config(dir.path(), 3)).unwrap();
+            for i in 0..10 {
+                store.set("t1", format!("k{}", i), i.into()).unwrap();
+            }
+            store.expire("t1", "k0", Duration::from_secs(60)).unwrap();
+        }
+
+        // 10 次 set 之后做了 3 次 snapshot，WAL 里只剩两条记录
+        let (records, _) = load_records(&dir.path().join(WAL_FILE)).unwrap();
+        assert_eq!(records.len(), 2);
+
+        let store = WalMemTable::new(&config(dir.path(), 3)).unwrap();
+        let mut data = store.get_all("t1").unwrap();
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(data.len(), 10);
+        assert_eq!(data[9], Kvpair::new("k9", 9.into()));
+        assert!(store.ttl("t1", "k0").unwrap().is_some());
+    }
+
+    #[test]
+    fn failed_snapshot_should_not_fail_write() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::new(&config(dir.path(), 1)).unwrap();
+
+        // 临时文件的位置被目录占住，snapshot 会失败
+        let tmp = dir.path().join(SNAPSHOT_TMP_FILE);
+        fs::create_dir(&tmp).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.lock_wal().records, 1);
+
+        // 下次写入时重新做 snapshot
+        fs::remove_dir(&tmp).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert_eq!(store.lock_wal().records, 0);
+    }
+
+    #[test]
+    fn incomplete_wal_record_should_be_discarded() {
+        let dir = tempdir().unwrap();
+        {
+            let store = WalMemTable::new(&config(dir.path(), 0)).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+        }
+
+        // 模拟写了一半就崩溃的记录
+        let path = dir.path().join(WAL_FILE);
+        let len = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[100, 1, 2]).unwrap();
+
+        let store = WalMemTable::new(&config(dir.path(), 0)).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        // 之后的写入不受影响
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        drop(store);
+        let store = WalMemTable::new(&config(dir.path(), 0)).unwrap();
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+    }
+}